@@ -1,11 +1,13 @@
-use snap_coin::{build_block, crypto::keys::Private, full_node::{accept_block, create_full_node}};
-use flexi_logger::Logger;
+use snap_coin::{build_block, crypto::keys::Private, full_node::{accept_block, create_full_node}, logging::{LogConfig, init_logging}};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let _ = Logger::try_with_str("debug").ok();
+    let _logger = init_logging(&LogConfig {
+        level: "debug".to_string(),
+        ..Default::default()
+    })?;
 
-    let (blockchain, node_state) = create_full_node("./node-devnet", false);
+    let (blockchain, node_state) = create_full_node("./node-devnet");
 
 
     loop {
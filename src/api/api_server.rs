@@ -1,20 +1,31 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
 use futures::io;
 use log::{error, info, warn};
 use thiserror::Error;
 use tokio::{
     io::AsyncWriteExt,
     net::{TcpListener, TcpStream},
+    sync::broadcast::error::RecvError,
 };
 
 use crate::{
     api::requests::{Request, Response},
     blockchain_data_provider::BlockchainDataProvider,
     core::{
-        difficulty::calculate_live_transaction_difficulty, transaction::TransactionError,
+        blockchain::BlockchainError,
+        difficulty::calculate_live_transaction_difficulty,
+        transaction::{TransactionError, TransactionId},
         utils::slice_vec,
     },
     economics::get_block_reward,
-    full_node::{SharedBlockchain, accept_block, accept_transaction, node_state::SharedNodeState},
+    full_node::{
+        SharedBlockchain, accept_block, accept_stem_transaction, accept_transaction,
+        block_template::build_block_template,
+        mempool::MemPoolEvent,
+        node_state::SharedNodeState,
+        tx_relay::{track_submitted, transaction_status},
+    },
 };
 
 pub const PAGE_SIZE: u32 = 200;
@@ -25,18 +36,54 @@ pub enum ApiError {
     IOError(#[from] io::Error),
 }
 
+/// Track a transaction submitted through the API so it gets re-announced if `rebroadcast`, or tell chain event subscribers it was not accepted.
+/// Private submissions are not re-announced, that would broadcast them from this node. Their embargo already fluffs them if they get lost on the stem
+fn handle_submission(
+    node_state: &SharedNodeState,
+    transaction: Option<TransactionId>,
+    status: &Result<(), BlockchainError>,
+    rebroadcast: bool,
+) {
+    match status {
+        Ok(()) => {
+            if let Some(transaction) = transaction
+                && rebroadcast
+            {
+                track_submitted(node_state, transaction);
+            }
+        }
+        Err(reason) => node_state.send_mempool_event(MemPoolEvent::Rejected {
+            transaction,
+            reason: reason.clone(),
+        }),
+    }
+}
+
 /// Server for hosting a Snap Coin API
 pub struct Server {
-    port: u32,
+    address: SocketAddr,
     blockchain: SharedBlockchain,
     node_state: SharedNodeState,
 }
 
 impl Server {
-    /// Create a new server, do not listen for connections yet
+    /// Create a new server on `0.0.0.0:port`, do not listen for connections yet
     pub fn new(port: u32, blockchain: SharedBlockchain, node_state: SharedNodeState) -> Self {
+        Self::new_with_address(
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port as u16),
+            blockchain,
+            node_state,
+        )
+    }
+
+    /// Create a new server that binds to `address`, do not listen for connections yet
+    pub fn new_with_address(
+        address: SocketAddr,
+        blockchain: SharedBlockchain,
+        node_state: SharedNodeState,
+    ) -> Self {
         Server {
-            port,
+            address,
             blockchain,
             node_state,
         }
@@ -139,14 +186,8 @@ impl Server {
                         reward: get_block_reward(blockchain.block_store().get_height()),
                     },
                     Request::Peers => {
-                        let peers = node_state
-                            .connected_peers
-                            .read()
-                            .await
-                            .iter()
-                            .map(|peer| *peer.0)
-                            .collect();
-                        Response::Peers { peers }
+                        let (inbound, outbound) = node_state.peers_by_direction().await;
+                        Response::Peers { inbound, outbound }
                     }
                     Request::Mempool {
                         page: requested_page,
@@ -168,12 +209,34 @@ impl Server {
                             next_page,
                         }
                     }
+                    Request::MempoolInfo => Response::MempoolInfo {
+                        info: node_state
+                            .mempool
+                            .info(blockchain.get_transaction_difficulty())
+                            .await,
+                    },
                     Request::NewBlock { new_block } => Response::NewBlock {
                         status: accept_block(&blockchain, &node_state, new_block).await,
                     },
-                    Request::NewTransaction { new_transaction } => Response::NewTransaction {
-                        status: accept_transaction(&blockchain, &node_state, new_transaction).await,
-                    },
+                    Request::NewTransaction { new_transaction } => {
+                        let transaction_id = new_transaction.transaction_id;
+                        let status =
+                            accept_transaction(&blockchain, &node_state, new_transaction).await;
+                        handle_submission(&node_state, transaction_id, &status, true);
+                        Response::NewTransaction { status }
+                    }
+                    Request::NewPrivateTransaction { new_transaction } => {
+                        let transaction_id = new_transaction.transaction_id;
+                        let status = accept_stem_transaction(
+                            &blockchain,
+                            &node_state,
+                            new_transaction,
+                            true,
+                        )
+                        .await;
+                        handle_submission(&node_state, transaction_id, &status, false);
+                        Response::NewTransaction { status }
+                    }
                     Request::Difficulty => Response::Difficulty {
                         transaction_difficulty: blockchain.get_transaction_difficulty(),
                         block_difficulty: blockchain.get_block_difficulty(),
@@ -187,6 +250,30 @@ impl Server {
                             node_state.mempool.mempool_size().await,
                         ),
                     },
+                    Request::IbdStatus => Response::IbdStatus {
+                        status: node_state.ibd_status.read().await.clone(),
+                    },
+                    Request::Ban { ip, duration } => Response::Ban {
+                        ban: node_state
+                            .ban_ip(ip, duration, "Manual ban".to_string())
+                            .await,
+                    },
+                    Request::Unban { ip } => Response::Unban {
+                        was_banned: node_state.ban_list.unban(ip),
+                    },
+                    Request::ListBans => Response::ListBans {
+                        bans: node_state.ban_list.list_bans(),
+                    },
+                    Request::PeerInfo => Response::PeerInfo {
+                        peers: node_state.peer_info().await,
+                    },
+                    Request::BlockTemplate { miner } => Response::BlockTemplate {
+                        template: build_block_template(&blockchain, &node_state.mempool, miner)
+                            .await?,
+                    },
+                    Request::TransactionStatus { id } => Response::TransactionStatus {
+                        status: transaction_status(&blockchain, &node_state, id).await,
+                    },
                     Request::SubscribeToChainEvents => {
                         let mut rx = node_state.chain_events.subscribe();
                         // Start event stream task
@@ -196,7 +283,10 @@ impl Server {
                                     let response = Response::ChainEvent { event };
                                     stream.write_all(&response.encode()?).await?;
                                 }
-                                Err(_) => break,
+                                Err(RecvError::Lagged(skipped)) => {
+                                    warn!("API client lagged behind, skipped {skipped} events");
+                                }
+                                Err(RecvError::Closed) => break,
                             }
                         }
 
@@ -218,23 +308,22 @@ impl Server {
         }
     }
 
-    /// Start listening for clients
+    /// Start listening for clients, until the node shuts down
     pub async fn listen(self) -> Result<(), ApiError> {
-        let listener = match TcpListener::bind(format!("0.0.0.0:{}", self.port)).await {
+        let listener = match TcpListener::bind(self.address).await {
             Ok(l) => l,
-            Err(_) => TcpListener::bind("0.0.0.0:0").await?,
+            Err(_) => TcpListener::bind(SocketAddr::new(self.address.ip(), 0)).await?,
         };
-        info!(
-            "API Server listening on 0.0.0.0:{}",
-            listener.local_addr()?.port()
-        );
+        info!("API Server listening on {}", listener.local_addr()?);
 
-        tokio::spawn(async move {
+        // Clients are cut off when the node shuts down
+        let node_state = self.node_state.clone();
+        node_state.shutdown.spawn(async move {
             loop {
                 if let Err(e) = async {
                     let (stream, _) = listener.accept().await?;
 
-                    tokio::spawn(Self::connection(
+                    self.node_state.shutdown.spawn(Self::connection(
                         stream,
                         self.blockchain.clone(),
                         self.node_state.clone(),
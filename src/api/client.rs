@@ -1,4 +1,4 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 
 use tokio::{io::AsyncWriteExt, net::TcpStream, sync::Mutex};
 
@@ -11,11 +11,18 @@ use crate::{
         transaction::{Transaction, TransactionId, TransactionOutput},
     },
     crypto::{Hash, keys::Public},
-    full_node::node_state::ChainEvent,
+    full_node::{
+        ban_list::BanEntry, block_template::BlockTemplate, ibd::IbdStatus, mempool::MemPoolInfo,
+        node_state::ChainEvent, tx_relay::TransactionStatus,
+    },
+    node::{
+        connector::{Endpoint, SharedConnector},
+        peer::PeerInfo,
+    },
 };
 
 pub struct Client {
-    pub node: SocketAddr,
+    pub node: Endpoint,
     stream: Mutex<TcpStream>,
 }
 
@@ -23,6 +30,22 @@ impl Client {
     pub async fn connect(node: SocketAddr) -> Result<Self, std::io::Error> {
         let stream = TcpStream::connect(node).await?;
         stream.set_nodelay(true)?;
+        Ok(Client {
+            node: node.into(),
+            stream: Mutex::new(stream),
+        })
+    }
+
+    /// Connect to a node through `connector`, for example a `Socks5Connector` to reach a node over Tor
+    pub async fn connect_via(
+        node: Endpoint,
+        connector: &SharedConnector,
+    ) -> Result<Self, std::io::Error> {
+        let stream = connector
+            .connect(&node)
+            .await
+            .map_err(std::io::Error::other)?;
+        stream.set_nodelay(true)?;
         Ok(Client {
             node,
             stream: Mutex::new(stream),
@@ -67,6 +90,20 @@ impl Client {
         }
     }
 
+    /// Submit a new transaction to the network privately. It takes a Dandelion++ stem route first, so peers can't tell it came from this node
+    pub async fn submit_private_transaction(
+        &self,
+        new_transaction: Transaction,
+    ) -> Result<Result<(), BlockchainError>, BlockchainDataProviderError> {
+        match self
+            .fetch(Request::NewPrivateTransaction { new_transaction })
+            .await?
+        {
+            Response::NewTransaction { status } => Ok(status),
+            _ => Err(RequestResponseError::IncorrectResponse.into()),
+        }
+    }
+
     /// Get current full mempool
     pub async fn get_mempool(&self) -> Result<Vec<Transaction>, BlockchainDataProviderError> {
         let mut mempool = vec![];
@@ -99,8 +136,17 @@ impl Client {
 
     /// Get a list of peers of the connected node
     pub async fn get_peers(&self) -> Result<Vec<SocketAddr>, BlockchainDataProviderError> {
+        let (mut inbound, outbound) = self.get_peers_by_direction().await?;
+        inbound.extend(outbound);
+        Ok(inbound)
+    }
+
+    /// Get the (inbound, outbound) peers of the connected node
+    pub async fn get_peers_by_direction(
+        &self,
+    ) -> Result<(Vec<SocketAddr>, Vec<SocketAddr>), BlockchainDataProviderError> {
         match self.fetch(Request::Peers).await? {
-            Response::Peers { peers } => Ok(peers),
+            Response::Peers { inbound, outbound } => Ok((inbound, outbound)),
             _ => Err(RequestResponseError::IncorrectResponse.into()),
         }
     }
@@ -161,8 +207,82 @@ impl Client {
         &self,
     ) -> Result<[u8; 32], BlockchainDataProviderError> {
         match self.fetch(Request::LiveTransactionDifficulty).await? {
-            Response::LiveTransactionDifficulty { live_difficulty } => return Ok(live_difficulty),
-            _ => return Err(RequestResponseError::IncorrectResponse.into()),
+            Response::LiveTransactionDifficulty { live_difficulty } => Ok(live_difficulty),
+            _ => Err(RequestResponseError::IncorrectResponse.into()),
+        }
+    }
+
+    /// Get the initial block download progress of the connected node
+    pub async fn get_ibd_status(&self) -> Result<IbdStatus, BlockchainDataProviderError> {
+        match self.fetch(Request::IbdStatus).await? {
+            Response::IbdStatus { status } => Ok(status),
+            _ => Err(RequestResponseError::IncorrectResponse.into()),
+        }
+    }
+
+    /// Get stats of every peer of the connected node
+    pub async fn get_peer_info(&self) -> Result<Vec<PeerInfo>, BlockchainDataProviderError> {
+        match self.fetch(Request::PeerInfo).await? {
+            Response::PeerInfo { peers } => Ok(peers),
+            _ => Err(RequestResponseError::IncorrectResponse.into()),
+        }
+    }
+
+    /// Get a block template for `miner` with transactions from the mempool of the connected node, see `build_block_template`
+    pub async fn get_block_template(
+        &self,
+        miner: Public,
+    ) -> Result<BlockTemplate, BlockchainDataProviderError> {
+        match self.fetch(Request::BlockTemplate { miner }).await? {
+            Response::BlockTemplate { template } => Ok(template),
+            _ => Err(RequestResponseError::IncorrectResponse.into()),
+        }
+    }
+
+    /// Get the status of a transaction on the connected node. Transactions submitted through it are re-announced until they are mined or expire
+    pub async fn get_transaction_status(
+        &self,
+        id: TransactionId,
+    ) -> Result<TransactionStatus, BlockchainDataProviderError> {
+        match self.fetch(Request::TransactionStatus { id }).await? {
+            Response::TransactionStatus { status } => Ok(status),
+            _ => Err(RequestResponseError::IncorrectResponse.into()),
+        }
+    }
+
+    /// Get mempool statistics of the connected node
+    pub async fn get_mempool_info(&self) -> Result<MemPoolInfo, BlockchainDataProviderError> {
+        match self.fetch(Request::MempoolInfo).await? {
+            Response::MempoolInfo { info } => Ok(info),
+            _ => Err(RequestResponseError::IncorrectResponse.into()),
+        }
+    }
+
+    /// Ban an ip on the connected node for `duration` seconds, forever if `None`
+    pub async fn ban(
+        &self,
+        ip: IpAddr,
+        duration: Option<u64>,
+    ) -> Result<BanEntry, BlockchainDataProviderError> {
+        match self.fetch(Request::Ban { ip, duration }).await? {
+            Response::Ban { ban } => Ok(ban),
+            _ => Err(RequestResponseError::IncorrectResponse.into()),
+        }
+    }
+
+    /// Lift a ban on the connected node, returns true if the ip was banned
+    pub async fn unban(&self, ip: IpAddr) -> Result<bool, BlockchainDataProviderError> {
+        match self.fetch(Request::Unban { ip }).await? {
+            Response::Unban { was_banned } => Ok(was_banned),
+            _ => Err(RequestResponseError::IncorrectResponse.into()),
+        }
+    }
+
+    /// Get all active bans of the connected node
+    pub async fn list_bans(&self) -> Result<Vec<BanEntry>, BlockchainDataProviderError> {
+        match self.fetch(Request::ListBans).await? {
+            Response::ListBans { bans } => Ok(bans),
+            _ => Err(RequestResponseError::IncorrectResponse.into()),
         }
     }
 
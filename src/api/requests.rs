@@ -1,4 +1,4 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
@@ -11,7 +11,11 @@ use crate::{
         transaction::{Transaction, TransactionId, TransactionOutput},
     },
     crypto::{Hash, keys::Public},
-    full_node::node_state::ChainEvent,
+    full_node::{
+        ban_list::BanEntry, block_template::BlockTemplate, ibd::IbdStatus, mempool::MemPoolInfo,
+        node_state::ChainEvent, tx_relay::TransactionStatus,
+    },
+    node::peer::PeerInfo,
 };
 
 #[derive(Error, Debug, Serialize, Deserialize)]
@@ -46,10 +50,19 @@ pub enum Request {
     Reward,
     Peers,
     Mempool { page: u32 },
+    MempoolInfo,
     NewBlock { new_block: Block },
     NewTransaction { new_transaction: Transaction },
+    NewPrivateTransaction { new_transaction: Transaction },
     LiveTransactionDifficulty,
     SubscribeToChainEvents,
+    IbdStatus,
+    Ban { ip: IpAddr, duration: Option<u64> },
+    Unban { ip: IpAddr },
+    ListBans,
+    PeerInfo,
+    BlockTemplate { miner: Public },
+    TransactionStatus { id: TransactionId },
 }
 
 impl Request {
@@ -130,7 +143,8 @@ pub enum Response {
         reward: u64,
     },
     Peers {
-        peers: Vec<SocketAddr>,
+        inbound: Vec<SocketAddr>,
+        outbound: Vec<SocketAddr>,
     },
     Mempool {
         mempool: Vec<Transaction>,
@@ -148,6 +162,30 @@ pub enum Response {
     ChainEvent {
         event: ChainEvent,
     },
+    IbdStatus {
+        status: IbdStatus,
+    },
+    Ban {
+        ban: BanEntry,
+    },
+    Unban {
+        was_banned: bool,
+    },
+    ListBans {
+        bans: Vec<BanEntry>,
+    },
+    PeerInfo {
+        peers: Vec<PeerInfo>,
+    },
+    BlockTemplate {
+        template: BlockTemplate,
+    },
+    TransactionStatus {
+        status: TransactionStatus,
+    },
+    MempoolInfo {
+        info: MemPoolInfo,
+    },
 }
 
 impl Response {
@@ -0,0 +1,234 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+};
+
+use anyhow::anyhow;
+use clap::{Parser, Subcommand};
+use log::{error, info};
+use snap_coin::{
+    api::client::Client,
+    blockchain_data_provider::BlockchainDataProvider,
+    config::{ConfigError, NodeConfig},
+    core::blockchain::Blockchain,
+    crypto::RandomXMode,
+    full_node::{auto_peer::AutoPeerConfig, start_full_node},
+    logging::{LogConfig, init_logging},
+    node::connector::Endpoint,
+};
+
+/// Snap Coin full node
+#[derive(Parser)]
+#[command(name = "snap-coin-node", version)]
+struct Cli {
+    /// Config file (.toml or .json), flags override it
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Directory the node keeps its data in
+    #[arg(short, long)]
+    data_dir: Option<PathBuf>,
+
+    /// Port to accept peers on
+    #[arg(long)]
+    p2p_port: Option<u16>,
+
+    /// Address we tell peers to connect to us on
+    #[arg(long)]
+    advertise: Option<SocketAddr>,
+
+    /// Address the API server binds to (and `status` connects to)
+    #[arg(long)]
+    api: Option<SocketAddr>,
+
+    /// Peer to connect to on start (ip:port or host:port), can be repeated
+    #[arg(long = "peer")]
+    peers: Vec<Endpoint>,
+
+    /// Find and keep peers automatically
+    #[arg(long)]
+    auto_peer: bool,
+
+    /// SOCKS5 proxy (like Tor) to make all outbound connections through
+    #[arg(long)]
+    proxy: Option<SocketAddr>,
+
+    /// Keep only this many blocks on disk
+    #[arg(long)]
+    prune: Option<usize>,
+
+    /// Run RandomX in full mode, faster but uses about 2GB of memory
+    #[arg(long)]
+    randomx_full: bool,
+
+    /// Log level, or a full spec like `info,sled=warn`
+    #[arg(long)]
+    log_level: Option<String>,
+
+    /// Directory to write log files to
+    #[arg(long)]
+    log_dir: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Clone, Copy)]
+enum Command {
+    /// Run the node until Ctrl+C or SIGTERM (the default)
+    Run,
+    /// Rebuild the block index and UTXOs from the blocks on disk. The node must not be running
+    Reindex,
+    /// Print the status of a running node, through its API
+    Status,
+    /// Print the config (file and flags merged) as TOML
+    Config,
+}
+
+impl Cli {
+    /// Load the config file, and apply the flags on top of it
+    fn node_config(&self) -> Result<NodeConfig, ConfigError> {
+        let mut config = match &self.config {
+            Some(path) => NodeConfig::load(path)?,
+            None => NodeConfig::default(),
+        };
+        if let Some(data_dir) = &self.data_dir {
+            config.node_path = data_dir.clone();
+        }
+        if self.p2p_port.is_some() {
+            config.p2p_port = self.p2p_port;
+        }
+        if self.advertise.is_some() {
+            config.advertised_address = self.advertise;
+        }
+        if self.api.is_some() {
+            config.api_address = self.api;
+        }
+        config.peers.extend(self.peers.iter().cloned());
+        if self.auto_peer && config.auto_peer.is_none() {
+            config.auto_peer = Some(AutoPeerConfig::default());
+        }
+        if self.proxy.is_some() {
+            config.proxy = self.proxy;
+        }
+        if self.prune.is_some() {
+            config.prune_depth = self.prune;
+        }
+        if self.randomx_full {
+            config.randomx_mode = Some(RandomXMode::Full);
+        }
+
+        // The binary owns the process, so it always sets up logging
+        let log = config.log.get_or_insert_with(LogConfig::default);
+        if let Some(level) = &self.log_level {
+            log.level = level.clone();
+        }
+        if let Some(log_dir) = &self.log_dir {
+            log.directory = Some(log_dir.clone());
+        }
+        Ok(config)
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    let cli = Cli::parse();
+    let config = cli.node_config()?;
+
+    match cli.command.unwrap_or(Command::Run) {
+        Command::Run => run(config).await,
+        Command::Reindex => reindex(config),
+        Command::Status => status(config).await,
+        Command::Config => {
+            print!("{}", config.to_toml()?);
+            Ok(())
+        }
+    }
+}
+
+async fn run(config: NodeConfig) -> Result<(), anyhow::Error> {
+    let node = start_full_node(&config).await?;
+    info!("Node running in {}", config.node_path.display());
+
+    wait_for_signal().await?;
+    info!("Shutdown requested, press Ctrl+C again to exit right away");
+    tokio::spawn(async {
+        let _ = tokio::signal::ctrl_c().await;
+        error!("Exiting without a clean shutdown");
+        std::process::exit(1);
+    });
+
+    node.shutdown().await?;
+    Ok(())
+}
+
+/// Wait for Ctrl+C, or SIGTERM on unix
+async fn wait_for_signal() -> Result<(), std::io::Error> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
+
+fn reindex(config: NodeConfig) -> Result<(), anyhow::Error> {
+    let _logger = config.log.as_ref().map(init_logging).transpose()?;
+    let blockchain_path = config.node_path.join("blockchain");
+    let blockchain = Blockchain::reindex(
+        blockchain_path
+            .to_str()
+            .ok_or(anyhow!("Data directory is not valid UTF-8"))?,
+    )?;
+    println!(
+        "Reindexed {} blocks, tip {}",
+        blockchain.block_store().get_height(),
+        blockchain.block_store().get_last_block_hash().dump_base36()
+    );
+    Ok(())
+}
+
+async fn status(config: NodeConfig) -> Result<(), anyhow::Error> {
+    let mut api = config
+        .api_address
+        .ok_or(anyhow!("No API address configured, pass --api"))?;
+    if api.ip().is_unspecified() {
+        api.set_ip(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    }
+    let client = Client::connect(api).await?;
+
+    let height = client.get_height().await?;
+    let tip = match height {
+        0 => None,
+        height => client.get_block_hash_by_height(height - 1).await?,
+    };
+    let (inbound, outbound) = client.get_peers_by_direction().await?;
+    let ibd = client.get_ibd_status().await?;
+    let mempool = client.get_mempool_info().await?;
+
+    println!("Height:   {height}");
+    println!(
+        "Tip:      {}",
+        tip.map_or("none".to_string(), |tip| tip.dump_base36())
+    );
+    println!(
+        "Peers:    {} inbound, {} outbound",
+        inbound.len(),
+        outbound.len()
+    );
+    println!(
+        "IBD:      {:?} ({} / {} blocks)",
+        ibd.phase, ibd.blocks_applied, ibd.target_height
+    );
+    println!(
+        "Mempool:  {} transactions, {} bytes, ~{} blocks wait",
+        mempool.transactions, mempool.bytes, mempool.expected_wait
+    );
+    Ok(())
+}
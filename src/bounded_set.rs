@@ -1,5 +1,6 @@
 use std::collections::{HashSet, VecDeque};
 
+#[derive(Debug)]
 pub struct BoundedSet<T> {
     capacity: usize,
     set: HashSet<T>,
@@ -20,10 +21,10 @@ impl<T: std::hash::Hash + Eq + Clone> BoundedSet<T> {
             return;
         }
 
-        if self.set.len() == self.capacity {
-            if let Some(old) = self.order.pop_front() {
-                self.set.remove(&old);
-            }
+        if self.set.len() == self.capacity
+            && let Some(old) = self.order.pop_front()
+        {
+            self.set.remove(&old);
         }
 
         self.order.push_back(value.clone());
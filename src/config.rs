@@ -0,0 +1,245 @@
+use std::{
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    api::api_server::ApiError,
+    crypto::RandomXMode,
+    full_node::{
+        NodeHandle,
+        auto_peer::AutoPeerConfig,
+        ban_list::BanConfig,
+        mempool::MemPoolConfig,
+        p2p_server::{ConnectionLimits, P2PServerError},
+        start_full_node,
+        tx_relay::TxRelayConfig,
+    },
+    light_node::{LightNodeHandle, start_light_node},
+    logging::{LogConfig, LoggingError},
+    node::{connector::Endpoint, peer::PeerConfig},
+};
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("TOML error: {0}")]
+    Toml(String),
+
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("Unknown config format {0}, expected a .toml or .json file")]
+    UnknownFormat(String),
+}
+
+#[derive(Error, Debug)]
+pub enum StartError {
+    #[error("Logging error: {0}")]
+    Logging(#[from] LoggingError),
+
+    #[error("P2P server error: {0}")]
+    P2PServer(#[from] P2PServerError),
+
+    #[error("API error: {0}")]
+    Api(#[from] ApiError),
+}
+
+/// Everything needed to run a node. Loadable from TOML or JSON, missing fields get their defaults.
+/// Light nodes only use the path, peers, proxy, peer settings, RandomX mode and logging
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct NodeConfig {
+    /// Directory the node keeps its data in
+    pub node_path: PathBuf,
+    /// Port peers can connect to us on, `None` to not accept peers
+    pub p2p_port: Option<u16>,
+    /// Address we tell peers to connect to us on
+    pub advertised_address: Option<SocketAddr>,
+    /// Address the API server binds to, `None` for no API server
+    pub api_address: Option<SocketAddr>,
+    /// Settings of the auto peer daemon, `None` to not run it
+    pub auto_peer: Option<AutoPeerConfig>,
+    /// Peers to connect to on start
+    pub peers: Vec<Endpoint>,
+    /// SOCKS5 proxy (like Tor) to make all outbound connections through
+    pub proxy: Option<SocketAddr>,
+    pub connection_limits: ConnectionLimits,
+    pub peer: PeerConfig,
+    pub ban: BanConfig,
+    pub mempool: MemPoolConfig,
+    pub tx_relay: TxRelayConfig,
+    /// Keep only this many blocks on disk, `None` keeps every block
+    pub prune_depth: Option<usize>,
+    /// RandomX mode to switch to on start. It is process wide, `None` leaves it as it is
+    pub randomx_mode: Option<RandomXMode>,
+    /// Logger to set up on start. It is process wide, leave it empty if the process sets up its own
+    pub log: Option<LogConfig>,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        Self {
+            node_path: PathBuf::from("./node"),
+            p2p_port: None,
+            advertised_address: None,
+            api_address: None,
+            auto_peer: None,
+            peers: vec![],
+            proxy: None,
+            connection_limits: ConnectionLimits::default(),
+            peer: PeerConfig::default(),
+            ban: BanConfig::default(),
+            mempool: MemPoolConfig::default(),
+            tx_relay: TxRelayConfig::default(),
+            prune_depth: None,
+            randomx_mode: None,
+            log: None,
+        }
+    }
+}
+
+impl NodeConfig {
+    /// Default config for a node at `node_path`
+    pub fn new(node_path: impl Into<PathBuf>) -> Self {
+        Self {
+            node_path: node_path.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Load a config file, the format is picked by its extension (.toml or .json)
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)?;
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => Self::from_toml(&content),
+            Some("json") => Self::from_json(&content),
+            _ => Err(ConfigError::UnknownFormat(path.display().to_string())),
+        }
+    }
+
+    pub fn from_toml(content: &str) -> Result<Self, ConfigError> {
+        toml::from_str(content).map_err(|e| ConfigError::Toml(e.to_string()))
+    }
+
+    pub fn from_json(content: &str) -> Result<Self, ConfigError> {
+        Ok(serde_json::from_str(content)?)
+    }
+
+    pub fn to_toml(&self) -> Result<String, ConfigError> {
+        toml::to_string_pretty(self).map_err(|e| ConfigError::Toml(e.to_string()))
+    }
+
+    pub fn to_json(&self) -> Result<String, ConfigError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+/// Builds a `NodeConfig`, and starts a node with it
+#[derive(Clone, Debug, Default)]
+pub struct NodeBuilder {
+    config: NodeConfig,
+}
+
+impl NodeBuilder {
+    pub fn new(node_path: impl Into<PathBuf>) -> Self {
+        Self::from_config(NodeConfig::new(node_path))
+    }
+
+    /// Start from an existing (for example loaded) config
+    pub fn from_config(config: NodeConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn p2p_port(mut self, port: u16) -> Self {
+        self.config.p2p_port = Some(port);
+        self
+    }
+
+    pub fn advertised_address(mut self, address: SocketAddr) -> Self {
+        self.config.advertised_address = Some(address);
+        self
+    }
+
+    pub fn api_address(mut self, address: SocketAddr) -> Self {
+        self.config.api_address = Some(address);
+        self
+    }
+
+    pub fn auto_peer(mut self, auto_peer: AutoPeerConfig) -> Self {
+        self.config.auto_peer = Some(auto_peer);
+        self
+    }
+
+    /// Add a peer to connect to on start
+    pub fn peer(mut self, endpoint: Endpoint) -> Self {
+        self.config.peers.push(endpoint);
+        self
+    }
+
+    pub fn proxy(mut self, proxy: SocketAddr) -> Self {
+        self.config.proxy = Some(proxy);
+        self
+    }
+
+    pub fn connection_limits(mut self, connection_limits: ConnectionLimits) -> Self {
+        self.config.connection_limits = connection_limits;
+        self
+    }
+
+    pub fn peer_config(mut self, peer: PeerConfig) -> Self {
+        self.config.peer = peer;
+        self
+    }
+
+    pub fn ban_config(mut self, ban: BanConfig) -> Self {
+        self.config.ban = ban;
+        self
+    }
+
+    pub fn mempool_config(mut self, mempool: MemPoolConfig) -> Self {
+        self.config.mempool = mempool;
+        self
+    }
+
+    pub fn tx_relay_config(mut self, tx_relay: TxRelayConfig) -> Self {
+        self.config.tx_relay = tx_relay;
+        self
+    }
+
+    pub fn prune_depth(mut self, prune_depth: usize) -> Self {
+        self.config.prune_depth = Some(prune_depth);
+        self
+    }
+
+    pub fn randomx_mode(mut self, mode: RandomXMode) -> Self {
+        self.config.randomx_mode = Some(mode);
+        self
+    }
+
+    pub fn log(mut self, log: LogConfig) -> Self {
+        self.config.log = Some(log);
+        self
+    }
+
+    pub fn build(self) -> NodeConfig {
+        self.config
+    }
+
+    /// Start a full node, see `full_node::start_full_node`
+    pub async fn start_full_node(self) -> Result<NodeHandle, StartError> {
+        start_full_node(&self.config).await
+    }
+
+    /// Start a light node, see `light_node::start_light_node`
+    pub async fn start_light_node(self) -> Result<LightNodeHandle, StartError> {
+        start_light_node(&self.config).await
+    }
+}
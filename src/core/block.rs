@@ -84,16 +84,19 @@ impl Block {
     /// Get this blocks hashing buffer required to mine this transaction. Essentially makes sure that any hash attached to this block is not included in the block hashing buffer
     /// WARNING: Slow
     pub fn get_hashing_buf(&self) -> Result<Vec<u8>, EncodeError> {
+        self.to_header()?.get_hashing_buf()
+    }
+
+    /// Strip this block down to its header, which carries everything required to check its hash and pow
+    pub fn to_header(&self) -> Result<BlockHeader, EncodeError> {
         use sha2::{Digest, Sha256};
 
-        // Clone once and normalize
-        let mut hash_less_block = self.clone();
-        hash_less_block.meta.hash = None;
+        let mut stripped_transactions = self.transactions.clone();
 
         // SCIP-1: We hash the transaction inputs with sha256 to avoid hashing them with random x during validation
-        let mut transactions_digest = Vec::with_capacity(hash_less_block.transactions.len() * 32);
+        let mut transactions_digest = Vec::with_capacity(stripped_transactions.len() * 32);
 
-        for tx in &mut hash_less_block.transactions {
+        for tx in &mut stripped_transactions {
             // Hash the transaction itself (before stripping)
             let mut encoded_tx_io =
                 bincode::encode_to_vec(tx.inputs.clone(), bincode::config::standard())?;
@@ -110,13 +113,13 @@ impl Block {
             tx.outputs.clear();
         }
 
-        // Encode normalized block
-        let mut buf = bincode::encode_to_vec(hash_less_block, bincode::config::standard())?;
-        if self.timestamp > SCIP_1_MIGRATION {
-            buf.extend_from_slice(&transactions_digest);
-        }
-
-        Ok(buf)
+        Ok(BlockHeader {
+            stripped_transactions,
+            transactions_digest,
+            timestamp: self.timestamp,
+            nonce: self.nonce,
+            meta: self.meta.clone(),
+        })
     }
 
     /// Mine this block and attach its hash.
@@ -238,3 +241,82 @@ pub struct BlockMetadata {
     pub merkle_tree_root: [u8; 32],
     pub address_inclusion_filter: AddressInclusionFilter,
 }
+
+/// A block without its transaction inputs and outputs. Big enough to verify the blocks hash, pow and difficulties, small enough to download a whole chain of them before any block bodies
+#[derive(Encode, Decode, Serialize, Deserialize, Clone, Debug)]
+pub struct BlockHeader {
+    /// Block transactions with their inputs and outputs cleared
+    pub stripped_transactions: Vec<Transaction>,
+    /// SCIP-1 sha256 digests of every transactions io, in transaction order
+    pub transactions_digest: Vec<u8>,
+    pub timestamp: u64,
+    pub nonce: u64,
+    pub meta: BlockMetadata,
+}
+
+impl BlockHeader {
+    /// Get the hashing buffer of the block this header belongs to
+    pub fn get_hashing_buf(&self) -> Result<Vec<u8>, EncodeError> {
+        let hash_less_block = Block {
+            transactions: self.stripped_transactions.clone(),
+            timestamp: self.timestamp,
+            nonce: self.nonce,
+            meta: BlockMetadata {
+                hash: None,
+                ..self.meta.clone()
+            },
+        };
+
+        // Encode normalized block
+        let mut buf = bincode::encode_to_vec(hash_less_block, bincode::config::standard())?;
+        if self.timestamp > SCIP_1_MIGRATION {
+            buf.extend_from_slice(&self.transactions_digest);
+        }
+
+        Ok(buf)
+    }
+
+    /// Amount of transactions in the block this header belongs to
+    pub fn transaction_count(&self) -> usize {
+        self.stripped_transactions.len()
+    }
+
+    /// Checks if the attached block hash is valid
+    pub fn validate_block_hash(&self) -> Result<(), BlockError> {
+        if !self
+            .meta
+            .hash
+            .ok_or(BlockError::IncompleteBlock)?
+            .compare_with_data(
+                &self
+                    .get_hashing_buf()
+                    .map_err(|_| BlockError::EncodeError)?,
+            )
+        {
+            return Err(BlockError::InvalidBlockHash);
+        }
+        Ok(())
+    }
+
+    /// Checks if the passed difficulties match the headers difficulties, and if the header hash beats them
+    pub fn validate_difficulties(
+        &self,
+        real_block_pow_difficulty: &[u8; 32],
+        real_tx_pow_difficulty: &[u8; 32],
+    ) -> Result<(), BlockError> {
+        if self.meta.block_pow_difficulty != *real_block_pow_difficulty
+            || self.meta.tx_pow_difficulty != *real_tx_pow_difficulty
+        {
+            return Err(BlockError::DifficultyMismatch);
+        }
+        if BigUint::from_bytes_be(&*self.meta.hash.ok_or(BlockError::IncompleteBlock)?)
+            > BigUint::from_bytes_be(&calculate_block_difficulty(
+                real_block_pow_difficulty,
+                self.transaction_count(),
+            ))
+        {
+            return Err(BlockError::BlockPowDifficultyIncorrect);
+        }
+        Ok(())
+    }
+}
@@ -2,6 +2,7 @@ use std::{
     collections::HashMap,
     fs::{self, File},
     io::Write,
+    path::Path,
     sync::RwLock,
 };
 
@@ -165,6 +166,40 @@ impl BlockStore {
         Ok(())
     }
 
+    /// Delete blocks (and their UTXO diffs) that are more than `keep` blocks below the tip. They can't be served or popped afterwards, the index is kept.
+    /// Returns the amount of blocks deleted
+    pub fn prune(&self, keep: usize) -> Result<usize, BlockStoreError> {
+        let mut height = self.get_height().saturating_sub(keep);
+        let mut pruned = 0;
+        // Everything below the first missing block was pruned before
+        while height > 0 {
+            height -= 1;
+            let block_path = self.block_path_by_height(height);
+            if !Path::new(&block_path).exists() {
+                break;
+            }
+            fs::remove_file(block_path)?;
+            let _ = fs::remove_file(self.utxo_diffs_path_by_height(height));
+            pruned += 1;
+        }
+        Ok(pruned)
+    }
+
+    /// Lowest height that still has its block on disk, above 0 once blocks were pruned (the height if there are no blocks)
+    pub fn first_block(&self) -> usize {
+        // Pruning deletes from the bottom, so the blocks on disk are one range ending at the tip
+        let (mut low, mut high) = (0, self.get_height());
+        while low < high {
+            let middle = low + (high - low) / 2;
+            if Path::new(&self.block_path_by_height(middle)).exists() {
+                high = middle;
+            } else {
+                low = middle + 1;
+            }
+        }
+        low
+    }
+
     /// Gets current block height (count of all blocks)
     pub fn get_height(&self) -> usize {
         *self.height.read().unwrap()
@@ -231,6 +266,27 @@ impl BlockStore {
         self.get_utxo_diffs_by_height(height)
     }
 
+    /// Read the block file at `height` without looking at the index, `None` if it is missing or unreadable. Used to replay blocks on a reindex
+    pub fn read_block_file(&self, height: usize) -> Option<Block> {
+        Self::load_block_from_path(&self.block_path_by_height(height)).ok()
+    }
+
+    /// Highest height that has a block file on disk, indexed or not
+    pub fn last_block_file(&self) -> Option<usize> {
+        fs::read_dir(&self.store_path)
+            .ok()?
+            .filter_map(Result::ok)
+            .filter_map(|entry| {
+                entry
+                    .file_name()
+                    .to_str()?
+                    .strip_suffix(".dat")?
+                    .parse::<usize>()
+                    .ok()
+            })
+            .max()
+    }
+
     fn load_utxo_diffs_from_path(path: &str) -> Result<UTXODiff, BlockStoreError> {
         let data = fs::read(path).map_err(|e| BlockStoreError::IO(e.to_string()))?;
         let (diffs, _) = bincode::decode_from_slice(&data, bincode::config::standard())
@@ -267,6 +323,25 @@ impl BlockStore {
         None
     }
 
+    /// Get the height of the block a transaction is in, newest blocks are searched first.
+    /// The search stops at the first block timestamped before `since`, so it doesn't read the whole chain
+    pub fn get_transaction_height(&self, tx_id: TransactionId, since: u64) -> Option<usize> {
+        for height in (0..self.get_height()).rev() {
+            let block = self.get_block_by_height(height)?;
+            if block.timestamp < since {
+                return None;
+            }
+            if block
+                .transactions
+                .iter()
+                .any(|tx| tx.transaction_id == Some(tx_id))
+            {
+                return Some(height);
+            }
+        }
+        None
+    }
+
     pub fn iter_blocks(&self) -> impl DoubleEndedIterator<Item = Result<Block, BlockStoreError>> + '_ {
         let height = self.get_height();
         (0..height).map(move |h| {
@@ -5,21 +5,26 @@ use std::{
 };
 
 use bincode::{Decode, Encode};
+use log::{info, warn};
 use num_bigint::BigUint;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 use crate::{
     core::{
-        block::{Block, BlockError, MAX_TRANSACTIONS_PER_BLOCK},
+        block::{Block, BlockError, BlockHeader, MAX_TRANSACTIONS_PER_BLOCK},
         block_store::{BlockStore, BlockStoreError},
         difficulty::DifficultyState,
         transaction::{Transaction, TransactionError, TransactionId},
         utxo::{UTXODiff, UTXOs},
     },
+    crypto::Hash,
     economics::{DEV_WALLET, EXPIRATION_TIME, calculate_dev_fee, get_block_reward},
 };
 
+/// Min amount of blocks a pruned node keeps, more than a reorg (or a sync) ever rolls back
+pub const MIN_PRUNE_DEPTH: usize = 100;
+
 #[derive(Error, Debug, Serialize, Deserialize, Clone, Encode, Decode)]
 pub enum BlockchainError {
     #[error("IO error: {0}")]
@@ -81,11 +86,30 @@ pub enum BlockchainError {
 
     #[error("Live transaction difficulty not beat")]
     LiveTransactionDifficulty,
+
+    #[error("Blocks were pruned, a reindex needs every block")]
+    Pruned,
+
+    #[error("Transaction spends a chain of too many unconfirmed transactions")]
+    ChainTooLong,
+
+    #[error("Mempool is full of transactions with stronger pow")]
+    MemPoolFull,
+
+    #[error("Reindex stopped early at block {0}, old data is kept in blockchain.reindex/")]
+    ReindexIncomplete(usize),
+
+    #[error("Transaction can't be applied to the current chain: {0}")]
+    TransactionNotApplicable(String),
 }
 
 impl From<TransactionError> for BlockchainError {
     fn from(err: TransactionError) -> Self {
-        BlockchainError::InvalidTransaction(err.to_string())
+        if err.is_invalid_on_any_chain() {
+            BlockchainError::InvalidTransaction(err.to_string())
+        } else {
+            BlockchainError::TransactionNotApplicable(err.to_string())
+        }
     }
 }
 
@@ -119,33 +143,88 @@ impl Blockchain {
         }
 
         match Self::load_blockchain_data(&blockchain_path) {
-            Ok(blockchain_data) => {
-                return Blockchain {
-                    block_store: blockchain_data.block_store,
-                    utxos: UTXOs::new(blockchain_path.clone()),
-                    difficulty_state: blockchain_data.difficulty_state,
-                    blockchain_path,
-                };
+            Ok(blockchain_data) => Blockchain {
+                block_store: blockchain_data.block_store,
+                utxos: UTXOs::new(blockchain_path.clone()),
+                difficulty_state: blockchain_data.difficulty_state,
+                blockchain_path,
+            },
+            Err(_) => Blockchain {
+                utxos: UTXOs::new(blockchain_path.clone()),
+                difficulty_state: DifficultyState::new_default(),
+                block_store: BlockStore::new_empty(&format!("{}blocks/", blockchain_path)),
+                blockchain_path,
+            },
+        }
+    }
+
+    /// Rebuild the block index, difficulty state and UTXOs at `blockchain_path` by replaying the block files on disk, from genesis.
+    /// Replay stops at the first missing or invalid block. If block files are left after that, the old data is kept and `ReindexIncomplete` returned.
+    /// An interrupted reindex picks up where it left off when run again
+    pub fn reindex(blockchain_path: &str) -> Result<Self, BlockchainError> {
+        let mut root = blockchain_path.to_string();
+        if !root.ends_with('/') {
+            root.push('/');
+        }
+        let data_path = format!("{root}blockchain/");
+        let backup_path = format!("{root}blockchain.reindex/");
+        let io = |e: std::io::Error| BlockchainError::Io(e.to_string());
+
+        if Path::new(&backup_path).exists() {
+            // Previous reindex was interrupted, start over from its backup
+            if Path::new(&data_path).exists() {
+                fs::remove_dir_all(&data_path).map_err(io)?;
+            }
+        } else if Path::new(&data_path).exists() {
+            // Fails if the node is still running, sled locks its database
+            drop(sled::open(&data_path).map_err(|e| BlockchainError::UTXOs(e.to_string()))?);
+
+            let blocks_path = format!("{data_path}blocks/");
+            let has_genesis = Path::new(&format!("{blocks_path}0.dat")).exists();
+            let has_blocks = fs::read_dir(&blocks_path)
+                .map_err(io)?
+                .filter_map(Result::ok)
+                .any(|entry| entry.file_name().to_string_lossy().ends_with(".dat"));
+            if has_blocks && !has_genesis {
+                return Err(BlockchainError::Pruned);
+            }
+            fs::rename(&data_path, &backup_path).map_err(io)?;
+        }
+
+        let blockchain = Blockchain::new(blockchain_path);
+        let old_blocks = BlockStore::new_empty(&format!("{backup_path}blocks/"));
+        let mut height = 0;
+        while let Some(block) = old_blocks.read_block_file(height) {
+            if let Err(e) = blockchain.add_block(block, true) {
+                warn!("Reindex stopped at invalid block {height}, error: {e}");
+                break;
             }
-            Err(_) => {
-                return Blockchain {
-                    utxos: UTXOs::new(blockchain_path.clone()),
-                    difficulty_state: DifficultyState::new_default(),
-                    block_store: BlockStore::new_empty(&format!("{}blocks/", blockchain_path)),
-                    blockchain_path,
-                };
+            height += 1;
+            if height % 1000 == 0 {
+                info!("Reindexed {height} blocks");
             }
         }
+        blockchain.flush()?;
+
+        // Blocks after a gap or an invalid block would be lost with the backup
+        if let Some(last) = old_blocks.last_block_file()
+            && height <= last
+        {
+            return Err(BlockchainError::ReindexIncomplete(height));
+        }
+        if Path::new(&backup_path).exists() {
+            fs::remove_dir_all(&backup_path).map_err(io)?;
+        }
+        info!("Reindex done, height {height}");
+        Ok(blockchain)
     }
 
     /// Load the blockchain data
     fn load_blockchain_data(blockchain_path: &str) -> Result<BlockchainData, BlockchainError> {
         let mut file = File::open(format!("{}blockchain.dat", blockchain_path))
             .map_err(|e| BlockchainError::Io(e.to_string()))?;
-        Ok(
-            bincode::decode_from_std_read(&mut file, bincode::config::standard())
-                .map_err(|e| BlockchainError::BincodeDecode(e.to_string()))?,
-        )
+        bincode::decode_from_std_read(&mut file, bincode::config::standard())
+            .map_err(|e| BlockchainError::BincodeDecode(e.to_string()))
     }
 
     /// Save the blockchain data
@@ -156,11 +235,26 @@ impl Blockchain {
             difficulty_state: self.difficulty_state.clone(),
             block_store: self.block_store().clone(),
         };
-        file.sync_all()
-            .map_err(|e| BlockchainError::Io(e.to_string()))?;
 
         bincode::encode_into_std_write(blockchain_data, &mut file, bincode::config::standard())
             .map_err(|e| BlockchainError::BincodeEncode(e.to_string()))?;
+        file.sync_all()
+            .map_err(|e| BlockchainError::Io(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Delete all but the last `keep` blocks from disk, `keep` is raised to `MIN_PRUNE_DEPTH` so reorgs still work. Returns the amount of blocks deleted
+    pub fn prune(&self, keep: usize) -> Result<usize, BlockchainError> {
+        Ok(self.block_store.prune(keep.max(MIN_PRUNE_DEPTH))?)
+    }
+
+    /// Write the blockchain data and UTXOs to disk. Both are already saved on every block, this makes sure nothing is left in buffers
+    pub fn flush(&self) -> Result<(), BlockchainError> {
+        self.save_blockchain_data()?;
+        self.utxos
+            .db
+            .flush()
+            .map_err(|e| BlockchainError::UTXOs(e.to_string()))?;
         Ok(())
     }
 
@@ -200,12 +294,12 @@ impl Blockchain {
                 // Check for double spending
                 for input in &transaction.inputs {
                     let key = (input.transaction_id, input.output_index);
-                    if !used_inputs.insert(key.clone()) {
+                    if !used_inputs.insert(key) {
                         return Err(BlockchainError::DoubleSpend);
                     }
                 }
 
-                validate_transaction_timestamp_in_block(&transaction, &new_block)?;
+                validate_transaction_timestamp_in_block(transaction, &new_block)?;
             } else {
                 // Reward tx
                 if seen_reward_transaction {
@@ -262,8 +356,9 @@ impl Blockchain {
         Ok(())
     }
 
-    /// Remove the last block added to the blockchain, and update the states of utxos and difficulty manager to return the blockchain to the state it was before the last block was added
-    pub fn pop_block(&self) -> Result<(), BlockchainError> {
+    /// Remove the last block added to the blockchain, and update the states of utxos and difficulty manager to return the blockchain to the state it was before the last block was added.
+    /// Returns the removed block
+    pub fn pop_block(&self) -> Result<Block, BlockchainError> {
         if self.block_store().get_height() == 0 {
             return Err(BlockchainError::NoBlocksToPop);
         }
@@ -301,7 +396,7 @@ impl Blockchain {
         // Save blockchain data
         self.save_blockchain_data()?;
 
-        Ok(())
+        Ok(recalled_block)
     }
 
     pub fn get_utxos(&self) -> &UTXOs {
@@ -325,6 +420,33 @@ impl Blockchain {
     }
 }
 
+/// Validate a block header in the context of its expected previous block and the difficulty state it is built on
+/// On success the difficulty state is advanced past this header, so a chain of headers can be validated by calling this in order
+/// WARNING: Slow, checks the header hash
+pub fn validate_header(
+    header: &BlockHeader,
+    previous_block: Hash,
+    difficulty_state: &DifficultyState,
+) -> Result<(), BlockchainError> {
+    if header.meta.previous_block != previous_block {
+        return Err(BlockchainError::InvalidPreviousBlockHash);
+    }
+
+    if header.transaction_count() > MAX_TRANSACTIONS_PER_BLOCK {
+        return Err(BlockchainError::TooManyTransactions);
+    }
+
+    header.validate_difficulties(
+        &difficulty_state.get_block_difficulty(),
+        &difficulty_state.get_transaction_difficulty(),
+    )?;
+    header.validate_block_hash()?;
+
+    difficulty_state.update_difficulty_with(header.timestamp, header.transaction_count());
+
+    Ok(())
+}
+
 /// Returns true if transaction timestamp is valid in the context of a block
 pub fn validate_transaction_timestamp_in_block(
     transaction: &Transaction,
@@ -87,10 +87,10 @@ impl BlockchainDataProvider for Blockchain {
                 .0;
 
             for (index, output_opt) in outputs.into_iter().enumerate() {
-                if let Some(output) = output_opt {
-                    if output.receiver == address {
-                        available_outputs.push((txid, output, index));
-                    }
+                if let Some(output) = output_opt
+                    && output.receiver == address
+                {
+                    available_outputs.push((txid, output, index));
                 }
             }
         }
@@ -10,9 +10,7 @@ use crate::{
 };
 use bincode::{Decode, Encode};
 use num_bigint::BigUint;
-use rand::Rng;
 use log::debug;
-use tokio::time;
 
 pub const STARTING_BLOCK_DIFFICULTY: [u8; 32] = [u8::MAX; 32];
 pub const STARTING_TX_DIFFICULTY: [u8; 32] = [u8::MAX; 32];
@@ -37,9 +35,14 @@ impl DifficultyState {
 
     /// Update the network difficulties after adding a new block to the blockchain
     pub fn update_difficulty(&self, new_block: &Block) {
+        self.update_difficulty_with(new_block.timestamp, new_block.transactions.len());
+    }
+
+    /// Update the network difficulties with only the timestamp and transaction count of a new block (eg. from a block header)
+    pub fn update_difficulty_with(&self, timestamp: u64, transaction_count: usize) {
         let last_timestamp = *self.last_timestamp.read().unwrap();
         // delta is the difference between the timestamp of the new block and the last block.
-        let delta = new_block.timestamp.saturating_sub(last_timestamp);
+        let delta = timestamp.saturating_sub(last_timestamp);
 
         // raw_ratio is the ratio of the actual time taken to mine the block to the target time. If it's above 1, blocks are being mined too slowly, if it's below 1, blocks are being mined too quickly.
         let raw_ratio = delta as f64 / TARGET_TIME as f64;
@@ -68,7 +71,7 @@ impl DifficultyState {
 
         // Transaction difficulty
         let tx_ratio = (clamp_f(
-            TX_TARGET as f64 / new_block.transactions.len() as f64,
+            TX_TARGET as f64 / transaction_count as f64,
             MAX_DIFF_CHANGE,
             2.0 - MAX_DIFF_CHANGE,
         ) * 1000.0) as u64;
@@ -79,7 +82,7 @@ impl DifficultyState {
             biguint_to_32_bytes(tx_big.min(max_256_bui()).max(BigUint::ZERO));
 
         // Update last timestamp
-        *self.last_timestamp.write().unwrap() = new_block.timestamp;
+        *self.last_timestamp.write().unwrap() = timestamp;
     }
 
     pub fn get_block_difficulty(&self) -> [u8; 32] {
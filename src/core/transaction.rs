@@ -65,6 +65,22 @@ pub enum TransactionError {
     Other(String),
 }
 
+impl TransactionError {
+    /// True if the transaction is broken no matter the chain state (bad hash or signature, amounts that don't add up).
+    /// Missing or spent inputs and too little pow for the current difficulty can change with the chain, local failures aren't the transaction's fault
+    pub fn is_invalid_on_any_chain(&self) -> bool {
+        !matches!(
+            self,
+            TransactionError::EncodeError(_)
+                | TransactionError::InsufficientDifficulty(_)
+                | TransactionError::InputNotFound(_)
+                | TransactionError::InvalidInputIndex { .. }
+                | TransactionError::SpentInputIndex
+                | TransactionError::Other(_)
+        )
+    }
+}
+
 /// A transaction input, that are funding a set transaction output, that must exist in the current utxo set
 #[derive(Encode, Decode, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct TransactionInput {
@@ -100,7 +116,7 @@ impl Transaction {
     pub fn new_transaction_now(
         inputs: Vec<TransactionInput>,
         outputs: Vec<TransactionOutput>,
-        signing_keys: &mut Vec<Private>,
+        signing_keys: &mut [Private],
     ) -> Result<Self, EncodeError> {
         let mut transaction = Self {
             inputs,
@@ -130,13 +146,13 @@ impl Transaction {
     ) -> Result<(), EncodeError> {
         let mut target = BigUint::from_bytes_be(live_tx_difficulty);
 
-        if let Some(margin) = difficulty_margin {
-            if margin > 0.0 {
-                let scale = 1.0 - margin; // smaller target -> harder
-                let scale_int = (scale * 1_000_000.0) as u64;
-                target *= BigUint::from(scale_int);
-                target /= BigUint::from(1_000_000u64);
-            }
+        if let Some(margin) = difficulty_margin
+            && margin > 0.0
+        {
+            let scale = 1.0 - margin; // smaller target -> harder
+            let scale_int = (scale * 1_000_000.0) as u64;
+            target *= BigUint::from(scale_int);
+            target /= BigUint::from(1_000_000u64);
         }
         let mut rng = rand::rng();
         loop {
@@ -160,10 +176,7 @@ impl Transaction {
             input.signature = None; // remove all signatures for signing
         }
 
-        Ok(bincode::encode_to_vec(
-            signature_less_transaction,
-            bincode::config::standard(),
-        )?)
+        bincode::encode_to_vec(signature_less_transaction, bincode::config::standard())
     }
 
     /// Get the buffer that needs to be hashed to compute the pow puzzle
@@ -172,10 +185,7 @@ impl Transaction {
 
         signature_less_transaction.transaction_id = None;
 
-        Ok(bincode::encode_to_vec(
-            signature_less_transaction,
-            bincode::config::standard(),
-        )?)
+        bincode::encode_to_vec(signature_less_transaction, bincode::config::standard())
     }
 
     pub fn check_completeness(&self) -> Result<(), TransactionError> {
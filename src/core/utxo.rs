@@ -1,6 +1,6 @@
 use bincode::{Decode, Encode};
 use num_bigint::BigUint;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::ops::Deref;
 
 use crate::{
@@ -83,6 +83,23 @@ impl UTXOs {
         transaction: &Transaction,
         tx_hashing_difficulty: &BigUint,
         is_ibd: bool
+    ) -> Result<(), TransactionError> {
+        self.validate_transaction_with_pending(
+            transaction,
+            tx_hashing_difficulty,
+            is_ibd,
+            &HashMap::new(),
+        )
+    }
+
+    /// Validate a transaction in the context of these UTXOs, that may also spend outputs of `pending` transactions.
+    /// Pending transactions are not confirmed yet (in the mempool), the caller makes sure their outputs are not spent twice
+    pub fn validate_transaction_with_pending(
+        &self,
+        transaction: &Transaction,
+        tx_hashing_difficulty: &BigUint,
+        is_ibd: bool,
+        pending: &HashMap<TransactionId, Vec<TransactionOutput>>,
     ) -> Result<(), TransactionError> {
         let tx_id = transaction
             .transaction_id
@@ -117,6 +134,11 @@ impl UTXOs {
         for input in &transaction.inputs {
             let prev_outputs = self
                 .get_tx_outputs(&input.transaction_id)
+                .or_else(|| {
+                    pending
+                        .get(&input.transaction_id)
+                        .map(|outputs| outputs.iter().copied().map(Some).collect())
+                })
                 .ok_or(TransactionError::InputNotFound(tx_id.dump_base36()))?;
 
             let output = prev_outputs.get(input.output_index).ok_or(
@@ -252,7 +274,7 @@ impl UTXOs {
                 .map(|(o, _)| o)
                 .unwrap_or_default()
             })
-            .filter_map(|out| out)
+            .flatten()
             .filter(|out| out.receiver == address)
             .map(|out| out.amount)
             .sum()
@@ -286,18 +308,16 @@ impl UTXOs {
     pub fn get_all_utxos(&self) -> Vec<(TransactionId, TransactionOutput, usize)> {
         let mut all_utxos = Vec::new();
 
-        for item in self.db.iter() {
-            if let Ok((txid_bytes, value)) = item {
-                // Convert bytes to transaction ID
-                if let Ok(txid_str) = String::from_utf8(txid_bytes.to_vec()) {
-                    if let Some(txid) = TransactionId::new_from_base36(&txid_str) {
-                        // Decode outputs
-                        if let Ok((outputs, _)) = bincode::decode_from_slice::<Vec<Option<TransactionOutput>>, _>(&value, bincode::config::standard()) {
-                            for (index, output_opt) in outputs.into_iter().enumerate() {
-                                if let Some(output) = output_opt {
-                                    all_utxos.push((txid, output, index));
-                                }
-                            }
+        for (txid_bytes, value) in self.db.iter().flatten() {
+            // Convert bytes to transaction ID
+            if let Ok(txid_str) = String::from_utf8(txid_bytes.to_vec())
+                && let Some(txid) = TransactionId::new_from_base36(&txid_str)
+            {
+                // Decode outputs
+                if let Ok((outputs, _)) = bincode::decode_from_slice::<Vec<Option<TransactionOutput>>, _>(&value, bincode::config::standard()) {
+                    for (index, output_opt) in outputs.into_iter().enumerate() {
+                        if let Some(output) = output_opt {
+                            all_utxos.push((txid, output, index));
                         }
                     }
                 }
@@ -42,7 +42,7 @@ impl AddressInclusionFilter {
         let num_hashes = ((num_bits as f64 / address_count as f64) * 2f64.ln()).ceil() as u32;
 
         let mut filter = Self {
-            bits: vec![0u8; (num_bits + 7) / 8],
+            bits: vec![0u8; num_bits.div_ceil(8)],
             num_bits,
             num_hashes,
         };
@@ -55,7 +55,7 @@ impl AddressInclusionFilter {
     }
 
     pub fn search_filter(&self, address: Public) -> Result<bool, AddressInclusionFilterError> {
-        Ok(self.contains(&*address))
+        Ok(self.contains(&address))
     }
 
     fn insert(&mut self, address: &[u8; 32]) {
@@ -83,17 +83,17 @@ impl AddressInclusionFilter {
 
     fn insert_transaction(bloom: &mut Self, tx: &Transaction) {
         for input in &tx.inputs {
-            bloom.insert(&*input.output_owner);
+            bloom.insert(&input.output_owner);
         }
         for output in &tx.outputs {
-            bloom.insert(&*output.receiver);
+            bloom.insert(&output.receiver);
         }
     }
 
     fn hash(&self, address: &[u8; 32], i: u32) -> u64 {
         let mut hasher = Sha256::new();
         hasher.update(address);
-        hasher.update(&i.to_le_bytes());
+        hasher.update(i.to_le_bytes());
         let result = hasher.finalize();
         u64::from_le_bytes(result[0..8].try_into().unwrap())
     }
@@ -150,12 +150,12 @@ mod tests {
 
         let filter = AddressInclusionFilter::create_filter(&[tx1.clone(), tx2.clone()]).unwrap();
 
-        assert!(filter.contains(&*addr1));
-        assert!(filter.contains(&*addr2));
+        assert!(filter.contains(&addr1));
+        assert!(filter.contains(&addr2));
 
         let unknown_addr = random_public_key();
         // Bloom filter may produce false positives, but very unlikely with our parameters
-        assert!(!filter.contains(&*unknown_addr));
+        assert!(!filter.contains(&unknown_addr));
     }
 
     #[test]
@@ -164,9 +164,9 @@ mod tests {
         let tx = create_dummy_tx(vec![addr]);
         let filter = AddressInclusionFilter::create_filter(&[tx]).unwrap();
 
-        assert_eq!(filter.search_filter(addr).unwrap(), true);
+        assert!(filter.search_filter(addr).unwrap());
         let unknown_addr = random_public_key();
-        assert_eq!(filter.search_filter(unknown_addr).unwrap(), false);
+        assert!(!filter.search_filter(unknown_addr).unwrap());
     }
 
     #[test]
@@ -176,8 +176,8 @@ mod tests {
         let tx = create_dummy_tx(vec![addr1, addr2]);
 
         let filter = AddressInclusionFilter::create_filter(&[tx]).unwrap();
-        assert!(filter.contains(&*addr1));
-        assert!(filter.contains(&*addr2));
+        assert!(filter.contains(&addr1));
+        assert!(filter.contains(&addr2));
     }
 
     #[test]
@@ -185,6 +185,6 @@ mod tests {
         let filter = AddressInclusionFilter::create_filter(&[]).unwrap();
         // No addresses, so filter should not contain any random address
         let addr = random_public_key();
-        assert!(!filter.contains(&*addr));
+        assert!(!filter.contains(&addr));
     }
 }
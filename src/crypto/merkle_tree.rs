@@ -24,7 +24,7 @@ impl MerkleTree {
             .iter()
             .map(|tx| {
                 let mut hasher = Sha256::new();
-                hasher.update(&tx.dump_buf());
+                hasher.update(tx.dump_buf());
                 MerkleNode {
                     hash: hasher.finalize().into(),
                     left: None,
@@ -54,8 +54,8 @@ impl MerkleTree {
                 };
 
                 let mut hasher = Sha256::new();
-                hasher.update(&left.hash);
-                hasher.update(&right.hash);
+                hasher.update(left.hash);
+                hasher.update(right.hash);
                 let parent_hash: [u8; 32] = hasher.finalize().into();
 
                 next_level.push(MerkleNode {
@@ -83,8 +83,8 @@ impl MerkleTree {
             if node.left.is_none() && node.right.is_none() {
                 return node.hash == tx_hash;
             }
-            node.left.as_ref().map_or(false, |l| verify(l, tx_hash))
-                || node.right.as_ref().map_or(false, |r| verify(r, tx_hash))
+            node.left.as_ref().is_some_and(|l| verify(l, tx_hash))
+                || node.right.as_ref().is_some_and(|r| verify(r, tx_hash))
         }
         verify(&self.root, tx_hash)
     }
@@ -96,20 +96,20 @@ impl MerkleTree {
                 return node.hash == tx_hash;
             }
 
-            if let Some(left) = &node.left {
-                if helper(left, tx_hash, path) {
-                    let right_hash = node.right.as_ref().unwrap().hash;
-                    path.push((right_hash, false)); // sibling is right
-                    return true;
-                }
+            if let Some(left) = &node.left
+                && helper(left, tx_hash, path)
+            {
+                let right_hash = node.right.as_ref().unwrap().hash;
+                path.push((right_hash, false)); // sibling is right
+                return true;
             }
 
-            if let Some(right) = &node.right {
-                if helper(right, tx_hash, path) {
-                    let left_hash = node.left.as_ref().unwrap().hash;
-                    path.push((left_hash, true)); // sibling is left
-                    return true;
-                }
+            if let Some(right) = &node.right
+                && helper(right, tx_hash, path)
+            {
+                let left_hash = node.left.as_ref().unwrap().hash;
+                path.push((left_hash, true)); // sibling is left
+                return true;
             }
 
             false
@@ -140,9 +140,9 @@ impl MerkleTreeProof {
             let mut hasher = Sha256::new();
             if *is_left {
                 hasher.update(sibling);
-                hasher.update(&hash);
+                hasher.update(hash);
             } else {
-                hasher.update(&hash);
+                hasher.update(hash);
                 hasher.update(sibling);
             }
             hash = hasher.finalize().into();
@@ -157,7 +157,7 @@ impl MerkleTreeProof {
         let mut path = Vec::new();
 
         while layer.len() > 1 {
-            if layer.len() % 2 != 0 {
+            if !layer.len().is_multiple_of(2) {
                 layer.push(*layer.last().unwrap());
             }
 
@@ -168,8 +168,8 @@ impl MerkleTreeProof {
             let mut next_layer = Vec::with_capacity(layer.len() / 2);
             for pair in layer.chunks(2) {
                 let mut hasher = Sha256::new();
-                hasher.update(&pair[0]);
-                hasher.update(&pair[1]);
+                hasher.update(pair[0]);
+                hasher.update(pair[1]);
                 next_layer.push(hasher.finalize().into());
             }
             layer = next_layer;
@@ -198,14 +198,14 @@ mod tests {
 
         for tx in &tx_ids {
             let mut hasher = Sha256::new();
-            hasher.update(&tx.dump_buf());
+            hasher.update(tx.dump_buf());
             let hash: [u8; 32] = hasher.finalize().into();
             assert!(tree.verify_inclusion(hash));
         }
 
         let fake = dummy_tx_id(255);
         let mut hasher = Sha256::new();
-        hasher.update(&fake.dump_buf());
+        hasher.update(fake.dump_buf());
         let hash: [u8; 32] = hasher.finalize().into();
         assert!(!tree.verify_inclusion(hash));
     }
@@ -217,7 +217,7 @@ mod tests {
 
         for tx in &tx_ids {
             let mut hasher = Sha256::new();
-            hasher.update(&tx.dump_buf());
+            hasher.update(tx.dump_buf());
             let hash: [u8; 32] = hasher.finalize().into();
 
             let proof = tree.generate_proof(hash).unwrap();
@@ -226,7 +226,7 @@ mod tests {
 
         let fake = dummy_tx_id(99);
         let mut hasher = Sha256::new();
-        hasher.update(&fake.dump_buf());
+        hasher.update(fake.dump_buf());
         let hash: [u8; 32] = hasher.finalize().into();
         assert!(tree.generate_proof(hash).is_none());
     }
@@ -238,7 +238,7 @@ mod tests {
             .iter()
             .map(|tx| {
                 let mut hasher = Sha256::new();
-                hasher.update(&tx.dump_buf());
+                hasher.update(tx.dump_buf());
                 hasher.finalize().into()
             })
             .collect();
@@ -249,15 +249,15 @@ mod tests {
         // compute root manually
         let mut layer = tx_hashes.clone();
         while layer.len() > 1 {
-            if layer.len() % 2 != 0 {
+            if !layer.len().is_multiple_of(2) {
                 layer.push(*layer.last().unwrap());
             }
             layer = layer
                 .chunks(2)
                 .map(|pair| {
                     let mut hasher = Sha256::new();
-                    hasher.update(&pair[0]);
-                    hasher.update(&pair[1]);
+                    hasher.update(pair[0]);
+                    hasher.update(pair[1]);
                     hasher.finalize().into()
                 })
                 .collect();
@@ -13,6 +13,7 @@ use std::sync::OnceLock;
 use std::sync::atomic::{AtomicBool, Ordering};
 
 use keys::{Private, Public};
+use log::info;
 
 /// Public / Private key logic
 pub mod keys;
@@ -33,19 +34,49 @@ struct SharedDataset(RandomXDataset);
 unsafe impl Sync for SharedDataset {}
 unsafe impl Send for SharedDataset {}
 
+/// Shared by every node in the process, hashes don't depend on the mode, so there is no need for more than one
 static DATASET: OnceLock<SharedDataset> = OnceLock::new();
 static IS_LIGHT_MODE: AtomicBool = AtomicBool::new(true);
 
-/// This can only be called at the beginning of a program to be effective (before all Hash::new() or Hash::compare_with_data() calls to work)
-/// Enables full memory mode, substantially increasing hash rate, by allocating a 2GB scratch pad for hashing
+/// How RandomX hashes. Both modes produce the same hashes
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum RandomXMode {
+    /// Small memory footprint, slow hashing. Fine for validating
+    #[default]
+    Light,
+    /// Allocates a 2GB dataset, for substantially faster hashing. Meant for mining
+    Full,
+}
+
+/// Set the RandomX mode. It is process wide and can be switched at any time, each thread picks it up on its next hash
+pub fn randomx_set_mode(mode: RandomXMode) {
+    IS_LIGHT_MODE.store(mode == RandomXMode::Light, Ordering::SeqCst);
+}
+
+/// Get the current RandomX mode
+pub fn randomx_mode() -> RandomXMode {
+    if IS_LIGHT_MODE.load(Ordering::SeqCst) {
+        RandomXMode::Light
+    } else {
+        RandomXMode::Full
+    }
+}
+
+/// Enables full memory mode, substantially increasing hash rate, by allocating a 2GB scratch pad for hashing.
+/// Same as `randomx_set_mode(RandomXMode::Full)`
 pub fn randomx_use_full_mode() {
-    IS_LIGHT_MODE.store(false, Ordering::SeqCst);
+    randomx_set_mode(RandomXMode::Full);
+}
+
+/// Go back to light mode. The full mode dataset stays allocated once created
+pub fn randomx_use_light_mode() {
+    randomx_set_mode(RandomXMode::Light);
 }
 
 /// Returns a reference to the shared dataset
 fn get_dataset() -> RandomXDataset {
     let dataset = DATASET.get_or_init(|| {
-        println!("Creating RandomX dataset...");
+        info!("Creating RandomX dataset...");
         let flags = RandomXFlag::FLAG_FULL_MEM | RandomXFlag::FLAG_JIT;
 
         let cache = RandomXCache::new(flags, RANDOMX_SEED).expect("Failed to create RandomX cache");
@@ -54,32 +85,38 @@ fn get_dataset() -> RandomXDataset {
             RandomXDataset::new(flags, cache, 0).expect("Failed to create RandomX dataset");
 
         let shared_dataset = SharedDataset(dataset);
-        println!("RandomX dataset created!");
+        info!("RandomX dataset created!");
         shared_dataset
     });
     dataset.clone().0
 }
 
-// Thread-local VM
+fn create_vm(light_mode: bool) -> RandomXVM {
+    if light_mode {
+        let flags = RandomXFlag::FLAG_JIT;
+        let cache = RandomXCache::new(flags, RANDOMX_SEED)
+            .expect("Failed to create RandomX cache (light mode)");
+        RandomXVM::new(flags, Some(cache), None).expect("Failed to create RandomX VM (light mode)")
+    } else {
+        let flags = RandomXFlag::FLAG_FULL_MEM | RandomXFlag::FLAG_JIT;
+        RandomXVM::new(flags, None, Some(get_dataset()))
+            .expect("Failed to create RandomX VM (full mode)")
+    }
+}
+
+// Thread-local VM, with the mode it was created in
 thread_local! {
-    static THREAD_VM: RefCell<RandomXVM> = RefCell::new({
-        if IS_LIGHT_MODE.load(Ordering::SeqCst) {
-            let flags = RandomXFlag::FLAG_JIT;
-            let cache = RandomXCache::new(flags, RANDOMX_SEED).expect("Failed to create RandomX cache (light mode)");
-            RandomXVM::new(flags, Some(cache), None)
-                .expect("Failed to create RandomX VM (light mode)")
-        } else {
-            let flags = RandomXFlag::FLAG_FULL_MEM | RandomXFlag::FLAG_JIT;
-            let dataset = get_dataset();
-            RandomXVM::new(flags, None, Some(dataset.clone()))
-                .expect("Failed to create RandomX VM (full mode)")
-        }
-    });
+    static THREAD_VM: RefCell<Option<(bool, RandomXVM)>> = const { RefCell::new(None) };
 }
 
 pub fn randomx_hash(input: &[u8]) -> [u8; 32] {
+    let light_mode = IS_LIGHT_MODE.load(Ordering::SeqCst);
     THREAD_VM.with(|vm_cell| {
-        let vm = vm_cell.borrow_mut();
+        let mut vm_cell = vm_cell.borrow_mut();
+        let vm = match &mut *vm_cell {
+            Some((mode, vm)) if *mode == light_mode => vm,
+            vm => &mut vm.insert((light_mode, create_vm(light_mode))).1,
+        };
         let hash_vec = vm.calculate_hash(input).expect("RandomX hashing failed");
         hash_vec.try_into().expect("Hash must be 32 bytes")
     })
@@ -186,7 +223,7 @@ impl Signature {
 
     /// Create a signature with the raw signature bytes (no signing)
     pub fn new_from_buf(signature: &[u8; 64]) -> Self {
-        Signature(signature.clone())
+        Signature(*signature)
     }
 
     /// Validate a signature and return true if valid
@@ -0,0 +1,355 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::Write,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::RwLock,
+};
+
+use bincode::{Decode, Encode};
+use rand::{random, seq::SliceRandom};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::node::connector::{Endpoint, is_host_address};
+
+/// Amount of buckets addresses are spread over
+pub const BUCKET_COUNT: usize = 256;
+
+/// Max amount of addresses one bucket can hold
+pub const BUCKET_SIZE: usize = 16;
+
+/// Addresses that failed this many times in a row, without ever succeeding, get evicted first
+pub const MAX_FAILURES: u32 = 5;
+
+/// Addresses not seen for this long (seconds) get evicted first
+pub const ADDRESS_HORIZON: u64 = 30 * 24 * 60 * 60;
+
+/// Base retry backoff (seconds), doubled for every consecutive failure
+pub const RETRY_BACKOFF: u64 = 60;
+
+/// Identifies a network an address belongs to. /16 for IPv4, /32 for IPv6
+pub type SubnetGroup = [u8; 5];
+
+#[derive(Error, Debug, Clone)]
+pub enum AddressBookError {
+    #[error("Encoding failed")]
+    Encode,
+
+    #[error("Decoding failed")]
+    Decode,
+
+    #[error("IO error: {0}")]
+    Io(String),
+}
+
+impl From<std::io::Error> for AddressBookError {
+    fn from(e: std::io::Error) -> Self {
+        AddressBookError::Io(e.to_string())
+    }
+}
+
+/// Where we learned about an address
+#[derive(Encode, Decode, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum AddressSource {
+    /// Added by the node operator
+    Manual,
+    /// Referred to us by a peer (via `GetPeers`)
+    Referral { from: IpAddr },
+    /// We connected to it ourselves
+    Outbound,
+    /// From the bootstrap seed list
+    Seed,
+}
+
+/// Everything we know about a peer address
+#[derive(Encode, Decode, Serialize, Deserialize, Clone, Debug)]
+pub struct AddressEntry {
+    pub address: Endpoint,
+    pub source: AddressSource,
+    pub bucket: usize,
+    pub first_seen: u64,
+    pub last_seen: u64,
+    pub last_attempt: u64,
+    pub last_success: u64,
+    pub successes: u32,
+    /// Consecutive failures since last success
+    pub failures: u32,
+}
+
+impl AddressEntry {
+    /// Higher is better. Rewards successful connections and recency, punishes failures
+    pub fn score(&self, now: u64) -> i64 {
+        let hours_unseen = now.saturating_sub(self.last_seen) / 3600;
+        self.successes.min(100) as i64 * 10 - self.failures as i64 * 20 - hours_unseen as i64
+    }
+
+    /// Addresses that are most likely dead
+    pub fn is_terrible(&self, now: u64) -> bool {
+        (self.successes == 0 && self.failures >= MAX_FAILURES)
+            || now.saturating_sub(self.last_seen) > ADDRESS_HORIZON
+    }
+
+    /// Returns true if we are still backing off from this address after a failure
+    pub fn is_backing_off(&self, now: u64) -> bool {
+        self.failures > 0
+            && now.saturating_sub(self.last_attempt)
+                < RETRY_BACKOFF.saturating_mul(1 << self.failures.min(10))
+    }
+}
+
+#[derive(Encode, Decode, Clone)]
+struct AddressBookData {
+    /// Secret used to spread addresses over buckets, so bucket placement can't be predicted by other peers
+    key: [u8; 32],
+    entries: HashMap<Endpoint, AddressEntry>,
+}
+
+/// On disk address manager. Remembers peer addresses (ip or host endpoints) over restarts, scores them, and keeps any one network from taking over the book
+pub struct AddressBook {
+    path: Option<PathBuf>,
+    data: RwLock<AddressBookData>,
+}
+
+impl AddressBook {
+    /// Create an address book that only lives in memory
+    pub fn new_empty() -> Self {
+        Self {
+            path: None,
+            data: RwLock::new(AddressBookData {
+                key: random(),
+                entries: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Load an address book from `path`, or create a new one that will be saved there
+    pub fn load(path: PathBuf) -> Self {
+        let data = fs::read(&path)
+            .ok()
+            .and_then(|buf| bincode::decode_from_slice(&buf, bincode::config::standard()).ok())
+            .map(|(data, _)| data)
+            .unwrap_or_else(|| AddressBookData {
+                key: random(),
+                entries: HashMap::new(),
+            });
+
+        Self {
+            path: Some(path),
+            data: RwLock::new(data),
+        }
+    }
+
+    /// Save the address book to disk (no-op for in memory books)
+    pub fn save(&self) -> Result<(), AddressBookError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let buffer = bincode::encode_to_vec(
+            self.data.read().unwrap().clone(),
+            bincode::config::standard(),
+        )
+        .map_err(|_| AddressBookError::Encode)?;
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // Atomic write
+        let tmp_path = path.with_extension("dat.tmp");
+        {
+            let mut f = File::create(&tmp_path)?;
+            f.write_all(&buffer)?;
+            f.sync_all()?;
+        }
+        fs::rename(&tmp_path, path)?;
+
+        Ok(())
+    }
+
+    /// Add or refresh an address. When its bucket is full, the worst address of that bucket is evicted
+    pub fn add(&self, address: Endpoint, source: AddressSource) {
+        self.add_seen(address, source, chrono::Utc::now().timestamp() as u64);
+    }
+
+    /// Add or refresh an address that was alive at `last_seen` (clamped to now)
+    pub fn add_seen(&self, address: Endpoint, source: AddressSource, last_seen: u64) {
+        let now = chrono::Utc::now().timestamp() as u64;
+        let last_seen = last_seen.min(now);
+        let mut data = self.data.write().unwrap();
+
+        if let Some(entry) = data.entries.get_mut(&address) {
+            entry.last_seen = entry.last_seen.max(last_seen);
+            return;
+        }
+
+        let bucket = bucket_of(&data.key, address.socket_addr().ip(), source);
+        let mut in_bucket: Vec<&AddressEntry> = data
+            .entries
+            .values()
+            .filter(|entry| entry.bucket == bucket)
+            .collect();
+
+        if in_bucket.len() >= BUCKET_SIZE {
+            in_bucket.sort_by_key(|entry| (!entry.is_terrible(now), entry.score(now)));
+            let evicted = in_bucket[0].address.clone();
+            data.entries.remove(&evicted);
+        }
+
+        data.entries.insert(
+            address.clone(),
+            AddressEntry {
+                address,
+                source,
+                bucket,
+                first_seen: now,
+                last_seen,
+                last_attempt: 0,
+                last_success: 0,
+                successes: 0,
+                failures: 0,
+            },
+        );
+    }
+
+    /// Record a connection attempt to an address that succeeded
+    pub fn mark_success(&self, address: &Endpoint) {
+        let now = chrono::Utc::now().timestamp() as u64;
+        if !self.data.read().unwrap().entries.contains_key(address) {
+            self.add(address.clone(), AddressSource::Outbound);
+        }
+        if let Some(entry) = self.data.write().unwrap().entries.get_mut(address) {
+            entry.last_attempt = now;
+            entry.last_success = now;
+            entry.last_seen = now;
+            entry.successes = entry.successes.saturating_add(1);
+            entry.failures = 0;
+        }
+    }
+
+    /// Record a connection attempt to an address that failed
+    pub fn mark_failure(&self, address: &Endpoint) {
+        let now = chrono::Utc::now().timestamp() as u64;
+        if let Some(entry) = self.data.write().unwrap().entries.get_mut(address) {
+            entry.last_attempt = now;
+            entry.failures = entry.failures.saturating_add(1);
+        }
+    }
+
+    /// Record that an address is (still) alive
+    pub fn mark_seen(&self, address: &Endpoint) {
+        if let Some(entry) = self.data.write().unwrap().entries.get_mut(address) {
+            entry.last_seen = chrono::Utc::now().timestamp() as u64;
+        }
+    }
+
+    /// Remove an address from the book
+    pub fn remove(&self, address: &Endpoint) {
+        self.data.write().unwrap().entries.remove(address);
+    }
+
+    /// Get an entry by its address
+    pub fn get(&self, address: &Endpoint) -> Option<AddressEntry> {
+        self.data.read().unwrap().entries.get(address).cloned()
+    }
+
+    /// Get all entries in this book
+    pub fn entries(&self) -> Vec<AddressEntry> {
+        self.data
+            .read()
+            .unwrap()
+            .entries
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.read().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Select up to `count` addresses to connect to, best first.
+    /// Addresses in `exclude` (compared by `Endpoint::socket_addr`), addresses we are backing off from, and addresses in a subnet group from `used_groups` (or already selected) are skipped, so that a single network can't surround us
+    pub fn select_candidates(
+        &self,
+        count: usize,
+        exclude: &HashSet<SocketAddr>,
+        used_groups: &HashSet<SubnetGroup>,
+    ) -> Vec<Endpoint> {
+        let now = chrono::Utc::now().timestamp() as u64;
+        let mut eligible: Vec<AddressEntry> = self
+            .data
+            .read()
+            .unwrap()
+            .entries
+            .values()
+            .filter(|entry| {
+                !exclude.contains(&entry.address.socket_addr()) && !entry.is_backing_off(now)
+            })
+            .cloned()
+            .collect();
+
+        // Shuffle first so equally scored addresses get picked at random
+        eligible.shuffle(&mut rand::rng());
+        eligible.sort_by_key(|entry| std::cmp::Reverse(entry.score(now)));
+
+        let mut used_groups = used_groups.clone();
+        let mut selected = vec![];
+        for entry in eligible {
+            if selected.len() >= count {
+                break;
+            }
+            if used_groups.insert(subnet_group(entry.address.socket_addr().ip())) {
+                selected.push(entry.address);
+            }
+        }
+        selected
+    }
+}
+
+/// Returns true if an address could be connected to. Made up addresses of host endpoints can't be
+pub fn is_dialable(address: &SocketAddr) -> bool {
+    !address.ip().is_unspecified()
+        && !address.ip().is_multicast()
+        && !is_host_address(address.ip())
+        && address.port() != 0
+}
+
+/// Get the subnet group of an ip (/16 for IPv4, /32 for IPv6). Every host endpoint is a group of its own
+pub fn subnet_group(ip: IpAddr) -> SubnetGroup {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            [4, octets[0], octets[1], 0, 0]
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => subnet_group(IpAddr::V4(ip)),
+            None if is_host_address(IpAddr::V6(ip)) => {
+                let octets = ip.octets();
+                [0, octets[6], octets[7], octets[8], octets[9]]
+            }
+            None => {
+                let octets = ip.octets();
+                [6, octets[0], octets[1], octets[2], octets[3]]
+            }
+        },
+    }
+}
+
+/// Place an address in a bucket, based on its own subnet group and the subnet group of whoever told us about it
+fn bucket_of(key: &[u8; 32], ip: IpAddr, source: AddressSource) -> usize {
+    let mut hasher = Sha256::new();
+    hasher.update(key);
+    hasher.update(subnet_group(ip));
+    if let AddressSource::Referral { from } = source {
+        hasher.update(subnet_group(from));
+    }
+    let digest = hasher.finalize();
+    u16::from_be_bytes([digest[0], digest[1]]) as usize % BUCKET_COUNT
+}
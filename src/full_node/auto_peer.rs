@@ -1,111 +1,264 @@
 use std::{
+    collections::HashSet,
     net::{IpAddr, SocketAddr},
     time::Duration,
 };
 
 use get_if_addrs::get_if_addrs;
 use log::{error, info};
-use rand::seq::IteratorRandom;
+use rand::seq::{IteratorRandom, SliceRandom};
+use serde::{Deserialize, Serialize};
 use tokio::{task::JoinHandle, time::sleep};
 
-use crate::{full_node::{SharedBlockchain, connect_peer, node_state::SharedNodeState}, node::{message::{Command, Message}, peer::{PeerError, PeerHandle}}};
+use crate::{
+    full_node::{
+        SharedBlockchain,
+        address_book::{AddressSource, SubnetGroup, is_dialable, subnet_group},
+        bootstrap::BootstrapConfig,
+        connect_peer,
+        node_state::SharedNodeState,
+    },
+    node::{
+        connector::Endpoint,
+        message::{Command, MAX_SEND_PEERS, Message, PeerAddress},
+        misbehavior::Misbehavior,
+        peer::{PeerError, PeerHandle},
+    },
+};
 
-/// Amount of peers, that the node is trying to achieve stable connections with
+/// Default amount of peers, that the node is trying to achieve stable connections with (`ConnectionLimits::max_outbound`)
 pub const TARGET_PEERS: usize = 12;
 
-/// Daemon reload cycle time
+/// Default daemon reload cycle time
 pub const DAEMON_CYCLE: Duration = Duration::from_secs(30);
 
-async fn get_peer_referrals(peer: &PeerHandle) -> Result<Vec<SocketAddr>, PeerError> {
+/// How the auto peer daemon finds peers
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AutoPeerConfig {
+    /// Time between rounds of finding new peers
+    pub cycle: Duration,
+    /// Ips of this machine that interfaces don't report (like a public ip behind NAT), so we never connect to ourselves
+    pub reserved_ips: Vec<IpAddr>,
+    pub bootstrap: BootstrapConfig,
+}
+
+impl Default for AutoPeerConfig {
+    fn default() -> Self {
+        Self {
+            cycle: DAEMON_CYCLE,
+            reserved_ips: vec![],
+            bootstrap: BootstrapConfig::default(),
+        }
+    }
+}
+
+async fn get_peer_referrals(
+    peer: &PeerHandle,
+    node_state: &SharedNodeState,
+) -> Result<Vec<PeerAddress>, PeerError> {
     if let Command::SendPeers { peers } =
         peer.request(Message::new(Command::GetPeers)).await?.command
     {
-        let mut referrals = vec![];
-        for peer in peers {
-            if let Ok(referral) = peer.parse() {
-                referrals.push(referral);
-            } else {
-                return Err(PeerError::Unknown(format!(
-                    "Peer address {peer} is invalid"
-                )));
-            }
+        if peers.len() > MAX_SEND_PEERS {
+            node_state
+                .punish(peer, Misbehavior::ProtocolViolation)
+                .await;
+            return Err(PeerError::Unknown(format!(
+                "Peer sent {} addresses, more than the limit of {MAX_SEND_PEERS}",
+                peers.len()
+            )));
         }
-        return Ok(referrals);
+        return Ok(peers
+            .into_iter()
+            .filter(|peer| is_dialable(&peer.address))
+            .collect());
     }
+    node_state
+        .punish(peer, Misbehavior::ProtocolViolation)
+        .await;
     Err(PeerError::Unknown(
         "GetPeers returned incorrect response".into(),
     ))
 }
 
-/// Start a Auto Peer daemon, that automatically finds peers to connect to via P2P
+/// Endpoints of all connected peers that we dialed
+async fn outbound_peers(node_state: &SharedNodeState) -> Vec<Endpoint> {
+    node_state
+        .connected_peers
+        .read()
+        .await
+        .values()
+        .filter(|peer| !peer.is_client)
+        .map(|peer| peer.endpoint.clone())
+        .collect()
+}
+
+/// Start a Auto Peer daemon, that automatically finds peers to connect to via P2P.
+/// Persistent peers from `bootstrap` are always reconnected, seeds are used when there are no peers at all.
+/// Referrals are collected into the address book, and the best scored addresses are connected to, at most one per subnet group
 pub fn start_auto_peer(
     node_state: SharedNodeState,
     blockchain: SharedBlockchain,
     reserved_ips: Vec<IpAddr>,
+    bootstrap: BootstrapConfig,
 ) -> JoinHandle<()> {
-    tokio::spawn(async move {
+    start_auto_peer_with_config(
+        node_state,
+        blockchain,
+        AutoPeerConfig {
+            reserved_ips,
+            bootstrap,
+            ..Default::default()
+        },
+    )
+}
+
+/// Start a Auto Peer daemon, see `start_auto_peer`
+pub fn start_auto_peer_with_config(
+    node_state: SharedNodeState,
+    blockchain: SharedBlockchain,
+    config: AutoPeerConfig,
+) -> JoinHandle<()> {
+    let AutoPeerConfig {
+        cycle,
+        reserved_ips,
+        bootstrap,
+    } = config;
+    let shutdown_state = node_state.clone();
+    shutdown_state.shutdown.spawn(async move {
+        let is_my_ip = |ip: &IpAddr| {
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || reserved_ips.contains(ip)
+                || get_if_addrs()
+                    .expect("Could not get Local machine IP addresses.")
+                    .iter()
+                    .any(|interface| interface.ip() == *ip)
+        };
+
+        node_state
+            .persistent_peers
+            .write()
+            .await
+            .extend(bootstrap.persistent_peers.iter().cloned());
+
         loop {
-            sleep(DAEMON_CYCLE).await;
+            let target_peers = node_state.connection_limits.read().await.max_outbound;
 
-            let peer_count =
-                node_state
+            // Reconnect persistent peers
+            let persistent_peers = node_state.persistent_peers.read().await.clone();
+            for endpoint in persistent_peers {
+                if node_state
                     .connected_peers
                     .read()
                     .await
-                    .iter()
-                    .fold(
-                        0,
-                        |acc, (_, peer)| if !peer.is_client { acc + 1 } else { acc },
-                    );
+                    .contains_key(&endpoint.socket_addr())
+                {
+                    continue;
+                }
+                match connect_peer(endpoint.clone(), &blockchain, &node_state).await {
+                    Ok(_) => info!("Connected to persistent peer: {endpoint}"),
+                    Err(e) => error!("Could not connect to persistent peer {endpoint}, error: {e}"),
+                }
+            }
 
-            if peer_count >= TARGET_PEERS {
-                continue;
+            // Bootstrap from seeds when we are all alone
+            if outbound_peers(&node_state).await.is_empty() {
+                let mut seeds = bootstrap.resolve_seeds().await;
+                seeds.shuffle(&mut rand::rng());
+                for seed in seeds {
+                    if !seed.ip().is_some_and(|ip| is_my_ip(&ip)) {
+                        node_state
+                            .address_book
+                            .add(seed.clone(), AddressSource::Seed);
+                    }
+                    if outbound_peers(&node_state).await.len() >= target_peers
+                        || node_state
+                            .connected_peers
+                            .read()
+                            .await
+                            .contains_key(&seed.socket_addr())
+                    {
+                        continue;
+                    }
+                    if connect_peer(seed.clone(), &blockchain, &node_state)
+                        .await
+                        .is_ok()
+                    {
+                        info!("Connected to seed peer: {seed}");
+                    }
+                }
             }
 
-            // Get a random peer to poll for referrals
-            let selected_peer = {
-                let peers = node_state.connected_peers.read().await;
-                let mut rng = rand::rng();
-                peers.values().choose(&mut rng).cloned()
-            };
-
-            if let Some(peer) = selected_peer {
-                let peer_address = peer.address;
-
-                if let Err(e) = async {
-                    // define the closure here so it lives inside this async block
-                    let is_my_ip = |ip: &IpAddr| {
-                        ip.is_loopback()
-                            || ip.is_unspecified()
-                            || reserved_ips.contains(ip)
-                            || get_if_addrs()
-                                .expect("Could not get Local machine IP addresses.")
-                                .iter()
-                                .any(|interface| interface.ip() == *ip)
-                    };
-
-                    let referrals = get_peer_referrals(&peer).await?;
-
-                    for referral in referrals {
-                        if is_my_ip(&referral.ip()) {
-                            continue;
-                        }
-                        if node_state.connected_peers.read().await.contains_key(&referral) {
-                            continue;
-                        }
-                        // try to connect to peer, if cant, no biggie
-                        if let Ok(connected_peer) = connect_peer(referral, &blockchain, &node_state).await {
-                            info!("Connected to new peer: {}, referred by: {}", connected_peer.address, peer.address);
+            let outbound = outbound_peers(&node_state).await;
+
+            // Peers we are connected to are alive
+            for endpoint in &outbound {
+                node_state.address_book.mark_seen(endpoint);
+            }
+
+            if outbound.len() < target_peers {
+                // Get a random peer to poll for referrals
+                let selected_peer = {
+                    let peers = node_state.connected_peers.read().await;
+                    let mut rng = rand::rng();
+                    peers.values().choose(&mut rng).cloned()
+                };
+
+                if let Some(peer) = selected_peer {
+                    match get_peer_referrals(&peer, &node_state).await {
+                        Ok(referrals) => {
+                            for referral in referrals {
+                                if !is_my_ip(&referral.address.ip()) {
+                                    node_state.address_book.add_seen(
+                                        referral.address.into(),
+                                        AddressSource::Referral {
+                                            from: peer.address.ip(),
+                                        },
+                                        referral.last_seen,
+                                    );
+                                }
+                            }
                         }
+                        Err(e) => error!("Auto peer failed {}, error: {e}", peer.address),
                     }
-
-                    Ok::<(), PeerError>(())
                 }
-                .await
-                {
-                    error!("Auto peer failed {peer_address}, error: {e}");
+
+                let connected: HashSet<SocketAddr> = node_state
+                    .connected_peers
+                    .read()
+                    .await
+                    .keys()
+                    .copied()
+                    .collect();
+                let used_groups: HashSet<SubnetGroup> = outbound
+                    .iter()
+                    .map(|endpoint| subnet_group(endpoint.socket_addr().ip()))
+                    .collect();
+
+                for candidate in node_state.address_book.select_candidates(
+                    target_peers - outbound.len(),
+                    &connected,
+                    &used_groups,
+                ) {
+                    if candidate.ip().is_some_and(|ip| is_my_ip(&ip)) {
+                        node_state.address_book.remove(&candidate);
+                        continue;
+                    }
+                    // try to connect to peer, if cant, no biggie
+                    if let Ok(connected_peer) =
+                        connect_peer(candidate, &blockchain, &node_state).await
+                    {
+                        info!("Connected to new peer: {}", connected_peer.address);
+                    }
                 }
             }
+
+            if let Err(e) = node_state.address_book.save() {
+                error!("Failed to save address book, error: {e}");
+            }
+
+            sleep(cycle).await;
         }
     })
 }
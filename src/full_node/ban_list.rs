@@ -0,0 +1,230 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::Write,
+    net::IpAddr,
+    path::PathBuf,
+    sync::RwLock,
+    time::Duration,
+};
+
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::node::misbehavior::Misbehavior;
+
+/// Ban score at which an ip gets banned
+pub const BAN_SCORE_THRESHOLD: u32 = 100;
+
+/// Ban score forgiven every decay cycle
+pub const SCORE_DECAY: u32 = 2;
+
+/// How often scores decay
+pub const SCORE_DECAY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long (seconds) an ip stays banned after reaching `BAN_SCORE_THRESHOLD`
+pub const MISBEHAVIOR_BAN_DURATION: u64 = 24 * 60 * 60;
+
+/// When misbehaving ips get banned, and for how long
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct BanConfig {
+    /// Ban score at which an ip gets banned
+    pub score_threshold: u32,
+    /// Ban score forgiven every `decay_interval`
+    pub score_decay: u32,
+    pub decay_interval: Duration,
+    /// How long (seconds) an ip stays banned after reaching `score_threshold`
+    pub ban_duration: u64,
+}
+
+impl Default for BanConfig {
+    fn default() -> Self {
+        Self {
+            score_threshold: BAN_SCORE_THRESHOLD,
+            score_decay: SCORE_DECAY,
+            decay_interval: SCORE_DECAY_INTERVAL,
+            ban_duration: MISBEHAVIOR_BAN_DURATION,
+        }
+    }
+}
+
+#[derive(Error, Debug, Clone)]
+pub enum BanListError {
+    #[error("Encoding failed")]
+    Encode,
+
+    #[error("IO error: {0}")]
+    Io(String),
+}
+
+impl From<std::io::Error> for BanListError {
+    fn from(e: std::io::Error) -> Self {
+        BanListError::Io(e.to_string())
+    }
+}
+
+/// A banned ip
+#[derive(Encode, Decode, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BanEntry {
+    pub ip: IpAddr,
+    /// Unix timestamp the ban ends at, `None` for a permanent ban
+    pub until: Option<u64>,
+    pub reason: String,
+}
+
+impl BanEntry {
+    pub fn is_active(&self, now: u64) -> bool {
+        self.until.is_none_or(|until| until > now)
+    }
+}
+
+#[derive(Encode, Decode, Clone, Default)]
+struct BanListData {
+    scores: HashMap<IpAddr, u32>,
+    bans: HashMap<IpAddr, BanEntry>,
+}
+
+/// On disk misbehavior scores and bans, per ip
+pub struct BanList {
+    pub config: RwLock<BanConfig>,
+    path: Option<PathBuf>,
+    data: RwLock<BanListData>,
+}
+
+impl BanList {
+    /// Create a ban list that only lives in memory
+    pub fn new_empty() -> Self {
+        Self {
+            config: RwLock::new(BanConfig::default()),
+            path: None,
+            data: RwLock::new(BanListData::default()),
+        }
+    }
+
+    /// Load a ban list from `path`, or create a new one that will be saved there
+    pub fn load(path: PathBuf) -> Self {
+        let data = fs::read(&path)
+            .ok()
+            .and_then(|buf| bincode::decode_from_slice(&buf, bincode::config::standard()).ok())
+            .map(|(data, _)| data)
+            .unwrap_or_default();
+
+        Self {
+            config: RwLock::new(BanConfig::default()),
+            path: Some(path),
+            data: RwLock::new(data),
+        }
+    }
+
+    /// Save the ban list to disk (no-op for in memory lists)
+    pub fn save(&self) -> Result<(), BanListError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let buffer = bincode::encode_to_vec(
+            self.data.read().unwrap().clone(),
+            bincode::config::standard(),
+        )
+        .map_err(|_| BanListError::Encode)?;
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // Atomic write
+        let tmp_path = path.with_extension("dat.tmp");
+        {
+            let mut f = File::create(&tmp_path)?;
+            f.write_all(&buffer)?;
+            f.sync_all()?;
+        }
+        fs::rename(&tmp_path, path)?;
+
+        Ok(())
+    }
+
+    /// Add the weight of `misbehavior` to the score of `ip`. Returns true if this got `ip` banned
+    pub fn punish(&self, ip: IpAddr, misbehavior: Misbehavior) -> bool {
+        let config = *self.config.read().unwrap();
+        let banned = {
+            let mut data = self.data.write().unwrap();
+            let score = data.scores.entry(ip).or_insert(0);
+            *score = score.saturating_add(misbehavior.weight());
+            *score >= config.score_threshold
+        };
+
+        if banned {
+            self.ban(ip, Some(config.ban_duration), format!("{misbehavior:?}"));
+        }
+        banned
+    }
+
+    /// Ban `ip` for `duration` seconds, or forever if `None`
+    pub fn ban(&self, ip: IpAddr, duration: Option<u64>, reason: String) -> BanEntry {
+        let now = chrono::Utc::now().timestamp() as u64;
+        let entry = BanEntry {
+            ip,
+            until: duration.map(|duration| now.saturating_add(duration)),
+            reason,
+        };
+        {
+            let mut data = self.data.write().unwrap();
+            data.scores.remove(&ip);
+            data.bans.insert(ip, entry.clone());
+        }
+        let _ = self.save();
+        entry
+    }
+
+    /// Lift the ban of `ip` and reset its score. Returns true if it was banned
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        let was_banned = {
+            let mut data = self.data.write().unwrap();
+            data.scores.remove(&ip);
+            data.bans.remove(&ip).is_some()
+        };
+        let _ = self.save();
+        was_banned
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let now = chrono::Utc::now().timestamp() as u64;
+        self.data
+            .read()
+            .unwrap()
+            .bans
+            .get(&ip)
+            .is_some_and(|ban| ban.is_active(now))
+    }
+
+    /// Get the current ban score of `ip`
+    pub fn score(&self, ip: IpAddr) -> u32 {
+        *self.data.read().unwrap().scores.get(&ip).unwrap_or(&0)
+    }
+
+    /// Get all active bans
+    pub fn list_bans(&self) -> Vec<BanEntry> {
+        let now = chrono::Utc::now().timestamp() as u64;
+        self.data
+            .read()
+            .unwrap()
+            .bans
+            .values()
+            .filter(|ban| ban.is_active(now))
+            .cloned()
+            .collect()
+    }
+
+    /// "Forgive" everyone by the configured score decay, and drop expired bans
+    pub fn decay(&self) {
+        let score_decay = self.config.read().unwrap().score_decay;
+        let now = chrono::Utc::now().timestamp() as u64;
+        let mut data = self.data.write().unwrap();
+        data.scores.retain(|_ip, score| {
+            *score = score.saturating_sub(score_decay);
+            *score != 0
+        });
+        data.bans.retain(|_ip, ban| ban.is_active(now));
+    }
+}
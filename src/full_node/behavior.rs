@@ -1,14 +1,17 @@
-use std::sync::Arc;
+use std::sync::{Arc, atomic::Ordering};
 
 use log::{error, warn};
 
 use crate::{
+    core::transaction::{Transaction, TransactionId},
     crypto::merkle_tree::MerkleTreeProof,
     full_node::{
-        SharedBlockchain, accept_block, accept_transaction, node_state::SharedNodeState, p2p_server::BAN_SCORE_THRESHOLD, sync::sync_to_peer
+        SharedBlockchain, accept_block, accept_stem_transaction, accept_transaction,
+        node_state::SharedNodeState, sync::sync_to_peer,
     },
     node::{
-        message::{Command, Message},
+        message::{Command, MAX_HEADERS_PER_REQUEST, MAX_INV_SIZE, MAX_MESSAGE_SIZE, Message},
+        misbehavior::Misbehavior,
         peer::{PeerError, PeerHandle},
         peer_behavior::{PeerBehavior, SharedPeerBehavior},
     },
@@ -20,6 +23,7 @@ pub struct FullNodePeerBehavior {
 }
 
 impl FullNodePeerBehavior {
+    #[allow(clippy::new_ret_no_self)]
     pub fn new(blockchain: SharedBlockchain, node_state: SharedNodeState) -> SharedPeerBehavior {
         Arc::new(Self {
             blockchain,
@@ -28,21 +32,94 @@ impl FullNodePeerBehavior {
     }
 }
 
+impl FullNodePeerBehavior {
+    /// Accept a transaction relayed by `peer`, crediting or punishing it
+    async fn relayed_transaction(&self, transaction: Transaction, peer: &PeerHandle) {
+        let (blockchain, node_state) = (&self.blockchain, &self.node_state);
+        let is_new = transaction
+            .transaction_id
+            .is_some_and(|id| !node_state.last_seen_transactions().contains(&id));
+        match accept_transaction(blockchain, node_state, transaction).await {
+            Ok(()) => {
+                if is_new {
+                    peer.stats
+                        .transactions_relayed
+                        .fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            Err(e) => {
+                warn!("Incoming transaction is invalid: {e}");
+                if let Some(misbehavior) = Misbehavior::from_transaction_error(&e) {
+                    node_state.punish(peer, misbehavior).await;
+                }
+            }
+        }
+    }
+
+    /// Request announced transactions we don't know yet from `peer`, and accept them
+    async fn fetch_transactions(
+        &self,
+        unknown: Vec<TransactionId>,
+        peer: &PeerHandle,
+    ) -> Result<(), PeerError> {
+        let node_state = &self.node_state;
+        let transactions = match peer
+            .request(Message::new(Command::GetTransactions {
+                transaction_ids: unknown.clone(),
+            }))
+            .await?
+            .command
+        {
+            Command::GetTransactionsResponse { transactions } => transactions,
+            _ => {
+                node_state
+                    .punish(peer, Misbehavior::ProtocolViolation)
+                    .await;
+                return Err(PeerError::IncorrectResponse);
+            }
+        };
+        for transaction in transactions {
+            if !transaction
+                .transaction_id
+                .is_some_and(|id| unknown.contains(&id))
+            {
+                node_state
+                    .punish(peer, Misbehavior::ProtocolViolation)
+                    .await;
+                continue;
+            }
+            self.relayed_transaction(transaction, peer).await;
+        }
+        Ok(())
+    }
+}
+
 #[async_trait::async_trait]
 impl PeerBehavior for FullNodePeerBehavior {
     async fn on_message(&self, message: Message, peer: &PeerHandle) -> Result<Message, PeerError> {
         let (blockchain, node_state) = (&self.blockchain, &self.node_state);
 
         let response = match message.command {
-            Command::Connect => message.make_response(Command::AcknowledgeConnection),
-            Command::AcknowledgeConnection => {
-                return Err(PeerError::Unknown(
-                    "Got unhandled AcknowledgeConnection".to_string(),
-                ));
+            Command::Connect {
+                listen_address,
+                first_block,
+            } => {
+                if let Some(listen_address) = listen_address {
+                    peer.set_listen_address(listen_address);
+                }
+                peer.set_first_block(first_block);
+                message.make_response(Command::AcknowledgeConnection {
+                    listen_address: *node_state.advertised_address.read().await,
+                    first_block: blockchain.block_store().first_block(),
+                })
             }
             Command::Ping { height } => {
                 let local = blockchain.block_store().get_height();
-                if local + 1 < height && !*node_state.is_syncing.read().await {
+                // A pruned peer can't serve the blocks we are missing
+                if local + 1 < height
+                    && peer.first_block() <= local
+                    && !*node_state.is_syncing.read().await
+                {
                     // Need two block offset
                     *node_state.is_syncing.write().await = true;
                     let peer = peer.clone();
@@ -51,10 +128,9 @@ impl PeerBehavior for FullNodePeerBehavior {
                     // We need to sync to longer chain
                     let node_state = node_state.clone();
                     tokio::spawn(async move {
-                        node_state.mempool.clear().await; // We completely clear the mempool since syncing may invalidate, or double spend transactions
                         let res = {
                             let _lock = node_state.processing.lock().await; // Get a lock to make sure that we are not overwriting any blocks by accident
-                            sync_to_peer(&peer, &blockchain, height).await
+                            sync_to_peer(&peer, &blockchain, &node_state, height).await
                         };
                         *node_state.is_syncing.write().await = false;
                         match res {
@@ -69,76 +145,50 @@ impl PeerBehavior for FullNodePeerBehavior {
                 }
                 message.make_response(Command::Pong { height: local })
             }
-            Command::Pong { .. } => {
-                return Err(PeerError::Unknown("Got unhandled Ping".to_string()));
-            }
-            Command::GetPeers => {
-                let peers = node_state
-                    .connected_peers
-                    .read()
-                    .await
-                    .values()
-                    .map(|peer| peer.address.to_string())
-                    .collect();
-                message.make_response(Command::SendPeers { peers })
-            }
-            Command::SendPeers { .. } => {
-                return Err(PeerError::Unknown("Got unhandled SendPeers".to_string()));
-            }
-            Command::NewBlock { ref block } => {
-                if !*node_state.is_syncing.read().await {
-                    match accept_block(&blockchain, &node_state, block.clone()).await {
-                        Ok(()) => {}
-                        Err(e) => {
-                            warn!("Incoming block is invalid: {e}")
-                        }
-                    }
-                }
-
-                message.make_response(Command::NewBlockResolved)
-            }
-            Command::NewBlockResolved => {
-                return Err(PeerError::Unknown(
-                    "Got unhandled NewBlockAccepted".to_string(),
-                ));
-            }
-            Command::NewTransaction { ref transaction } => {
-                match accept_transaction(&blockchain, &node_state, transaction.clone()).await {
-                    Ok(()) => {}
-                    Err(e) => {
-                        warn!("Incoming transaction is invalid: {e}")
-                    }
-                }
-                message.make_response(Command::NewTransactionResolved)
-            }
-            Command::NewTransactionResolved => {
-                return Err(PeerError::Unknown(
-                    "Got unhandled NewTransactionAccepted".to_string(),
-                ));
-            }
+            Command::GetPeers => message.make_response(Command::SendPeers {
+                peers: node_state.shareable_peers().await,
+            }),
             Command::GetBlock { block_hash } => message.make_response(Command::GetBlockResponse {
                 block: blockchain.block_store().get_block_by_hash(block_hash),
             }),
-            Command::GetBlockResponse { .. } => {
-                return Err(PeerError::Unknown(
-                    "Got unhandled GetBlockResponse".to_string(),
-                ));
-            }
             Command::GetBlockHashes { start, end } => {
                 let mut hashes = vec![];
-                for height in start..end {
-                    if let Some(hash) = blockchain.block_store().get_block_hash_by_height(height) {
-                        hashes.push(hash);
+                // The index keeps the hashes of pruned blocks, refuse ranges we can't serve the blocks of
+                if start >= blockchain.block_store().first_block() {
+                    for height in start..end {
+                        if let Some(hash) =
+                            blockchain.block_store().get_block_hash_by_height(height)
+                        {
+                            hashes.push(hash);
+                        }
                     }
                 }
                 message.make_response(Command::GetBlockHashesResponse {
                     block_hashes: hashes,
                 })
             }
-            Command::GetBlockHashesResponse { .. } => {
-                return Err(PeerError::Unknown(
-                    "Got unhandled GetBlockResponse".to_string(),
-                ));
+            Command::GetHeaders { start, end } => {
+                let mut headers = vec![];
+                let mut size = 0;
+                for height in start..end.min(start.saturating_add(MAX_HEADERS_PER_REQUEST)) {
+                    match blockchain
+                        .block_store()
+                        .get_block_by_height(height)
+                        .map(|block| block.to_header())
+                    {
+                        Some(Ok(header)) => {
+                            // Stay well below the message size limit, the peer can ask for the rest
+                            size += bincode::encode_to_vec(&header, bincode::config::standard())
+                                .map_or(MAX_MESSAGE_SIZE, |buf| buf.len());
+                            if size > MAX_MESSAGE_SIZE / 2 && !headers.is_empty() {
+                                break;
+                            }
+                            headers.push(header);
+                        }
+                        _ => break,
+                    }
+                }
+                message.make_response(Command::GetHeadersResponse { headers })
             }
             Command::GetTransactionMerkleProof {
                 block,
@@ -159,42 +209,160 @@ impl PeerBehavior for FullNodePeerBehavior {
                         .make_response(Command::GetTransactionMerkleProofResponse { proof: None })
                 }
             }
-            Command::GetTransactionMerkleProofResponse { .. } => {
-                return Err(PeerError::Unknown(
-                    "Got unhandled GetTransactionMerkleProofResponse".to_string(),
-                ));
-            }
             Command::GetBlockMetadata { block_hash } => {
                 let block_metadata =
                     (|| Some(blockchain.block_store().get_block_by_hash(block_hash)?.meta))();
                 message.make_response(Command::GetBlockMetadataResponse { block_metadata })
             }
-            Command::GetBlockMetadataResponse { .. } => {
-                return Err(PeerError::Unknown(
-                    "Got unhandled GetBlockMetadataResponse".to_string(),
-                ));
+            Command::GetTransactions {
+                ref transaction_ids,
+            } => {
+                if transaction_ids.len() > MAX_INV_SIZE {
+                    node_state
+                        .punish(peer, Misbehavior::ProtocolViolation)
+                        .await;
+                }
+                // Only serve the mempool, stem transactions must not leak before they are fluffed
+                let mut transactions = vec![];
+                for id in &transaction_ids[..transaction_ids.len().min(MAX_INV_SIZE)] {
+                    if let Some(transaction) = node_state.mempool.get(id).await {
+                        transactions.push(transaction);
+                    }
+                }
+                message.make_response(Command::GetTransactionsResponse { transactions })
+            }
+            Command::NewBlock { .. }
+            | Command::NewTransaction { .. }
+            | Command::InvTransactions { .. }
+            | Command::StemTransaction { .. } => {
+                return Err(PeerError::Unknown(format!(
+                    "Got notification {} as a request",
+                    message.command.name()
+                )));
+            }
+            Command::AcknowledgeConnection { .. }
+            | Command::Pong { .. }
+            | Command::SendPeers { .. }
+            | Command::GetTransactionsResponse { .. }
+            | Command::GetBlockResponse { .. }
+            | Command::GetBlockHashesResponse { .. }
+            | Command::GetHeadersResponse { .. }
+            | Command::GetTransactionMerkleProofResponse { .. }
+            | Command::GetBlockMetadataResponse { .. } => {
+                node_state
+                    .punish(peer, Misbehavior::UnsolicitedResponse)
+                    .await;
+                return Err(PeerError::Unknown(format!(
+                    "Got unsolicited {}",
+                    message.command.name()
+                )));
             }
         };
 
         Ok(response)
     }
 
+    async fn on_notification(&self, message: Message, peer: &PeerHandle) -> Result<(), PeerError> {
+        let (blockchain, node_state) = (&self.blockchain, &self.node_state);
+
+        match message.command {
+            Command::NewBlock { block } => {
+                if *node_state.is_syncing.read().await {
+                    return Ok(());
+                }
+                let is_new = block
+                    .meta
+                    .hash
+                    .is_some_and(|hash| node_state.last_seen_block() != hash);
+                match accept_block(blockchain, node_state, block).await {
+                    Ok(()) => {
+                        if is_new {
+                            peer.stats.blocks_relayed.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                    Err(e) => {
+                        warn!("Incoming block is invalid: {e}");
+                        if let Some(misbehavior) = Misbehavior::from_block_error(&e) {
+                            node_state.punish(peer, misbehavior).await;
+                        }
+                    }
+                }
+            }
+            Command::NewTransaction { transaction } => {
+                self.relayed_transaction(transaction, peer).await;
+            }
+            Command::InvTransactions { transaction_ids } => {
+                if transaction_ids.len() > MAX_INV_SIZE {
+                    node_state
+                        .punish(peer, Misbehavior::ProtocolViolation)
+                        .await;
+                    return Ok(());
+                }
+                let mut unknown: Vec<TransactionId> = vec![];
+                for id in transaction_ids {
+                    let seen = node_state.last_seen_transactions().contains(&id);
+                    if !seen && !node_state.mempool.contains(&id).await {
+                        unknown.push(id);
+                    }
+                }
+                if unknown.is_empty() {
+                    return Ok(());
+                }
+
+                // Fetch in the background, a slow peer must not hold up its other notifications
+                if !node_state.tx_relay.start_fetch(peer.address) {
+                    return Ok(());
+                }
+                let behavior = Self {
+                    blockchain: blockchain.clone(),
+                    node_state: node_state.clone(),
+                };
+                let peer = peer.clone();
+                node_state.shutdown.spawn(async move {
+                    if let Err(e) = behavior.fetch_transactions(unknown, &peer).await {
+                        warn!(
+                            "Failed to fetch announced transactions from {}: {e}",
+                            peer.address
+                        );
+                    }
+                    behavior.node_state.tx_relay.end_fetch(peer.address);
+                });
+            }
+            Command::StemTransaction { transaction } => {
+                if let Err(e) =
+                    accept_stem_transaction(blockchain, node_state, transaction, false).await
+                {
+                    warn!("Incoming stem transaction is invalid: {e}");
+                    if let Some(misbehavior) = Misbehavior::from_transaction_error(&e) {
+                        node_state.punish(peer, misbehavior).await;
+                    }
+                }
+            }
+            command => {
+                return Err(PeerError::Unknown(format!(
+                    "Got {} as a notification",
+                    command.name()
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
     async fn get_height(&self) -> usize {
         self.blockchain.block_store().get_height()
     }
 
+    async fn on_misbehavior(&self, peer: &PeerHandle, misbehavior: Misbehavior) {
+        self.node_state.punish(peer, misbehavior).await;
+    }
+
     async fn on_kill(&self, peer: &PeerHandle) {
+        self.node_state.tx_relay.forget_peer(peer.address);
         self.node_state
             .connected_peers
             .write()
             .await
             .remove(&peer.address);
-        self.node_state.punish_ip(peer.address.ip()).await;
-        if self.node_state.client_health_scores.read().await.get(&peer.address.ip()).unwrap_or(&0) > &BAN_SCORE_THRESHOLD {
-            // Extra punishment
-            for _ in (0..10) { // 20 Min of punishment
-                self.node_state.punish_ip(peer.address.ip()).await;
-            }
-        }
     }
 }
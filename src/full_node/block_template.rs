@@ -0,0 +1,82 @@
+use std::collections::HashSet;
+
+use num_bigint::BigUint;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    UtilError, build_block,
+    core::{
+        block::{Block, MAX_TRANSACTIONS_PER_BLOCK},
+        blockchain::{Blockchain, validate_transaction_timestamp},
+        difficulty::calculate_block_difficulty,
+        transaction::Transaction,
+    },
+    crypto::keys::Public,
+    economics::{calculate_dev_fee, get_block_reward},
+    full_node::mempool::MemPool,
+};
+
+/// A block ready to be mined. Only the nonce has to be found, the hash of `block.get_hashing_buf()` must be at or below `target`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BlockTemplate {
+    /// Block with the selected transactions and the reward transaction, without a nonce or hash
+    pub block: Block,
+    /// Height the block will have
+    pub height: usize,
+    /// Block difficulty, already eased for the amount of transactions in the block
+    pub target: [u8; 32],
+    /// Reward the miner gets (dev fee already taken off)
+    pub reward: u64,
+}
+
+/// Build a block template for `miner`, with transactions from the mempool. Picks the mempool transactions that are valid together, in mempool order,
+/// skipping ones that conflict, expired or spend outputs that aren't confirmed, up to `MAX_TRANSACTIONS_PER_BLOCK`. Children of mempool transactions wait for a later block
+pub async fn build_block_template(
+    blockchain: &Blockchain,
+    mempool: &MemPool,
+    miner: Public,
+) -> Result<BlockTemplate, UtilError> {
+    let difficulty = BigUint::from_bytes_be(&blockchain.get_transaction_difficulty());
+    let mut selected: Vec<Transaction> = vec![];
+    let mut spent = HashSet::new();
+
+    // One spot is left for the reward transaction
+    for transaction in mempool.get_mempool().await {
+        if selected.len() == MAX_TRANSACTIONS_PER_BLOCK - 1 {
+            break;
+        }
+        if transaction
+            .inputs
+            .iter()
+            .any(|input| spent.contains(&(input.transaction_id, input.output_index)))
+            || validate_transaction_timestamp(&transaction).is_err()
+            || blockchain
+                .get_utxos()
+                .validate_transaction(&transaction, &difficulty, false)
+                .is_err()
+        {
+            continue;
+        }
+
+        spent.extend(
+            transaction
+                .inputs
+                .iter()
+                .map(|input| (input.transaction_id, input.output_index)),
+        );
+        selected.push(transaction);
+    }
+
+    let height = blockchain.block_store().get_height();
+    let block = build_block(blockchain, &selected, miner).await?;
+    let reward = get_block_reward(height);
+    Ok(BlockTemplate {
+        target: calculate_block_difficulty(
+            &block.meta.block_pow_difficulty,
+            block.transactions.len(),
+        ),
+        block,
+        height,
+        reward: reward - calculate_dev_fee(reward),
+    })
+}
@@ -0,0 +1,185 @@
+use std::time::Duration;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+
+use crate::node::connector::Endpoint;
+
+/// Max size of a fetched seed file
+pub const MAX_SEED_FILE_SIZE: u64 = 1024 * 1024;
+
+/// Timeout for fetching a remote seed file
+pub const SEED_FILE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug, Clone)]
+pub enum BootstrapError {
+    #[error("IO error: {0}")]
+    Io(String),
+
+    #[error("Unsupported seed file location: {0}")]
+    UnsupportedLocation(String),
+
+    #[error("HTTP error: {0}")]
+    Http(String),
+}
+
+impl From<std::io::Error> for BootstrapError {
+    fn from(e: std::io::Error) -> Self {
+        BootstrapError::Io(e.to_string())
+    }
+}
+
+/// Where the auto peer daemon starts looking for peers, and which peers it always stays connected to
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct BootstrapConfig {
+    /// Peers to connect to when we have no peers at all
+    pub seeds: Vec<Endpoint>,
+    /// Optional seed file with more seeds. Either a path, a `file://` URL or a `http://` URL.
+    /// URLs are fetched with plain HTTP only: no TLS (`https://` is refused), no redirects, no chunked bodies, and at most `MAX_SEED_FILE_SIZE` bytes.
+    /// One `ip:port` or `host:port` per line, `#` starts a comment
+    pub seed_file: Option<String>,
+    /// Peers that are always reconnected after they disconnect
+    pub persistent_peers: Vec<Endpoint>,
+}
+
+impl BootstrapConfig {
+    pub fn new(seeds: Vec<Endpoint>) -> Self {
+        Self {
+            seeds,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_seed_file(mut self, seed_file: impl Into<String>) -> Self {
+        self.seed_file = Some(seed_file.into());
+        self
+    }
+
+    pub fn with_persistent_peers(mut self, persistent_peers: Vec<Endpoint>) -> Self {
+        self.persistent_peers = persistent_peers;
+        self
+    }
+
+    /// Get all seeds, including the ones listed in the seed file. If the seed file can't be read, only the static seeds are returned
+    pub async fn resolve_seeds(&self) -> Vec<Endpoint> {
+        let mut seeds = self.seeds.clone();
+        if let Some(seed_file) = &self.seed_file {
+            match fetch_seed_file(seed_file).await {
+                Ok(contents) => {
+                    for seed in parse_seed_list(&contents) {
+                        if !seeds.contains(&seed) {
+                            seeds.push(seed);
+                        }
+                    }
+                }
+                Err(e) => warn!("Could not read seed file {seed_file}, error: {e}"),
+            }
+        }
+        seeds
+    }
+}
+
+/// Parse a seed list, one `ip:port` or `host:port` per line. Blank lines and `#` comments are ignored, invalid lines are skipped
+pub fn parse_seed_list(contents: &str) -> Vec<Endpoint> {
+    contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .filter_map(|line| match line.parse() {
+            Ok(seed) => Some(seed),
+            Err(_) => {
+                warn!("Skipping invalid seed address: {line}");
+                None
+            }
+        })
+        .collect()
+}
+
+/// Read a seed file from a path, `file://` URL or `http://` URL
+pub async fn fetch_seed_file(location: &str) -> Result<String, BootstrapError> {
+    if let Some(url) = location.strip_prefix("http://") {
+        return timeout(SEED_FILE_TIMEOUT, fetch_http(url))
+            .await
+            .map_err(|_| BootstrapError::Http("Timed out".into()))?;
+    }
+    if location.contains("://") && !location.starts_with("file://") {
+        return Err(BootstrapError::UnsupportedLocation(location.to_string()));
+    }
+
+    let path = location.strip_prefix("file://").unwrap_or(location);
+    Ok(std::fs::read_to_string(path)?)
+}
+
+/// Minimal HTTP/1.0 GET, `url` is without the `http://` prefix. Only a plain `200` response of at most `MAX_SEED_FILE_SIZE` bytes is accepted
+async fn fetch_http(url: &str) -> Result<String, BootstrapError> {
+    let (host, path) = match url.find('/') {
+        Some(i) => (&url[..i], &url[i..]),
+        None => (url, "/"),
+    };
+    let address = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{host}:80")
+    };
+
+    let mut stream = TcpStream::connect(address).await?;
+    stream
+        .write_all(
+            format!("GET {path} HTTP/1.0\r\nHost: {host}\r\nConnection: close\r\n\r\n").as_bytes(),
+        )
+        .await?;
+
+    // Read one byte past the limit, to tell a full response from a cut off one
+    let mut response = vec![];
+    stream
+        .take(MAX_SEED_FILE_SIZE + 1)
+        .read_to_end(&mut response)
+        .await?;
+    if response.len() as u64 > MAX_SEED_FILE_SIZE {
+        return Err(BootstrapError::Http(format!(
+            "Response is bigger than {MAX_SEED_FILE_SIZE} bytes"
+        )));
+    }
+    let response = String::from_utf8(response)
+        .map_err(|_| BootstrapError::Http("Response is not UTF-8".into()))?;
+
+    let malformed = || BootstrapError::Http("Malformed response".into());
+    let (head, body) = response.split_once("\r\n\r\n").ok_or_else(malformed)?;
+    let mut lines = head.lines();
+    let status = lines.next().unwrap_or_default();
+    let mut parts = status.split_whitespace();
+    if !parts
+        .next()
+        .is_some_and(|version| version.starts_with("HTTP/1."))
+    {
+        return Err(malformed());
+    }
+    // Redirects and errors end up here too
+    if parts.next() != Some("200") {
+        return Err(BootstrapError::Http(status.to_string()));
+    }
+
+    for header in lines {
+        let (name, value) = header.split_once(':').ok_or_else(malformed)?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("transfer-encoding") && !value.eq_ignore_ascii_case("identity")
+        {
+            return Err(BootstrapError::Http(format!(
+                "Unsupported transfer encoding: {value}"
+            )));
+        }
+        if name.eq_ignore_ascii_case("content-length") && value.parse().ok() != Some(body.len()) {
+            return Err(BootstrapError::Http(
+                "Body does not match its Content-Length".into(),
+            ));
+        }
+    }
+
+    Ok(body.to_string())
+}
@@ -1,22 +1,94 @@
-use std::sync::atomic::AtomicUsize;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    net::SocketAddr,
+    time::Duration,
+};
 
 use anyhow::anyhow;
-use futures::{StreamExt, TryStreamExt, stream};
-use log::info;
+use futures::{StreamExt, stream::FuturesUnordered};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
 use crate::{
-    full_node::SharedBlockchain,
+    core::{block::Block, blockchain::validate_header},
+    crypto::Hash,
+    full_node::{SharedBlockchain, node_state::SharedNodeState, prune_blocks},
     node::{
         message::{Command, Message},
+        misbehavior::Misbehavior,
         peer::PeerHandle,
     },
 };
 
 const IBD_SAFE_SKIP_TX_HASHING: usize = 500;
 
+/// Max amount of blocks that are requested ahead of the next block to be applied
+pub const BLOCK_DOWNLOAD_WINDOW: usize = 64;
+
+/// Max amount of block requests in flight to one peer
+pub const MAX_BLOCKS_IN_FLIGHT_PER_PEER: usize = 8;
+
+/// A block request that takes longer than this counts as a stall, and gets reassigned
+pub const BLOCK_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Peers that stall this many times are dropped from the download
+pub const MAX_PEER_STALLS: usize = 3;
+
+/// What the initial block download is currently doing
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub enum IbdPhase {
+    #[default]
+    Idle,
+    Headers,
+    Blocks,
+    Complete,
+    Failed {
+        reason: String,
+    },
+}
+
+/// Progress of the initial block download
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct IbdStatus {
+    pub phase: IbdPhase,
+    /// Local height when the download started
+    pub start_height: usize,
+    /// Height of the peer we are syncing to
+    pub target_height: usize,
+    /// Amount of headers downloaded and validated
+    pub headers_validated: usize,
+    /// Amount of blocks downloaded and added to the blockchain
+    pub blocks_applied: usize,
+    /// Amount of peers blocks are currently being downloaded from
+    pub download_peers: usize,
+}
+
+/// Sync the local blockchain to `peer`.
+/// Headers are downloaded from `peer` and their chain (links, difficulties and pow) is validated first, block bodies are then downloaded from every connected full peer in parallel.
+/// Progress is reported through `NodeState::ibd_status`
 pub async fn ibd_blockchain(
     peer: PeerHandle,
     blockchain: SharedBlockchain,
-    full_ibd: bool
+    node_state: SharedNodeState,
+    full_ibd: bool,
+) -> Result<(), anyhow::Error> {
+    *node_state.is_syncing.write().await = true;
+    let res = run_ibd(&peer, &blockchain, &node_state, full_ibd).await;
+    *node_state.is_syncing.write().await = false;
+
+    if let Err(e) = &res {
+        node_state.ibd_status.write().await.phase = IbdPhase::Failed {
+            reason: e.to_string(),
+        };
+    }
+    res
+}
+
+async fn run_ibd(
+    peer: &PeerHandle,
+    blockchain: &SharedBlockchain,
+    node_state: &SharedNodeState,
+    full_ibd: bool,
 ) -> Result<(), anyhow::Error> {
     info!("Starting initial block download");
 
@@ -34,66 +106,240 @@ pub async fn ibd_blockchain(
         _ => return Err(anyhow!("Could not fetch peer height to sync blockchain")),
     };
 
+    *node_state.ibd_status.write().await = IbdStatus {
+        phase: IbdPhase::Headers,
+        start_height: local_height,
+        target_height: remote_height,
+        ..Default::default()
+    };
+
     if remote_height <= local_height {
         info!("[SYNC] Already synced");
+        node_state.ibd_status.write().await.phase = IbdPhase::Complete;
         return Ok(());
     }
+    if peer.first_block() > local_height {
+        return Err(anyhow!(
+            "Peer is pruned, it has no blocks below height {}",
+            peer.first_block()
+        ));
+    }
 
-    // ---- Fetch block hashes ----
-    let hashes = match peer
-        .request(Message::new(Command::GetBlockHashes {
-            start: local_height,
-            end: remote_height,
-        }))
-        .await?
-        .command
+    let hashes =
+        download_headers(peer, blockchain, node_state, local_height, remote_height).await?;
+    info!("[SYNC] Validated {} block headers", hashes.len());
+
+    let res = download_blocks(peer, blockchain, node_state, &hashes, full_ibd).await;
     {
-        Command::GetBlockHashesResponse { block_hashes } => block_hashes,
-        _ => {
-            return Err(anyhow!(
-                "Could not fetch peer block hashes to sync blockchain"
-            ));
+        let _lock = node_state.processing.lock().await;
+        prune_blocks(blockchain, node_state).await;
+    }
+    res?;
+
+    node_state.ibd_status.write().await.phase = IbdPhase::Complete;
+    info!("[SYNC] Blockchain synced successfully");
+
+    Ok(())
+}
+
+/// Download and validate the header chain `local_height..remote_height`, returns the validated block hashes in order
+async fn download_headers(
+    peer: &PeerHandle,
+    blockchain: &SharedBlockchain,
+    node_state: &SharedNodeState,
+    local_height: usize,
+    remote_height: usize,
+) -> Result<Vec<Hash>, anyhow::Error> {
+    let difficulty_state = blockchain.get_difficulty_manager().clone();
+    let mut previous_block = blockchain.block_store().get_last_block_hash();
+    let mut hashes = Vec::with_capacity(remote_height - local_height);
+
+    while local_height + hashes.len() < remote_height {
+        let start = local_height + hashes.len();
+        let headers = match peer
+            .request(Message::new(Command::GetHeaders {
+                start,
+                end: remote_height,
+            }))
+            .await?
+            .command
+        {
+            Command::GetHeadersResponse { headers } => headers,
+            _ => return Err(anyhow!("Could not fetch peer headers to sync blockchain")),
+        };
+
+        if headers.is_empty() {
+            return Err(anyhow!("Peer returned no headers from height {start}"));
         }
-    };
 
-    info!("[SYNC] Fetched {} block hashes", hashes.len());
-
-    const BUFFER_SIZE: usize = 10;
-
-    let left = AtomicUsize::new(remote_height);
-    
-    // ---- Download concurrently, apply sequentially ----
-    stream::iter(hashes)
-        .map(|hash| {
-            let peer = peer.clone();
-
-            async move {
-                let resp = peer
-                    .request(Message::new(Command::GetBlock { block_hash: hash }))
-                    .await?;
-
-                match resp.command {
-                    Command::GetBlockResponse { block } => block
-                        .ok_or_else(|| anyhow!("Peer returned empty block {}", hash.dump_base36())),
-                    _ => Err(anyhow!(
-                        "Unexpected response for block {}",
-                        hash.dump_base36()
-                    )),
+        for header in headers.into_iter().take(remote_height - start) {
+            if let Err(e) = validate_header(&header, previous_block, &difficulty_state) {
+                if let Some(misbehavior) = Misbehavior::from_block_error(&e) {
+                    node_state.punish(peer, misbehavior).await;
                 }
+                return Err(e.into());
             }
-        })
-        .buffered(BUFFER_SIZE) // 👈 keeps order, runs concurrently
-        .try_for_each(|block| async {
-            let left_to_add = left.load(std::sync::atomic::Ordering::SeqCst);
-            blockchain.add_block(block, left_to_add > IBD_SAFE_SKIP_TX_HASHING && !full_ibd)?;
-            if left_to_add > 0 {
-                left.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+            previous_block = header.meta.hash.unwrap(); // Unwrap is okay, we validated the header hash
+            hashes.push(previous_block);
+        }
+
+        node_state.ibd_status.write().await.headers_validated = hashes.len();
+    }
+
+    Ok(hashes)
+}
+
+/// Download the blocks of `hashes` from all connected full peers, with a sliding window, and apply them in order
+async fn download_blocks(
+    peer: &PeerHandle,
+    blockchain: &SharedBlockchain,
+    node_state: &SharedNodeState,
+    hashes: &[Hash],
+    full_ibd: bool,
+) -> Result<(), anyhow::Error> {
+    let start_height = blockchain.block_store().get_height();
+    // Client peers might be light nodes, which don't store blocks
+    let mut peers: Vec<PeerHandle> = node_state
+        .connected_peers
+        .read()
+        .await
+        .values()
+        .filter(|connected| !connected.is_client && connected.address != peer.address)
+        .cloned()
+        .collect();
+    peers.push(peer.clone());
+
+    {
+        let mut status = node_state.ibd_status.write().await;
+        status.phase = IbdPhase::Blocks;
+        status.download_peers = peers.len();
+    }
+    info!("[SYNC] Downloading blocks from {} peers", peers.len());
+
+    let mut queue: VecDeque<usize> = VecDeque::new();
+    let mut downloaded: BTreeMap<usize, Block> = BTreeMap::new();
+    let mut in_flight: HashMap<SocketAddr, usize> = HashMap::new();
+    let mut stalls: HashMap<SocketAddr, usize> = HashMap::new();
+    let mut failed_by: HashMap<usize, HashSet<SocketAddr>> = HashMap::new();
+    let mut requests = FuturesUnordered::new();
+    let mut next_request = 0;
+    let mut next_apply = 0;
+
+    while next_apply < hashes.len() {
+        // Slide the window forward
+        while next_request < hashes.len() && next_request < next_apply + BLOCK_DOWNLOAD_WINDOW {
+            queue.push_back(next_request);
+            next_request += 1;
+        }
+
+        // Hand out work to the least busy peers that still have the block, avoiding ones that already failed it
+        while let Some(&index) = queue.front() {
+            let Some(selected) = peers
+                .iter()
+                .filter(|p| {
+                    p.first_block() <= start_height + index
+                        && *in_flight.get(&p.address).unwrap_or(&0) < MAX_BLOCKS_IN_FLIGHT_PER_PEER
+                })
+                .min_by_key(|p| {
+                    (
+                        failed_by
+                            .get(&index)
+                            .is_some_and(|failed| failed.contains(&p.address)),
+                        *in_flight.get(&p.address).unwrap_or(&0),
+                    )
+                })
+            else {
+                break;
+            };
+            queue.pop_front();
+            *in_flight.entry(selected.address).or_insert(0) += 1;
+            requests.push(request_block(
+                selected.clone(),
+                node_state.clone(),
+                index,
+                hashes[index],
+            ));
+        }
+
+        let Some((address, index, result)) = requests.next().await else {
+            return Err(anyhow!("No peers left to download blocks from"));
+        };
+        if let Some(count) = in_flight.get_mut(&address) {
+            *count = count.saturating_sub(1);
+        }
+
+        match result {
+            Ok(block) => {
+                downloaded.insert(index, block);
             }
-            Ok(())
-        })
-        .await?;
+            Err(e) => {
+                warn!("[SYNC] Block download from {address} failed: {e}");
+                failed_by.entry(index).or_default().insert(address);
+                queue.push_front(index);
 
-    info!("[SYNC] Blockchain synced successfully");
+                let peer_stalls = stalls.entry(address).or_insert(0);
+                *peer_stalls += 1;
+                if *peer_stalls >= MAX_PEER_STALLS {
+                    warn!("[SYNC] Dropping stalled peer {address} from block download");
+                    peers.retain(|p| p.address != address);
+                    node_state.ibd_status.write().await.download_peers = peers.len();
+                }
+            }
+        }
+
+        // Apply everything that is next in line. The lock is only held per block, so new blocks and submissions aren't stuck behind the whole download
+        while let Some(block) = downloaded.remove(&next_apply) {
+            let left_to_add = hashes.len() - next_apply;
+            let _lock = node_state.processing.lock().await;
+            blockchain.add_block(block, left_to_add > IBD_SAFE_SKIP_TX_HASHING && !full_ibd)?;
+            failed_by.remove(&next_apply);
+            next_apply += 1;
+        }
+        node_state.ibd_status.write().await.blocks_applied = next_apply;
+    }
 
     Ok(())
 }
+
+/// Request a single block, making sure it is the one the header chain promised
+async fn request_block(
+    peer: PeerHandle,
+    node_state: SharedNodeState,
+    index: usize,
+    block_hash: Hash,
+) -> (SocketAddr, usize, Result<Block, anyhow::Error>) {
+    let result = async {
+        let response = peer
+            .request_with_timeout(
+                Message::new(Command::GetBlock { block_hash }),
+                BLOCK_DOWNLOAD_TIMEOUT,
+            )
+            .await?;
+
+        match response.command {
+            Command::GetBlockResponse { block: Some(block) }
+                if block.meta.hash == Some(block_hash) =>
+            {
+                Ok(block)
+            }
+            Command::GetBlockResponse { block: None } => Err(anyhow!(
+                "Peer did not return block {}",
+                block_hash.dump_base36()
+            )),
+            Command::GetBlockResponse { block: Some(_) } => {
+                node_state.punish(&peer, Misbehavior::InvalidBlock).await;
+                Err(anyhow!(
+                    "Peer returned a different block than {}",
+                    block_hash.dump_base36()
+                ))
+            }
+            _ => Err(anyhow!(
+                "Unexpected response for block {}",
+                block_hash.dump_base36()
+            )),
+        }
+    }
+    .await;
+
+    (peer.address, index, result)
+}
@@ -0,0 +1,166 @@
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+use anyhow::anyhow;
+use rand::random;
+use tokio::time::{Instant, sleep};
+
+use crate::{
+    build_block,
+    core::{block::Block, blockchain::BlockchainError},
+    crypto::{Hash, keys::Private},
+    full_node::{
+        NodeHandle, SharedBlockchain, accept_block, connect_peer, create_full_node,
+        node_state::SharedNodeState, p2p_server::start_p2p_server,
+    },
+};
+
+/// Ping interval of localnet peers, short so reorgs happen quickly
+pub const LOCALNET_PING_INTERVAL: Duration = Duration::from_millis(200);
+
+/// One node of a `Localnet`
+pub struct LocalNode {
+    pub blockchain: SharedBlockchain,
+    pub node_state: SharedNodeState,
+    pub path: PathBuf,
+    pub port: u16,
+}
+
+impl LocalNode {
+    pub fn address(&self) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], self.port))
+    }
+
+    pub fn height(&self) -> usize {
+        self.blockchain.block_store().get_height()
+    }
+
+    pub fn tip(&self) -> Hash {
+        self.blockchain.block_store().get_last_block_hash()
+    }
+}
+
+/// Several full nodes running in this process, talking over localhost. Meant for tests
+pub struct Localnet {
+    pub nodes: Vec<LocalNode>,
+}
+
+impl Localnet {
+    /// Start `size` unconnected nodes in a temporary directory, listening on `base_port..base_port + size`
+    pub async fn start(size: usize, base_port: u16) -> Result<Self, anyhow::Error> {
+        let root = std::env::temp_dir().join(format!("snap-coin-localnet-{}", random::<u64>()));
+        let mut nodes = Vec::with_capacity(size);
+        for i in 0..size {
+            let path = root.join(format!("node-{i}"));
+            let port = base_port + i as u16;
+            let (blockchain, node_state) =
+                create_full_node(path.to_str().expect("Temp dir is not valid UTF-8"));
+            node_state.peer_config.write().await.ping_interval = LOCALNET_PING_INTERVAL;
+            start_p2p_server(port, blockchain.clone(), node_state.clone()).await?;
+            nodes.push(LocalNode {
+                blockchain,
+                node_state,
+                path,
+                port,
+            });
+        }
+        Ok(Self { nodes })
+    }
+
+    /// Connect node `from` to node `to`
+    pub async fn connect(&self, from: usize, to: usize) -> Result<(), anyhow::Error> {
+        let node = &self.nodes[from];
+        connect_peer(
+            self.nodes[to].address().into(),
+            &node.blockchain,
+            &node.node_state,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Connect every node to the next one
+    pub async fn connect_line(&self) -> Result<(), anyhow::Error> {
+        for i in 1..self.nodes.len() {
+            self.connect(i - 1, i).await?;
+        }
+        Ok(())
+    }
+
+    /// Drop the connection that node `from` made to node `to`, waiting until both sides noticed
+    pub async fn disconnect(&self, from: usize, to: usize) -> Result<(), anyhow::Error> {
+        let address = self.nodes[to].address();
+        let peer = self.nodes[from]
+            .node_state
+            .connected_peers
+            .read()
+            .await
+            .get(&address)
+            .cloned()
+            .ok_or(anyhow!("Node {from} is not connected to node {to}"))?;
+        let _ = peer.kill("Localnet disconnect".to_string()).await;
+
+        let peers_before = self.nodes[to].node_state.connected_peers.read().await.len();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            let gone_from = !self.nodes[from]
+                .node_state
+                .connected_peers
+                .read()
+                .await
+                .contains_key(&address);
+            let gone_to =
+                self.nodes[to].node_state.connected_peers.read().await.len() < peers_before;
+            if gone_from && gone_to {
+                return Ok(());
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+        Err(anyhow!("Node {from} and node {to} did not disconnect"))
+    }
+
+    /// Mine an empty block on top of the chain of `node`, and accept it there (which relays it)
+    pub async fn mine(&self, node: usize) -> Result<Block, anyhow::Error> {
+        let node = &self.nodes[node];
+        let mut block = build_block(
+            &*node.blockchain,
+            &vec![],
+            Private::new_random().to_public(),
+        )
+        .await?;
+        #[allow(deprecated)]
+        block.compute_pow()?;
+        accept_block(&node.blockchain, &node.node_state, block.clone()).await?;
+        Ok(block)
+    }
+
+    /// Wait until `nodes` all have the same tip, returns false if they don't within `timeout`
+    pub async fn wait_for_consensus(&self, nodes: &[usize], timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let first = self.nodes[nodes[0]].tip();
+            if nodes.iter().all(|i| self.nodes[*i].tip() == first) {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    /// Wait until every node has the same tip, returns false if they don't within `timeout`
+    pub async fn wait_for_all(&self, timeout: Duration) -> bool {
+        let all: Vec<usize> = (0..self.nodes.len()).collect();
+        self.wait_for_consensus(&all, timeout).await
+    }
+
+    /// Shut down every node
+    pub async fn shutdown(&self) -> Result<(), BlockchainError> {
+        for node in &self.nodes {
+            NodeHandle::new(node.blockchain.clone(), node.node_state.clone())
+                .shutdown()
+                .await?;
+        }
+        Ok(())
+    }
+}
@@ -1,114 +1,903 @@
-use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
+use bincode::enc::write::SizeWriter;
+use log::{error, info};
+use num_bigint::BigUint;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
 use tokio::{sync::RwLock, time::sleep};
 
 use crate::{
-    core::transaction::{Transaction, TransactionId},
-    economics::EXPIRATION_TIME,
+    core::{
+        block::MAX_TRANSACTIONS_PER_BLOCK,
+        blockchain::{Blockchain, BlockchainError, validate_transaction_timestamp},
+        difficulty::calculate_live_transaction_difficulty,
+        transaction::{Transaction, TransactionId, TransactionOutput},
+    },
+    crypto::keys::Public,
+    economics::{EXPIRATION_TIME, TARGET_TIME, TX_TARGET},
+    node::shutdown::Shutdown,
 };
 
+/// Default time between checks for expired transactions
+pub const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Default max amount of unconfirmed ancestors in a chain of mempool transactions
+pub const MAX_CHAIN_DEPTH: usize = 25;
+
+/// Default max amount of transactions in the mempool
+pub const MAX_MEMPOOL_TRANSACTIONS: usize = MAX_TRANSACTIONS_PER_BLOCK * 10;
+
+/// Default max size of all mempool transactions together, in bytes
+pub const MAX_MEMPOOL_BYTES: usize = 16 * 1024 * 1024;
+
+/// Default factor a replacing transaction's pow must be stronger by
+pub const REPLACEMENT_MARGIN: u64 = 2;
+
+/// Default time between saves of the mempool to disk
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Error, Debug)]
+pub enum MemPoolError {
+    #[error("Encoding failed")]
+    Encode,
+
+    #[error("IO error: {0}")]
+    Io(String),
+}
+
+impl From<std::io::Error> for MemPoolError {
+    fn from(e: std::io::Error) -> Self {
+        MemPoolError::Io(e.to_string())
+    }
+}
+
+/// How the mempool is managed
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct MemPoolConfig {
+    /// Time between checks for expired transactions
+    pub expiry_check_interval: Duration,
+    /// Max amount of unconfirmed ancestors in a chain of mempool transactions, 0 only accepts transactions spending confirmed outputs
+    pub max_chain_depth: usize,
+    /// Time between saves of the mempool to disk (if it has a path)
+    pub save_interval: Duration,
+    /// Max amount of transactions, when full the ones with the weakest pow are evicted
+    pub max_transactions: usize,
+    /// Max size of all transactions together in bytes, when full the ones with the weakest pow are evicted
+    pub max_bytes: usize,
+    /// A conflicting transaction replaces mempool ones if its pow is this many times stronger (its id at most theirs divided by this), 0 turns replacement off
+    pub replacement_margin: u64,
+}
+
+impl Default for MemPoolConfig {
+    fn default() -> Self {
+        Self {
+            expiry_check_interval: EXPIRY_CHECK_INTERVAL,
+            max_chain_depth: MAX_CHAIN_DEPTH,
+            save_interval: SAVE_INTERVAL,
+            max_transactions: MAX_MEMPOOL_TRANSACTIONS,
+            max_bytes: MAX_MEMPOOL_BYTES,
+            replacement_margin: REPLACEMENT_MARGIN,
+        }
+    }
+}
+
+/// Why a transaction left the mempool
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum RemovalReason {
+    /// Confirmed in a block
+    Mined,
+    /// Not mined in time
+    Expired,
+    /// Double spent by a block or a replacement, or spent outputs of a transaction that was
+    Conflict,
+    /// No longer valid after a reorg
+    Reorg,
+    /// Pushed out by transactions with stronger pow, while the mempool was full
+    Evicted,
+}
+
+/// Something that happened to a transaction in the mempool, streamed as `ChainEvent::MemPool`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum MemPoolEvent {
+    Added {
+        transaction: TransactionId,
+    },
+    Removed {
+        transaction: TransactionId,
+        reason: RemovalReason,
+    },
+    /// A transaction submitted through the API was not accepted (`None` if it has no id)
+    Rejected {
+        transaction: Option<TransactionId>,
+        reason: BlockchainError,
+    },
+}
+
+/// A transaction output, referenced by the id of its transaction and its index
+pub type OutPoint = (TransactionId, usize);
+
+/// Encoded size of a transaction in bytes
+pub fn transaction_size(transaction: &Transaction) -> usize {
+    let mut writer = SizeWriter::default();
+    let _ = bincode::encode_into_writer(transaction, &mut writer, bincode::config::standard());
+    writer.bytes_written
+}
+
+/// Mempool statistics, to help wallets pick how much pow to do on a transaction
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MemPoolInfo {
+    /// Amount of transactions
+    pub transactions: usize,
+    /// Size of all transactions together, in bytes
+    pub bytes: usize,
+    /// Transaction that has been in the mempool the longest, with the timestamp it got in
+    pub oldest: Option<(TransactionId, u64)>,
+    /// Transaction that got in the mempool last, with the timestamp it got in
+    pub newest: Option<(TransactionId, u64)>,
+    /// Amount of transactions expiring in each `TARGET_TIME` window from now, soonest first
+    pub expiry_histogram: Vec<usize>,
+    /// Transaction difficulty of the chain
+    pub transaction_difficulty: [u8; 32],
+    /// Transaction difficulty eased by the mempool pressure, new transactions are checked against this
+    pub live_difficulty: [u8; 32],
+    /// Blocks a transaction sent now is expected to wait before it is mined, if every block takes `TX_TARGET` transactions
+    pub expected_wait: usize,
+}
+
+/// What was taken out of the mempool to let a transaction in
+#[derive(Debug, Default, PartialEq)]
+pub struct Admission {
+    /// Conflicting transactions it replaced
+    pub replaced: Vec<TransactionId>,
+    /// The replaced transactions with their descendants, which can't be valid without them
+    pub conflicts: Vec<TransactionId>,
+    /// Transactions with the weakest pow (and their descendants), evicted to make room
+    pub evicted: Vec<TransactionId>,
+}
+
+/// A transaction waiting in the mempool
+#[derive(Clone, Debug)]
+pub struct MemPoolEntry {
+    pub transaction: Transaction,
+    /// Timestamp the transaction expires at
+    pub expiry: u64,
+    /// Timestamp the transaction got in the mempool
+    pub added: u64,
+}
+
+/// Mempool transactions, indexed by id, by the outputs they spend and by expiry.
+/// Transactions may spend outputs of other mempool transactions (their parents)
+#[derive(Default)]
+struct MemPoolIndex {
+    by_id: HashMap<TransactionId, MemPoolEntry>,
+    /// Spent output -> transaction spending it
+    by_outpoint: HashMap<OutPoint, TransactionId>,
+    /// Expiry timestamp -> transactions expiring then, in the order they were added
+    by_expiry: BTreeMap<u64, Vec<TransactionId>>,
+    /// Timestamp -> transactions that got in then, in the order they were added
+    by_added: BTreeMap<u64, Vec<TransactionId>>,
+    /// Id bytes -> transaction, the highest id has the weakest pow
+    by_pow: BTreeMap<[u8; 32], TransactionId>,
+    /// Size of all transactions together
+    bytes: usize,
+}
+
+impl MemPoolIndex {
+    /// Returns false if the transaction has no id, or is already in the mempool
+    fn insert(&mut self, transaction: Transaction, expiry: u64, added: u64) -> bool {
+        let Some(transaction_id) = transaction.transaction_id else {
+            return false;
+        };
+        if self.by_id.contains_key(&transaction_id) {
+            return false;
+        }
+        for input in &transaction.inputs {
+            self.by_outpoint
+                .insert((input.transaction_id, input.output_index), transaction_id);
+        }
+        self.by_expiry
+            .entry(expiry)
+            .or_default()
+            .push(transaction_id);
+        self.by_added.entry(added).or_default().push(transaction_id);
+        self.by_pow
+            .insert(transaction_id.dump_buf(), transaction_id);
+        self.bytes += transaction_size(&transaction);
+        self.by_id.insert(
+            transaction_id,
+            MemPoolEntry {
+                transaction,
+                expiry,
+                added,
+            },
+        );
+        true
+    }
+
+    fn remove(&mut self, transaction_id: &TransactionId) -> Option<MemPoolEntry> {
+        let entry = self.by_id.remove(transaction_id)?;
+        self.by_pow.remove(&transaction_id.dump_buf());
+        self.bytes = self
+            .bytes
+            .saturating_sub(transaction_size(&entry.transaction));
+        for input in &entry.transaction.inputs {
+            let outpoint = (input.transaction_id, input.output_index);
+            if self.by_outpoint.get(&outpoint) == Some(transaction_id) {
+                self.by_outpoint.remove(&outpoint);
+            }
+        }
+        if let Some(ids) = self.by_expiry.get_mut(&entry.expiry) {
+            ids.retain(|id| id != transaction_id);
+            if ids.is_empty() {
+                self.by_expiry.remove(&entry.expiry);
+            }
+        }
+        if let Some(ids) = self.by_added.get_mut(&entry.added) {
+            ids.retain(|id| id != transaction_id);
+            if ids.is_empty() {
+                self.by_added.remove(&entry.added);
+            }
+        }
+        Some(entry)
+    }
+
+    /// Remove a transaction and every transaction that spends its outputs (directly or not), returns the ids of everything removed
+    fn remove_with_descendants(&mut self, transaction_id: &TransactionId) -> Vec<TransactionId> {
+        if !self.by_id.contains_key(transaction_id) {
+            return vec![];
+        }
+        let mut removed = vec![*transaction_id];
+        removed.extend(self.descendants(transaction_id));
+        for transaction_id in &removed {
+            self.remove(transaction_id);
+        }
+        removed
+    }
+
+    /// Remove every transaction that expired at `now` (and their descendants), returns their ids
+    fn remove_expired(&mut self, now: u64) -> Vec<TransactionId> {
+        let expired: Vec<TransactionId> = self
+            .by_expiry
+            .range(..=now)
+            .flat_map(|(_, ids)| ids.iter().copied())
+            .collect();
+        let mut removed = vec![];
+        for transaction_id in &expired {
+            removed.extend(self.remove_with_descendants(transaction_id));
+        }
+        removed
+    }
+
+    /// Ids of every mempool transaction that spends outputs of `transaction_id`, directly or not
+    fn descendants(&self, transaction_id: &TransactionId) -> Vec<TransactionId> {
+        let mut descendants = vec![];
+        let mut seen = HashSet::new();
+        let mut queue = VecDeque::from([*transaction_id]);
+        while let Some(parent_id) = queue.pop_front() {
+            let Some(parent) = self.by_id.get(&parent_id) else {
+                continue;
+            };
+            for index in 0..parent.transaction.outputs.len() {
+                if let Some(child_id) = self.by_outpoint.get(&(parent_id, index))
+                    && seen.insert(*child_id)
+                {
+                    descendants.push(*child_id);
+                    queue.push_back(*child_id);
+                }
+            }
+        }
+        descendants
+    }
+
+    /// Outputs of the mempool transactions `transaction` spends from
+    fn parent_outputs(
+        &self,
+        transaction: &Transaction,
+    ) -> HashMap<TransactionId, Vec<TransactionOutput>> {
+        transaction
+            .inputs
+            .iter()
+            .filter_map(|input| {
+                let parent = self.by_id.get(&input.transaction_id)?;
+                Some((input.transaction_id, parent.transaction.outputs.clone()))
+            })
+            .collect()
+    }
+
+    /// Amount of unconfirmed ancestors in the longest chain above `transaction`
+    fn chain_depth(
+        &self,
+        transaction: &Transaction,
+        depths: &mut HashMap<TransactionId, usize>,
+    ) -> usize {
+        let mut depth = 0;
+        for input in &transaction.inputs {
+            let Some(parent) = self.by_id.get(&input.transaction_id) else {
+                continue;
+            };
+            let parent_depth = match depths.get(&input.transaction_id) {
+                Some(parent_depth) => *parent_depth,
+                None => {
+                    let parent_depth = self.chain_depth(&parent.transaction, depths);
+                    depths.insert(input.transaction_id, parent_depth);
+                    parent_depth
+                }
+            };
+            depth = depth.max(parent_depth + 1);
+        }
+        depth
+    }
+
+    fn conflicts_with(&self, transaction: &Transaction) -> Vec<TransactionId> {
+        let mut conflicts = vec![];
+        for input in &transaction.inputs {
+            if let Some(transaction_id) = self
+                .by_outpoint
+                .get(&(input.transaction_id, input.output_index))
+                && !conflicts.contains(transaction_id)
+            {
+                conflicts.push(*transaction_id);
+            }
+        }
+        conflicts
+    }
+
+    /// Ids of the transactions `transaction` replaces, see `MemPool::check_replacement`
+    fn replacements(
+        &self,
+        transaction: &Transaction,
+        margin: u64,
+    ) -> Result<Vec<TransactionId>, BlockchainError> {
+        let replaced = self.conflicts_with(transaction);
+        if replaced.is_empty() {
+            return Ok(replaced);
+        }
+        let transaction_id = transaction
+            .transaction_id
+            .ok_or(BlockchainError::DoubleSpend)?;
+        if margin == 0 {
+            return Err(BlockchainError::DoubleSpend);
+        }
+
+        let strength = BigUint::from_bytes_be(&transaction_id.dump_buf()) * BigUint::from(margin);
+        let owners: HashSet<Public> = transaction
+            .inputs
+            .iter()
+            .map(|input| input.output_owner)
+            .collect();
+        let mut removed = HashSet::new();
+        for replaced_id in &replaced {
+            let Some(entry) = self.by_id.get(replaced_id) else {
+                continue;
+            };
+            if strength > BigUint::from_bytes_be(&replaced_id.dump_buf())
+                || entry
+                    .transaction
+                    .inputs
+                    .iter()
+                    .any(|input| !owners.contains(&input.output_owner))
+            {
+                return Err(BlockchainError::DoubleSpend);
+            }
+            removed.insert(*replaced_id);
+            removed.extend(self.descendants(replaced_id));
+        }
+        if transaction
+            .inputs
+            .iter()
+            .any(|input| removed.contains(&input.transaction_id))
+        {
+            return Err(BlockchainError::DoubleSpend);
+        }
+        Ok(replaced)
+    }
+
+    /// Transactions, oldest expiry first, but parents always before their children
+    fn transactions(&self) -> Vec<&Transaction> {
+        let mut ordered = Vec::with_capacity(self.by_id.len());
+        let mut visited = HashSet::new();
+        for transaction_id in self.by_expiry.values().flatten() {
+            self.visit(transaction_id, &mut visited, &mut ordered);
+        }
+        ordered
+    }
+
+    /// Ids to evict (with their descendants) so `transaction` fits, weakest pow first.
+    /// Every transaction is measured against the same target, so the weakest pow is the highest id.
+    /// Returns `None` if it would have to evict a transaction with a pow at least as strong, or one of its own parents
+    fn evictions_for(
+        &self,
+        transaction: &Transaction,
+        max_transactions: usize,
+        max_bytes: usize,
+    ) -> Option<Vec<TransactionId>> {
+        let transaction_id = transaction.transaction_id?;
+        let mut count = self.by_id.len() + 1;
+        let mut bytes = self.bytes + transaction_size(transaction);
+        if count <= max_transactions && bytes <= max_bytes {
+            return Some(vec![]);
+        }
+        let parents: HashSet<TransactionId> = transaction
+            .inputs
+            .iter()
+            .map(|input| input.transaction_id)
+            .collect();
+
+        let mut evicted = vec![];
+        let mut seen = HashSet::new();
+        for candidate in self.by_pow.values().rev() {
+            if count <= max_transactions && bytes <= max_bytes {
+                break;
+            }
+            if seen.contains(candidate) {
+                continue;
+            }
+            if candidate.dump_buf() <= transaction_id.dump_buf() {
+                return None;
+            }
+            let mut removal = vec![*candidate];
+            removal.extend(self.descendants(candidate));
+            for id in removal {
+                if !seen.insert(id) {
+                    continue;
+                }
+                if parents.contains(&id) {
+                    return None;
+                }
+                count -= 1;
+                bytes -= transaction_size(&self.by_id[&id].transaction);
+                evicted.push(id);
+            }
+        }
+        (count <= max_transactions && bytes <= max_bytes).then_some(evicted)
+    }
+
+    /// Transactions with their expiry and the time they got in, in the order of `transactions`
+    fn entries(&self) -> Vec<(Transaction, u64, u64)> {
+        self.transactions()
+            .into_iter()
+            .map(|transaction| {
+                let entry = &self.by_id[&transaction.transaction_id.unwrap()]; // Unwrap is okay, only transactions with an id get in
+                (transaction.clone(), entry.expiry, entry.added)
+            })
+            .collect()
+    }
+
+    fn visit<'a>(
+        &'a self,
+        transaction_id: &TransactionId,
+        visited: &mut HashSet<TransactionId>,
+        ordered: &mut Vec<&'a Transaction>,
+    ) {
+        let Some(entry) = self.by_id.get(transaction_id) else {
+            return;
+        };
+        if !visited.insert(*transaction_id) {
+            return;
+        }
+        for input in &entry.transaction.inputs {
+            self.visit(&input.transaction_id, visited, ordered);
+        }
+        ordered.push(&entry.transaction);
+    }
+}
+
+/// Save mempool transactions to `path`, with an atomic write
+fn save_index(path: &Path, index: &MemPoolIndex) -> Result<(), MemPoolError> {
+    let buffer = bincode::encode_to_vec(index.entries(), bincode::config::standard())
+        .map_err(|_| MemPoolError::Encode)?;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let tmp_path = path.with_extension("dat.tmp");
+    {
+        let mut f = File::create(&tmp_path)?;
+        f.write_all(&buffer)?;
+        f.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)?;
+
+    Ok(())
+}
+
 pub struct MemPool {
-    /// BTreeMap of expiry timestamp -> transactions
-    pending: Arc<RwLock<BTreeMap<u64, Vec<Transaction>>>>,
+    pub config: Arc<std::sync::RwLock<MemPoolConfig>>,
+    pending: Arc<RwLock<MemPoolIndex>>,
+    path: Option<PathBuf>,
+}
+
+impl Default for MemPool {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MemPool {
+    /// Create a mempool that only lives in memory
     pub fn new() -> Self {
         MemPool {
-            pending: Arc::new(RwLock::new(BTreeMap::new())),
+            config: Arc::new(std::sync::RwLock::new(MemPoolConfig::default())),
+            pending: Arc::new(RwLock::new(MemPoolIndex::default())),
+            path: None,
         }
     }
 
-    /// Starts a background task that removes expired transactions
+    /// Load a mempool saved at `path`, or create an empty one that will be saved there.
+    /// Saved transactions are re-validated against the UTXOs of `blockchain`, expired and invalid ones are dropped
+    pub fn load(path: PathBuf, blockchain: &Blockchain) -> Self {
+        let saved: Vec<(Transaction, u64, u64)> = fs::read(&path)
+            .ok()
+            .and_then(|buf| bincode::decode_from_slice(&buf, bincode::config::standard()).ok())
+            .map(|(saved, _)| saved)
+            .unwrap_or_default();
+
+        let now = chrono::Utc::now().timestamp() as u64;
+        let difficulty = BigUint::from_bytes_be(&blockchain.get_transaction_difficulty());
+        let mut index = MemPoolIndex::default();
+        let saved_count = saved.len();
+        // Saved parents first, so children can be validated against them
+        for (transaction, expiry, added) in saved {
+            let parent_outputs = index.parent_outputs(&transaction);
+            if expiry > now
+                && validate_transaction_timestamp(&transaction).is_ok()
+                && index.conflicts_with(&transaction).is_empty()
+                && blockchain
+                    .get_utxos()
+                    .validate_transaction_with_pending(
+                        &transaction,
+                        &difficulty,
+                        false,
+                        &parent_outputs,
+                    )
+                    .is_ok()
+            {
+                index.insert(transaction, expiry, added);
+            }
+        }
+        if saved_count > 0 {
+            info!(
+                "Loaded {} of {saved_count} saved mempool transactions",
+                index.by_id.len()
+            );
+        }
+
+        MemPool {
+            config: Arc::new(std::sync::RwLock::new(MemPoolConfig::default())),
+            pending: Arc::new(RwLock::new(index)),
+            path: Some(path),
+        }
+    }
+
+    /// Save the mempool to disk (no-op for in memory mempools)
+    pub async fn save(&self) -> Result<(), MemPoolError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        save_index(path, &*self.pending.read().await)
+    }
+
+    /// Starts a background task that saves the mempool every `save_interval`, until `shutdown` is triggered (no-op for in memory mempools)
+    pub fn start_save_daemon(&self, shutdown: &Shutdown) {
+        let Some(path) = self.path.clone() else {
+            return;
+        };
+        let pending = self.pending.clone();
+        let config = self.config.clone();
+        shutdown.spawn(async move {
+            loop {
+                let interval = config.read().unwrap().save_interval;
+                sleep(interval).await;
+                if let Err(e) = save_index(&path, &*pending.read().await) {
+                    error!("Failed to save mempool, error: {e}");
+                }
+            }
+        });
+    }
+
+    /// Starts a background task that removes expired transactions, until `shutdown` is triggered
     pub fn start_expiry_watchdog(
         &self,
+        shutdown: &Shutdown,
         mut on_expiry: impl FnMut(TransactionId) + Send + Sync + 'static,
     ) {
         let pending = self.pending.clone();
-        tokio::spawn(async move {
+        let config = self.config.clone();
+        shutdown.spawn(async move {
             loop {
-                sleep(Duration::from_millis(500)).await;
+                let interval = config.read().unwrap().expiry_check_interval;
+                sleep(interval).await;
                 let now = chrono::Utc::now().timestamp() as u64;
 
-                let mut write_guard = pending.write().await;
-
-                // Remove all expired transactions efficiently
-                let expired_keys: Vec<u64> = write_guard.range(..=now).map(|(&k, _)| k).collect();
-
-                for key in expired_keys {
-                    if let Some(txs) = write_guard.remove(&key) {
-                        for tx in txs {
-                            if let Some(tx_id) = tx.transaction_id {
-                                on_expiry(tx_id);
-                            }
-                        }
-                    }
+                let expired = pending.write().await.remove_expired(now);
+                for transaction_id in expired {
+                    on_expiry(transaction_id);
                 }
             }
         });
     }
 
-    /// Get a vector of all transactions in this mempool
+    /// Get a vector of all transactions in this mempool, oldest expiry first. Parents always come before their children, so any prefix can go in a block
     pub async fn get_mempool(&self) -> Vec<Transaction> {
         self.pending
             .read()
             .await
-            .values()
-            .flat_map(|v| v.iter().cloned())
+            .transactions()
+            .into_iter()
+            .cloned()
             .collect()
     }
 
-    /// Add a transaction to the mempool
+    /// Get a transaction by its id
+    pub async fn get(&self, transaction_id: &TransactionId) -> Option<Transaction> {
+        self.pending
+            .read()
+            .await
+            .by_id
+            .get(transaction_id)
+            .map(|entry| entry.transaction.clone())
+    }
+
+    /// Returns true if a transaction with this id is in the mempool
+    pub async fn contains(&self, transaction_id: &TransactionId) -> bool {
+        self.pending.read().await.by_id.contains_key(transaction_id)
+    }
+
+    /// Get the ids of mempool transactions that spend an output `transaction` spends too
+    pub async fn conflicts_with(&self, transaction: &Transaction) -> Vec<TransactionId> {
+        self.pending.read().await.conflicts_with(transaction)
+    }
+
+    /// Get the outputs of the mempool transactions `transaction` spends from, to validate it with `UTXOs::validate_transaction_with_pending`
+    pub async fn parent_outputs(
+        &self,
+        transaction: &Transaction,
+    ) -> HashMap<TransactionId, Vec<TransactionOutput>> {
+        self.pending.read().await.parent_outputs(transaction)
+    }
+
+    /// Get the amount of unconfirmed ancestors in the longest chain above `transaction`, 0 if it only spends confirmed outputs
+    pub async fn chain_depth(&self, transaction: &Transaction) -> usize {
+        self.pending
+            .read()
+            .await
+            .chain_depth(transaction, &mut HashMap::new())
+    }
+
+    /// Get the ids of every mempool transaction that spends outputs of a transaction, directly or not
+    pub async fn descendants(&self, transaction_id: &TransactionId) -> Vec<TransactionId> {
+        self.pending.read().await.descendants(transaction_id)
+    }
+
+    /// Remove a transaction and its descendants, which can't be valid without it. Returns the ids of everything removed
+    pub async fn remove_transaction(&self, transaction_id: &TransactionId) -> Vec<TransactionId> {
+        self.pending
+            .write()
+            .await
+            .remove_with_descendants(transaction_id)
+    }
+
+    /// Remove mempool transactions that spend an output one of `transactions` (like the ones of a new block) spends too, and their descendants.
+    /// Returns the ids of everything removed
+    pub async fn remove_conflicting(&self, transactions: &[Transaction]) -> Vec<TransactionId> {
+        let mut pending = self.pending.write().await;
+        let mut removed = vec![];
+        for transaction in transactions {
+            for conflict in pending.conflicts_with(transaction) {
+                if Some(conflict) != transaction.transaction_id {
+                    removed.extend(pending.remove_with_descendants(&conflict));
+                }
+            }
+        }
+        removed
+    }
+
+    /// Apply the mempool to the confirmed unspent outputs of `address`: outputs spent by mempool transactions are dropped, and unspent outputs of mempool transactions to `address` are added
+    pub async fn available_outputs(
+        &self,
+        address: Public,
+        mut confirmed: Vec<(TransactionId, TransactionOutput, usize)>,
+    ) -> Vec<(TransactionId, TransactionOutput, usize)> {
+        let pending = self.pending.read().await;
+        confirmed.retain(|(transaction_id, _, index)| {
+            !pending.by_outpoint.contains_key(&(*transaction_id, *index))
+        });
+        for transaction in pending.transactions() {
+            let transaction_id = transaction.transaction_id.unwrap(); // Unwrap is okay, mempool transactions have ids
+            for (index, output) in transaction.outputs.iter().enumerate() {
+                if output.receiver == address
+                    && !pending.by_outpoint.contains_key(&(transaction_id, index))
+                {
+                    confirmed.push((transaction_id, *output, index));
+                }
+            }
+        }
+        confirmed
+    }
+
+    /// Add a transaction to the mempool, transactions without an id or already in the mempool are ignored
     /// WARNING: Make sure this transaction is valid before
     pub async fn add_transaction(&self, transaction: Transaction) {
-        let expiry = chrono::Utc::now().timestamp() as u64 + EXPIRATION_TIME;
+        let now = chrono::Utc::now().timestamp() as u64;
 
-        let mut write_guard = self.pending.write().await;
-        write_guard.entry(expiry).or_default().push(transaction);
+        self.pending
+            .write()
+            .await
+            .insert(transaction, now + EXPIRATION_TIME, now);
     }
 
-    /// Returns true if a transaction is valid (check for double spending)
-    pub async fn validate_transaction(&self, transaction: &Transaction) -> bool {
-        let mempool = self.get_mempool().await;
-        for mempool_transaction in mempool {
-            if transaction.inputs.iter().any(|i| {
-                mempool_transaction.inputs.iter().any(|mi| {
-                    mi.output_index == i.output_index && mi.transaction_id == i.transaction_id
-                })
-            }) {
-                return false;
-            }
+    /// Add a transaction, evicting the ones with the weakest pow (and their descendants) if the mempool is full. Returns the ids of evicted transactions.
+    /// Fails with `MemPoolFull` if every transaction that would have to go has a pow at least as strong as this one.
+    /// Fails with `DoubleSpend` if it conflicts with a mempool transaction, one could have been added since it was validated.
+    /// WARNING: Make sure this transaction is valid before
+    pub async fn try_add_transaction(
+        &self,
+        transaction: Transaction,
+    ) -> Result<Vec<TransactionId>, BlockchainError> {
+        let expiry = chrono::Utc::now().timestamp() as u64 + EXPIRATION_TIME;
+        self.try_add_transaction_expiring(transaction, expiry).await
+    }
+
+    /// Same as `try_add_transaction`, but the transaction expires at `expiry` instead of `EXPIRATION_TIME` from now
+    pub async fn try_add_transaction_expiring(
+        &self,
+        transaction: Transaction,
+        expiry: u64,
+    ) -> Result<Vec<TransactionId>, BlockchainError> {
+        let config = *self.config.read().unwrap();
+        let mut pending = self.pending.write().await;
+        if transaction
+            .transaction_id
+            .is_some_and(|transaction_id| pending.by_id.contains_key(&transaction_id))
+        {
+            return Ok(vec![]);
         }
-        true
+        if !pending.conflicts_with(&transaction).is_empty() {
+            return Err(BlockchainError::DoubleSpend);
+        }
+        let evicted = pending
+            .evictions_for(&transaction, config.max_transactions, config.max_bytes)
+            .ok_or(BlockchainError::MemPoolFull)?;
+        for transaction_id in &evicted {
+            pending.remove(transaction_id);
+        }
+        let now = chrono::Utc::now().timestamp() as u64;
+        pending.insert(transaction, expiry, now);
+        Ok(evicted)
     }
 
-    /// Remove transactions that have been spent
-    pub async fn spend_transactions(&self, transactions: Vec<TransactionId>) {
+    /// Get the ids of the mempool transactions `transaction` replaces, the ones it conflicts with (empty if none).
+    /// Fails with `DoubleSpend` unless its pow is `replacement_margin` times stronger than each of them, it is signed by the owners of all their inputs, and it doesn't spend outputs of anything it pushes out.
+    /// Conflicts can change until the transaction is added, `try_replace_transaction` checks again
+    pub async fn check_replacement(
+        &self,
+        transaction: &Transaction,
+    ) -> Result<Vec<TransactionId>, BlockchainError> {
+        let margin = self.config.read().unwrap().replacement_margin;
+        self.pending.read().await.replacements(transaction, margin)
+    }
+
+    /// Add a transaction in place of the mempool transactions it conflicts with (see `check_replacement`), evicting the ones with the weakest pow if the mempool is full.
+    /// Checked and applied under one lock, if the transaction can't get in the mempool is left as it was.
+    /// WARNING: Make sure this transaction is valid before
+    pub async fn try_replace_transaction(
+        &self,
+        transaction: Transaction,
+        expiry: u64,
+    ) -> Result<Admission, BlockchainError> {
+        let config = *self.config.read().unwrap();
         let mut pending = self.pending.write().await;
+        if transaction
+            .transaction_id
+            .is_some_and(|transaction_id| pending.by_id.contains_key(&transaction_id))
+        {
+            return Ok(Admission::default());
+        }
+        let replaced = pending.replacements(&transaction, config.replacement_margin)?;
 
-        for txs in pending.values_mut() {
-            txs.retain(|mempool_tx| {
-                if let Some(id) = mempool_tx.transaction_id {
-                    !transactions.contains(&id)
-                } else {
-                    true
-                }
-            });
+        let mut removed = vec![];
+        for replaced_id in &replaced {
+            let mut ids = vec![*replaced_id];
+            ids.extend(pending.descendants(replaced_id));
+            removed.extend(
+                ids.into_iter()
+                    .filter_map(|id| Some((id, pending.remove(&id)?))),
+            );
         }
+        let Some(evicted) =
+            pending.evictions_for(&transaction, config.max_transactions, config.max_bytes)
+        else {
+            // Put the replaced transactions back, parents before their children
+            for (_, entry) in removed {
+                pending.insert(entry.transaction, entry.expiry, entry.added);
+            }
+            return Err(BlockchainError::MemPoolFull);
+        };
+        for transaction_id in &evicted {
+            pending.remove(transaction_id);
+        }
+        let now = chrono::Utc::now().timestamp() as u64;
+        pending.insert(transaction, expiry, now);
+        Ok(Admission {
+            replaced,
+            conflicts: removed.into_iter().map(|(id, _)| id).collect(),
+            evicted,
+        })
+    }
+
+    /// Returns true if a transaction is valid (check for double spending)
+    pub async fn validate_transaction(&self, transaction: &Transaction) -> bool {
+        self.conflicts_with(transaction).await.is_empty()
+    }
 
-        // Clean up empty expiry buckets
-        pending.retain(|_, txs| !txs.is_empty());
+    /// Remove transactions that have been spent (confirmed), returns the ids of the ones that were in the mempool. Their descendants stay, they now spend confirmed outputs
+    pub async fn spend_transactions(&self, transactions: Vec<TransactionId>) -> Vec<TransactionId> {
+        let mut pending = self.pending.write().await;
+        transactions
+            .into_iter()
+            .filter(|transaction_id| pending.remove(transaction_id).is_some())
+            .collect()
     }
 
     pub async fn mempool_size(&self) -> usize {
-        self.pending
-            .read()
-            .await
-            .values()
-            .fold(0, |acc, txs| acc + txs.len())
+        self.pending.read().await.by_id.len()
+    }
+
+    /// Size of all mempool transactions together, in bytes
+    pub async fn mempool_bytes(&self) -> usize {
+        self.pending.read().await.bytes
+    }
+
+    /// Get mempool statistics, `transaction_difficulty` is the current one of the chain
+    pub async fn info(&self, transaction_difficulty: [u8; 32]) -> MemPoolInfo {
+        let pending = self.pending.read().await;
+        let now = chrono::Utc::now().timestamp() as u64;
+        let entered = |(added, ids): (&u64, &Vec<TransactionId>), first: bool| {
+            let id = if first { ids.first() } else { ids.last() };
+            id.map(|id| (*id, *added))
+        };
+
+        let buckets = (EXPIRATION_TIME / TARGET_TIME) as usize;
+        let mut expiry_histogram = vec![0; buckets];
+        for (expiry, ids) in &pending.by_expiry {
+            // Expired ones the watchdog did not remove yet count as expiring soonest
+            let bucket = (expiry.saturating_sub(now) / TARGET_TIME) as usize;
+            expiry_histogram[bucket.min(buckets - 1)] += ids.len();
+        }
+
+        let transactions = pending.by_id.len();
+        MemPoolInfo {
+            transactions,
+            bytes: pending.bytes,
+            oldest: pending
+                .by_added
+                .first_key_value()
+                .and_then(|entry| entered(entry, true)),
+            newest: pending
+                .by_added
+                .last_key_value()
+                .and_then(|entry| entered(entry, false)),
+            expiry_histogram,
+            transaction_difficulty,
+            live_difficulty: calculate_live_transaction_difficulty(
+                &transaction_difficulty,
+                transactions,
+            ),
+            expected_wait: transactions / TX_TARGET + 1,
+        }
     }
 
     pub async fn clear(&self) {
-        self.pending.write().await.clear();
+        *self.pending.write().await = MemPoolIndex::default();
     }
 }
@@ -0,0 +1,87 @@
+use crate::{
+    blockchain_data_provider::{BlockchainDataProvider, BlockchainDataProviderError},
+    core::{
+        block::Block,
+        transaction::{TransactionId, TransactionOutput},
+    },
+    crypto::{Hash, keys::Public},
+    full_node::{SharedBlockchain, node_state::SharedNodeState},
+};
+
+/// Blockchain data of a full node, with its mempool applied on top. Outputs spent by mempool transactions are not available, outputs they create are.
+/// Pass it to `build_transaction` to send again before earlier transactions are confirmed
+pub struct MemPoolView {
+    pub blockchain: SharedBlockchain,
+    pub node_state: SharedNodeState,
+}
+
+impl MemPoolView {
+    pub fn new(blockchain: SharedBlockchain, node_state: SharedNodeState) -> Self {
+        Self {
+            blockchain,
+            node_state,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BlockchainDataProvider for MemPoolView {
+    async fn get_height(&self) -> Result<usize, BlockchainDataProviderError> {
+        BlockchainDataProvider::get_height(&*self.blockchain).await
+    }
+
+    async fn get_reward(&self) -> Result<u64, BlockchainDataProviderError> {
+        self.blockchain.get_reward().await
+    }
+
+    async fn get_block_by_height(
+        &self,
+        height: usize,
+    ) -> Result<Option<Block>, BlockchainDataProviderError> {
+        BlockchainDataProvider::get_block_by_height(&*self.blockchain, height).await
+    }
+
+    async fn get_block_by_hash(
+        &self,
+        hash: Hash,
+    ) -> Result<Option<Block>, BlockchainDataProviderError> {
+        BlockchainDataProvider::get_block_by_hash(&*self.blockchain, hash).await
+    }
+
+    async fn get_height_by_hash(
+        &self,
+        hash: Hash,
+    ) -> Result<Option<usize>, BlockchainDataProviderError> {
+        self.blockchain.get_height_by_hash(hash).await
+    }
+
+    async fn get_block_hash_by_height(
+        &self,
+        height: usize,
+    ) -> Result<Option<Hash>, BlockchainDataProviderError> {
+        BlockchainDataProvider::get_block_hash_by_height(&*self.blockchain, height).await
+    }
+
+    async fn get_transaction_difficulty(&self) -> Result<[u8; 32], BlockchainDataProviderError> {
+        Ok(self.blockchain.get_transaction_difficulty())
+    }
+
+    async fn get_block_difficulty(&self) -> Result<[u8; 32], BlockchainDataProviderError> {
+        Ok(self.blockchain.get_block_difficulty())
+    }
+
+    async fn get_available_transaction_outputs(
+        &self,
+        address: Public,
+    ) -> Result<Vec<(TransactionId, TransactionOutput, usize)>, BlockchainDataProviderError> {
+        let confirmed = self
+            .blockchain
+            .get_available_transaction_outputs(address)
+            .await?;
+        Ok(self
+            .node_state
+            .mempool
+            .available_outputs(address, confirmed)
+            .await)
+    }
+}
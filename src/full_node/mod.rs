@@ -4,9 +4,27 @@ pub mod p2p_server;
 /// Handles public node discovery, and connection. A daemon
 pub mod auto_peer;
 
+/// Remembers peer addresses over restarts, and picks which ones to connect to
+pub mod address_book;
+
+/// Misbehavior scores and bans, per ip
+pub mod ban_list;
+
+/// Seed lists and persistent peers, used by auto peer
+pub mod bootstrap;
+
 /// Stores all currently pending transactions, that are waiting to be mined
 pub mod mempool;
 
+/// Blockchain data with the mempool applied on top, to spend unconfirmed outputs
+pub mod mempool_view;
+
+/// Block templates for miners, with transactions picked from the mempool
+pub mod block_template;
+
+/// Trickled transaction announcements and Dandelion++ stem routing
+pub mod tx_relay;
+
 /// Stores current node state, shared between threads
 pub mod node_state;
 
@@ -16,99 +34,239 @@ pub mod ibd;
 /// Handles full node on message logic
 mod behavior;
 
+/// Several full nodes in one process, for tests
+pub mod localnet;
+
 /// Enforces longest chain rule, syncs to a peer that has a higher height
 mod sync;
 
-use flexi_logger::{Duplicate, FileSpec, Logger};
+use flexi_logger::LoggerHandle;
 use futures::future::join_all;
-use log::{error, info};
+use log::{error, info, warn};
 use num_bigint::BigUint;
-use std::{
-    net::SocketAddr,
-    path::PathBuf,
-    sync::{Arc, Once},
-};
-use tokio::net::TcpStream;
+use std::{collections::HashSet, net::SocketAddr, path::PathBuf, sync::Arc};
 
 use crate::{
+    api::api_server::Server,
+    config::{NodeConfig, StartError},
     core::{
         block::Block,
         blockchain::{self, Blockchain, BlockchainError},
-        transaction::{Transaction, TransactionError},
+        transaction::Transaction,
     },
+    crypto::randomx_set_mode,
+    economics::EXPIRATION_TIME,
     full_node::{
+        auto_peer::start_auto_peer_with_config,
         behavior::FullNodePeerBehavior,
+        mempool::{MemPoolEvent, RemovalReason},
         node_state::{NodeState, SharedNodeState},
+        p2p_server::start_p2p_server,
+        tx_relay::start_tx_relay,
     },
+    logging::init_logging_unless_set,
     node::{
+        connector::Endpoint,
         message::{Command, Message},
-        peer::{PeerError, PeerHandle, create_peer},
+        peer::{PeerError, PeerHandle, create_peer_with_endpoint},
+        shutdown::disconnect_peers,
     },
 };
 
 pub type SharedBlockchain = Arc<Blockchain>;
 
-static LOGGER_INIT: Once = Once::new();
-
-/// Creates a full node (SharedBlockchain and SharedNodeState), connecting to peers, accepting blocks and transactions
-pub fn create_full_node(
-    node_path: &str,
-    disable_stdout: bool,
-) -> (SharedBlockchain, SharedNodeState) {
+/// Creates a full node (SharedBlockchain and SharedNodeState), connecting to peers, accepting blocks and transactions.
+/// Any amount of nodes can run in one process, as long as they have different paths. Nodes don't set up logging, see `logging::init_logging`
+pub fn create_full_node(node_path: &str) -> (SharedBlockchain, SharedNodeState) {
     let node_path = PathBuf::from(node_path);
 
-    LOGGER_INIT.call_once(|| {
-        let log_path = node_path.join("logs");
-        std::fs::create_dir_all(&log_path).expect("Failed to create log directory");
-
-        let mut logger = Logger::try_with_str("debug") // TODO: configure to info when in prod.
-            .unwrap()
-            .log_to_file(FileSpec::default().directory(&log_path));
-
-        if !disable_stdout {
-            logger = logger.duplicate_to_stderr(Duplicate::Debug); // TODO: configure to info when in prod.
-        }
-
-        logger.start().ok(); // Ignore errors if logger is already set
-
-        info!("Logger initialized for node at {:?}", node_path);
-    });
+    let blockchain = Blockchain::new(
+        node_path
+            .join("blockchain")
+            .to_str()
+            .expect("Failed to create node path"),
+    );
 
-    let node_state = NodeState::new_empty();
+    let node_state = NodeState::new(&node_path, &blockchain);
     let node_state_expiry = node_state.clone();
     node_state
         .mempool
-        .start_expiry_watchdog(move |transaction| {
+        .start_expiry_watchdog(&node_state.shutdown, move |transaction| {
             let _ = node_state_expiry
                 .chain_events
                 .send(node_state::ChainEvent::TransactionExpiration { transaction });
+            node_state_expiry.send_removed(&[transaction], RemovalReason::Expired);
         });
+    node_state.mempool.start_save_daemon(&node_state.shutdown);
 
-    let blockchain = Blockchain::new(
-        node_path
-            .join("blockchain")
+    let blockchain = Arc::new(blockchain);
+    start_tx_relay(blockchain.clone(), node_state.clone());
+
+    (blockchain, node_state)
+}
+
+/// Start a full node from a config in one call. Sets up logging and the RandomX mode if the config has them, starts the P2P server, API server and auto peer if they are configured, and connects to the configured peers.
+/// Peers that can't be connected to are only logged
+pub async fn start_full_node(config: &NodeConfig) -> Result<NodeHandle, StartError> {
+    let logger = match &config.log {
+        Some(log) => init_logging_unless_set(log)?,
+        None => None,
+    };
+    if let Some(mode) = config.randomx_mode {
+        randomx_set_mode(mode);
+    }
+
+    let (blockchain, node_state) = create_full_node(
+        config
+            .node_path
             .to_str()
-            .expect("Failed to create node path"),
+            .expect("Node path is not valid UTF-8"),
     );
+    node_state.apply_config(config).await;
+    let handle = NodeHandle {
+        blockchain,
+        node_state,
+        logger,
+    };
+
+    if let Err(e) = start_services(config, &handle).await {
+        let _ = handle.shutdown().await;
+        return Err(e);
+    }
+    Ok(handle)
+}
+
+async fn start_services(config: &NodeConfig, handle: &NodeHandle) -> Result<(), StartError> {
+    let NodeHandle {
+        blockchain,
+        node_state,
+        ..
+    } = handle;
+
+    if let Some(port) = config.p2p_port {
+        start_p2p_server(port, blockchain.clone(), node_state.clone()).await?;
+    }
+    if let Some(api_address) = config.api_address {
+        Server::new_with_address(api_address, blockchain.clone(), node_state.clone())
+            .listen()
+            .await?;
+    }
+    for endpoint in &config.peers {
+        if let Err(e) = connect_peer(endpoint.clone(), blockchain, node_state).await {
+            warn!("Failed to connect to peer {endpoint}, error: {e}");
+        }
+    }
+    if let Some(auto_peer) = &config.auto_peer {
+        start_auto_peer_with_config(node_state.clone(), blockchain.clone(), auto_peer.clone());
+    }
+    Ok(())
+}
+
+/// Handle to a running full node, used to shut it down
+#[derive(Clone)]
+pub struct NodeHandle {
+    pub blockchain: SharedBlockchain,
+    pub node_state: SharedNodeState,
+    /// Logger set up by `start_full_node`, kept so file logs stay open while the node runs
+    logger: Option<LoggerHandle>,
+}
+
+impl NodeHandle {
+    pub fn new(blockchain: SharedBlockchain, node_state: SharedNodeState) -> Self {
+        Self {
+            blockchain,
+            node_state,
+            logger: None,
+        }
+    }
+
+    /// Stop the node. Daemons (p2p server, auto peer, mempool watchdog and saver, tx relay and API server) are cancelled, peers are disconnected, and everything is flushed to disk.
+    /// Returns once the node is quiet, nothing touches the blockchain after this
+    pub async fn shutdown(&self) -> Result<(), BlockchainError> {
+        let node_state = &self.node_state;
+        info!("Shutting down node");
+
+        node_state.shutdown.trigger();
+        if !node_state.shutdown.wait_for_daemons().await {
+            warn!("Some daemons did not stop in time");
+        }
+
+        // Also fails running syncs, which hold the processing lock
+        disconnect_peers(&node_state.connected_peers).await;
 
-    (Arc::new(blockchain), node_state)
+        // Wait for blocks that are being added, and keep anything else from adding them
+        let _lock = node_state.processing.lock().await;
+
+        if let Err(e) = node_state.address_book.save() {
+            error!("Failed to save address book, error: {e}");
+        }
+        if let Err(e) = node_state.ban_list.save() {
+            error!("Failed to save ban list, error: {e}");
+        }
+        if let Err(e) = node_state.mempool.save().await {
+            error!("Failed to save mempool, error: {e}");
+        }
+        self.blockchain.flush()?;
+
+        info!("Node shut down");
+        if let Some(logger) = &self.logger {
+            logger.flush();
+        }
+        Ok(())
+    }
 }
 
-/// Connect to a peer
+/// Connect to a peer, an ip address or a host name (see `Endpoint`), using the connector of the node
 pub async fn connect_peer(
-    address: SocketAddr,
+    endpoint: Endpoint,
     blockchain: &SharedBlockchain,
     node_state: &SharedNodeState,
 ) -> Result<PeerHandle, PeerError> {
-    let stream = TcpStream::connect(address)
-        .await
-        .map_err(|e| PeerError::Io(format!("IO error: {e}")))?;
+    if node_state.shutdown.is_shutting_down() {
+        return Err(PeerError::ShuttingDown);
+    }
+    let address = endpoint.socket_addr();
+    if node_state.ban_list.is_banned(address.ip()) {
+        return Err(PeerError::Banned);
+    }
+    // Persistent peers don't count against the limit, so they can always come back
+    let max_outbound = node_state.connection_limits.read().await.max_outbound;
+    if node_state.peers_by_direction().await.1.len() >= max_outbound
+        && !node_state.persistent_peers.read().await.contains(&endpoint)
+    {
+        return Err(PeerError::ConnectionLimit(format!(
+            "Already connected to {max_outbound} outbound peers"
+        )));
+    }
 
-    let handle = create_peer(
+    let connector = node_state.connector.read().await.clone();
+    let stream = match connector.connect(&endpoint).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            node_state.address_book.mark_failure(&endpoint);
+            return Err(PeerError::Io(format!("Connector error: {e}")));
+        }
+    };
+
+    let handle = create_peer_with_endpoint(
         stream,
+        endpoint,
         FullNodePeerBehavior::new(blockchain.clone(), node_state.clone()),
         false,
+        *node_state.peer_config.read().await,
     )?;
+    if let Err(e) = handle
+        .handshake(
+            *node_state.advertised_address.read().await,
+            blockchain.block_store().first_block(),
+        )
+        .await
+    {
+        node_state.address_book.mark_failure(&handle.endpoint);
+        let _ = handle.kill(e.to_string()).await;
+        return Err(e);
+    }
+    node_state.address_book.mark_success(&handle.endpoint);
     node_state
         .connected_peers
         .write()
@@ -118,7 +276,7 @@ pub async fn connect_peer(
     Ok(handle)
 }
 
-/// Forward a message to all peers
+/// Forward a notification to all peers
 pub async fn to_peers(message: Message, node_state: &SharedNodeState) {
     let peers_snapshot: Vec<_> = node_state
         .connected_peers
@@ -132,10 +290,10 @@ pub async fn to_peers(message: Message, node_state: &SharedNodeState) {
     let futures = peers_snapshot.into_iter().map(|peer| {
         let message = message.clone();
         async move {
-            if let Err(err) = peer.request(message).await {
-                if let Err(e) = peer.kill(err.to_string()).await {
-                    error!("Failed to kill peer, error: {e}");
-                }
+            if let Err(err) = peer.send(message).await
+                && let Err(e) = peer.kill(err.to_string()).await
+            {
+                error!("Failed to kill peer, error: {e}");
             }
         }
     });
@@ -144,6 +302,15 @@ pub async fn to_peers(message: Message, node_state: &SharedNodeState) {
     join_all(futures).await;
 }
 
+/// Delete old blocks if the node is pruned, called whenever blocks were added (a new block, a sync or the initial block download)
+pub async fn prune_blocks(blockchain: &SharedBlockchain, node_state: &SharedNodeState) {
+    if let Some(prune_depth) = *node_state.prune_depth.read().await
+        && let Err(e) = blockchain.prune(prune_depth)
+    {
+        error!("Failed to prune blocks, error: {e}");
+    }
+}
+
 /// Accept a new block to the local blockchain, and forward it to all peers
 pub async fn accept_block(
     blockchain: &SharedBlockchain,
@@ -161,12 +328,16 @@ pub async fn accept_block(
     // Wait for any running add block tasks to finish, hold a lock to prevent stacking
     let _lock = node_state.processing.lock().await;
 
-    // Validation
+    // Validation. Blocks that don't extend our tip (siblings, forks, blocks ahead of us) fail the difficulty checks without being invalid, syncing handles them
+    if new_block.meta.previous_block != blockchain.block_store().get_last_block_hash() {
+        return Err(BlockchainError::InvalidPreviousBlockHash);
+    }
     blockchain::validate_block_timestamp(&new_block)?;
     blockchain.add_block(new_block.clone(), false)?;
+    prune_blocks(blockchain, node_state).await;
 
-    // Mempool, spend transactions
-    node_state
+    // Mempool, spend transactions, and drop the ones that now double spend (with their descendants)
+    let mined = node_state
         .mempool
         .spend_transactions(
             new_block
@@ -176,6 +347,12 @@ pub async fn accept_block(
                 .collect(),
         )
         .await;
+    node_state.send_removed(&mined, RemovalReason::Mined);
+    let conflicting = node_state
+        .mempool
+        .remove_conflicting(&new_block.transactions)
+        .await;
+    node_state.send_removed(&conflicting, RemovalReason::Conflict);
 
     info!("New block accepted: {}", block_hash.dump_base36());
 
@@ -197,7 +374,7 @@ pub async fn accept_block(
     Ok(())
 }
 
-/// Accept a new block to the local blockchain, and forward it to all peers
+/// Accept a new transaction to the mempool, and announce it to all peers
 pub async fn accept_transaction(
     blockchain: &SharedBlockchain,
     node_state: &SharedNodeState,
@@ -214,6 +391,207 @@ pub async fn accept_transaction(
     }
     node_state.add_last_seen_transaction(transaction_id);
 
+    validate_new_transaction(blockchain, node_state, &new_transaction).await?;
+    let expiry = chrono::Utc::now().timestamp() as u64 + EXPIRATION_TIME;
+    admit_transaction(node_state, new_transaction.clone(), expiry).await?;
+    node_state.send_mempool_event(MemPoolEvent::Added {
+        transaction: transaction_id,
+    });
+
+    // Broadcast new transaction
+    let _ = node_state
+        .chain_events
+        .send(node_state::ChainEvent::Transaction {
+            transaction: new_transaction,
+        });
+
+    // Announce to all peers, trickled by the relay daemon
+    let peers: Vec<SocketAddr> = node_state
+        .connected_peers
+        .read()
+        .await
+        .keys()
+        .copied()
+        .collect();
+    node_state.tx_relay.announce(transaction_id, peers);
+    Ok(())
+}
+
+/// Accept a transaction in the Dandelion++ stem phase. It is passed on to a single stem peer (not added to the mempool), or fluffed with `accept_transaction`.
+/// Use this with `is_origin` for transactions that should not be traced back to this node, the originating node always stems. Relays fluff with `fluff_probability`
+pub async fn accept_stem_transaction(
+    blockchain: &SharedBlockchain,
+    node_state: &SharedNodeState,
+    new_transaction: Transaction,
+    is_origin: bool,
+) -> Result<(), BlockchainError> {
+    new_transaction.check_completeness()?;
+    let transaction_id = new_transaction.transaction_id.unwrap(); // Unwrap is okay, we checked that tx is complete
+
+    if node_state
+        .last_seen_transactions()
+        .contains(&transaction_id)
+        || node_state.tx_relay.is_embargoed(&transaction_id)
+    {
+        return Ok(()); // Already fluffed, or looped back to us
+    }
+
+    let fluff_probability = node_state.tx_relay.config.read().unwrap().fluff_probability;
+    let candidates: Vec<SocketAddr> = node_state
+        .connected_peers
+        .read()
+        .await
+        .values()
+        .filter(|peer| !peer.is_client) // Light nodes and other clients don't relay
+        .map(|peer| peer.address)
+        .collect();
+    let stem_peer = node_state.tx_relay.stem_peer(&candidates);
+
+    // Fluffing at the origin would broadcast the transaction from the node that made it
+    let Some(stem_peer) =
+        stem_peer.filter(|_| is_origin || rand::random::<f64>() >= fluff_probability)
+    else {
+        return accept_transaction(blockchain, node_state, new_transaction).await;
+    };
+
+    validate_new_transaction(blockchain, node_state, &new_transaction).await?;
+    node_state.tx_relay.embargo(new_transaction.clone());
+
+    let peer = node_state
+        .connected_peers
+        .read()
+        .await
+        .get(&stem_peer)
+        .cloned();
+    if let Some(peer) = peer
+        && let Err(e) = peer
+            .send(Message::new(Command::StemTransaction {
+                transaction: new_transaction,
+            }))
+            .await
+    {
+        // The embargo will fluff it
+        error!("Failed to relay stem transaction to {stem_peer}: {e}");
+    }
+    Ok(())
+}
+
+/// Put transactions of disconnected blocks back into the mempool after a reorg, in chain order (parents first). They expire when they would have if they had never been mined.
+/// Mempool transactions that are no longer valid on the new chain (double spent, expired...) are dropped with their descendants, the rest keep their expiry.
+/// Everything dropped is announced as removed, with `RemovalReason::Reorg`
+pub async fn readmit_transactions(
+    blockchain: &SharedBlockchain,
+    node_state: &SharedNodeState,
+    transactions: Vec<Transaction>,
+) {
+    let mut readmitted = HashSet::new();
+    for transaction in transactions {
+        let Some(transaction_id) = transaction.transaction_id else {
+            continue;
+        };
+        // Reward transactions only belong in their block
+        if transaction.inputs.is_empty() || node_state.mempool.contains(&transaction_id).await {
+            continue;
+        }
+        let expiry = transaction.timestamp + EXPIRATION_TIME;
+        let valid = validate_mempool_transaction(blockchain, node_state, &transaction).await;
+        let result = match valid {
+            Ok(()) => admit_transaction(node_state, transaction, expiry).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => {
+                readmitted.insert(transaction_id);
+                node_state.send_mempool_event(MemPoolEvent::Added {
+                    transaction: transaction_id,
+                });
+            }
+            Err(e) => {
+                info!(
+                    "Dropped transaction {} after reorg: {e}",
+                    transaction_id.dump_base36()
+                );
+                node_state.send_removed(&[transaction_id], RemovalReason::Reorg);
+            }
+        }
+    }
+
+    // Re-check what was in the mempool before against the new chain, parents before their children
+    let difficulty = BigUint::from_bytes_be(&blockchain.get_transaction_difficulty());
+    for transaction in node_state.mempool.get_mempool().await {
+        let Some(transaction_id) = transaction.transaction_id else {
+            continue;
+        };
+        if readmitted.contains(&transaction_id)
+            || !node_state.mempool.contains(&transaction_id).await
+        {
+            continue; // Just validated, or dropped with its parent
+        }
+        let parent_outputs = node_state.mempool.parent_outputs(&transaction).await;
+        let valid = blockchain::validate_transaction_timestamp(&transaction).and_then(|()| {
+            Ok(blockchain.get_utxos().validate_transaction_with_pending(
+                &transaction,
+                &difficulty,
+                false,
+                &parent_outputs,
+            )?)
+        });
+        if let Err(e) = valid {
+            info!(
+                "Dropped transaction {} after reorg: {e}",
+                transaction_id.dump_base36()
+            );
+            let removed = node_state.mempool.remove_transaction(&transaction_id).await;
+            node_state.send_removed(&removed, RemovalReason::Reorg);
+        }
+    }
+}
+
+/// Add a validated transaction to the mempool expiring at `expiry`, in place of the transactions it replaces (their descendants are dropped with them).
+/// Replacements and evictions are announced once it is in
+async fn admit_transaction(
+    node_state: &SharedNodeState,
+    transaction: Transaction,
+    expiry: u64,
+) -> Result<(), BlockchainError> {
+    let transaction_id = transaction.transaction_id.unwrap(); // Unwrap is okay, callers check that tx is complete
+    let admission = node_state
+        .mempool
+        .try_replace_transaction(transaction, expiry)
+        .await?;
+
+    for replaced_id in admission.replaced {
+        info!(
+            "Transaction {} replaced by {}",
+            replaced_id.dump_base36(),
+            transaction_id.dump_base36()
+        );
+        let _ = node_state
+            .chain_events
+            .send(node_state::ChainEvent::TransactionReplaced {
+                transaction: replaced_id,
+                replaced_by: transaction_id,
+            });
+    }
+    node_state.send_removed(&admission.conflicts, RemovalReason::Conflict);
+    if !admission.evicted.is_empty() {
+        info!(
+            "Mempool full, evicted {} transactions",
+            admission.evicted.len()
+        );
+    }
+    node_state.send_removed(&admission.evicted, RemovalReason::Evicted);
+    Ok(())
+}
+
+/// Check a new transaction against the live difficulty, the UTXO set and the mempool
+async fn validate_new_transaction(
+    blockchain: &SharedBlockchain,
+    node_state: &SharedNodeState,
+    new_transaction: &Transaction,
+) -> Result<(), BlockchainError> {
+    let transaction_id = new_transaction.transaction_id.unwrap(); // Unwrap is okay, callers check that tx is complete
+
     if BigUint::from_bytes_be(
         &node_state
             .get_live_transaction_difficulty(blockchain.get_transaction_difficulty())
@@ -223,44 +601,33 @@ pub async fn accept_transaction(
         return Err(BlockchainError::LiveTransactionDifficulty);
     }
 
-    // Validation
-    blockchain::validate_transaction_timestamp(&new_transaction)?;
-    blockchain.get_utxos().validate_transaction(
-        &new_transaction,
+    validate_mempool_transaction(blockchain, node_state, new_transaction).await
+}
+
+/// Check a transaction against the UTXO set and the mempool, without the live difficulty. It may spend outputs of mempool transactions
+async fn validate_mempool_transaction(
+    blockchain: &SharedBlockchain,
+    node_state: &SharedNodeState,
+    new_transaction: &Transaction,
+) -> Result<(), BlockchainError> {
+    blockchain::validate_transaction_timestamp(new_transaction)?;
+    let parent_outputs = node_state.mempool.parent_outputs(new_transaction).await;
+    if !parent_outputs.is_empty() {
+        let max_chain_depth = node_state.mempool.config.read().unwrap().max_chain_depth;
+        if node_state.mempool.chain_depth(new_transaction).await > max_chain_depth {
+            return Err(BlockchainError::ChainTooLong);
+        }
+    }
+    blockchain.get_utxos().validate_transaction_with_pending(
+        new_transaction,
         &BigUint::from_bytes_be(&blockchain.get_transaction_difficulty()),
         false,
+        &parent_outputs,
     )?;
-    if !node_state
-        .mempool
-        .validate_transaction(&new_transaction)
-        .await
-    {
-        return Err(TransactionError::DoubleSpend(transaction_id.dump_base36()).into());
-    }
-
+    // Conflicts with the mempool are only okay for replacements, an honest peer might have relayed the other transaction first
     node_state
         .mempool
-        .add_transaction(new_transaction.clone())
-        .await;
-
-    // Broadcast new transaction
-    let _ = node_state
-        .chain_events
-        .send(node_state::ChainEvent::Transaction {
-            transaction: new_transaction.clone(),
-        });
-
-    let node_state = node_state.clone();
-
-    // Forward to all peers (non blocking)
-    tokio::spawn(async move {
-        to_peers(
-            Message::new(Command::NewTransaction {
-                transaction: new_transaction,
-            }),
-            &node_state,
-        )
-        .await;
-    });
+        .check_replacement(new_transaction)
+        .await?;
     Ok(())
 }
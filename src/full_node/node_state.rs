@@ -1,8 +1,9 @@
+use log::warn;
 use serde::{Deserialize, Serialize};
 use std::{
     collections::{HashMap, HashSet, VecDeque},
     net::{IpAddr, SocketAddr},
-    ops::Add,
+    path::Path,
     sync::Arc,
 };
 use tokio::sync::{
@@ -11,17 +12,29 @@ use tokio::sync::{
 };
 
 use crate::{
+    config::NodeConfig,
     core::{
         block::Block,
+        blockchain::Blockchain,
         difficulty::calculate_live_transaction_difficulty,
         transaction::{Transaction, TransactionId},
     },
     crypto::Hash,
     full_node::{
-        mempool::MemPool,
-        p2p_server::{BAN_SCORE_THRESHOLD, ClientHealthScores, PUNISHMENT},
+        address_book::{AddressBook, is_dialable},
+        ban_list::{BanEntry, BanList},
+        ibd::IbdStatus,
+        mempool::{MemPool, MemPoolEvent, RemovalReason},
+        p2p_server::ConnectionLimits,
+        tx_relay::TxRelay,
+    },
+    node::{
+        connector::{DirectConnector, Endpoint, SharedConnector, Socks5Connector},
+        message::{MAX_SEND_PEERS, PeerAddress},
+        misbehavior::Misbehavior,
+        peer::{PeerConfig, PeerHandle, PeerInfo},
+        shutdown::Shutdown,
     },
-    node::peer::PeerHandle,
 };
 
 pub type SharedNodeState = Arc<NodeState>;
@@ -32,7 +45,23 @@ pub struct NodeState {
     pub is_syncing: RwLock<bool>,
     pub chain_events: broadcast::Sender<ChainEvent>,
     pub processing: Mutex<()>,
-    pub client_health_scores: ClientHealthScores,
+    pub ban_list: BanList,
+    pub ibd_status: RwLock<IbdStatus>,
+    pub address_book: AddressBook,
+    /// Peers that the auto peer daemon always reconnects to
+    pub persistent_peers: RwLock<HashSet<Endpoint>>,
+    /// Address we tell peers to connect to us on. Set by `start_p2p_server`, with an unspecified ip unless set before
+    pub advertised_address: RwLock<Option<SocketAddr>>,
+    pub connection_limits: RwLock<ConnectionLimits>,
+    /// Request settings for new peer connections
+    pub peer_config: RwLock<PeerConfig>,
+    pub tx_relay: TxRelay,
+    /// Opens outbound peer connections, set a `Socks5Connector` to connect through Tor or another proxy
+    pub connector: RwLock<SharedConnector>,
+    /// Keep only this many blocks on disk (at least `MIN_PRUNE_DEPTH`), `None` keeps every block
+    pub prune_depth: RwLock<Option<usize>>,
+    /// Daemons of this node are spawned through this, so `NodeHandle::shutdown` can stop them
+    pub shutdown: Shutdown,
     last_seen_block_reader: watch::Receiver<Hash>,
     last_seen_block_writer: watch::Sender<Hash>,
     last_seen_transactions_reader: watch::Receiver<VecDeque<TransactionId>>,
@@ -40,7 +69,25 @@ pub struct NodeState {
 }
 
 impl NodeState {
+    /// Create a node state with an address book, ban list and mempool that only live in memory
     pub fn new_empty() -> SharedNodeState {
+        Self::new_with(
+            AddressBook::new_empty(),
+            BanList::new_empty(),
+            MemPool::new(),
+        )
+    }
+
+    /// Create a node state, loading the address book, ban list and mempool from `node_path`. Saved mempool transactions are re-validated against `blockchain`
+    pub fn new(node_path: &Path, blockchain: &Blockchain) -> SharedNodeState {
+        Self::new_with(
+            AddressBook::load(node_path.join("peers.dat")),
+            BanList::load(node_path.join("bans.dat")),
+            MemPool::load(node_path.join("mempool.dat"), blockchain),
+        )
+    }
+
+    fn new_with(address_book: AddressBook, ban_list: BanList, mempool: MemPool) -> SharedNodeState {
         let (last_seen_block_writer, last_seen_block_reader) =
             watch::channel(Hash::new_from_buf([0u8; 32]));
         let (last_seen_transactions_writer, last_seen_transactions_reader) =
@@ -48,21 +95,47 @@ impl NodeState {
 
         Arc::new(NodeState {
             connected_peers: RwLock::new(HashMap::new()),
-            mempool: MemPool::new(),
+            mempool,
             is_syncing: RwLock::new(false),
-            chain_events: broadcast::channel(64).0,
+            chain_events: broadcast::channel(1024).0,
             processing: Mutex::new(()),
             last_seen_block_reader,
             last_seen_block_writer,
             last_seen_transactions_reader,
             last_seen_transactions_writer,
-            client_health_scores: ClientHealthScores::new(HashMap::new()),
+            ban_list,
+            ibd_status: RwLock::new(IbdStatus::default()),
+            address_book,
+            persistent_peers: RwLock::new(HashSet::new()),
+            advertised_address: RwLock::new(None),
+            connection_limits: RwLock::new(ConnectionLimits::default()),
+            peer_config: RwLock::new(PeerConfig::default()),
+            tx_relay: TxRelay::new(),
+            connector: RwLock::new(DirectConnector::new()),
+            prune_depth: RwLock::new(None),
+            shutdown: Shutdown::new(),
         })
     }
 
+    /// Apply the node settings of a config (limits, peer, ban, mempool and relay settings, pruning, advertised address and proxy)
+    pub async fn apply_config(&self, config: &NodeConfig) {
+        *self.connection_limits.write().await = config.connection_limits.clone();
+        *self.peer_config.write().await = config.peer;
+        *self.tx_relay.config.write().unwrap() = config.tx_relay;
+        *self.ban_list.config.write().unwrap() = config.ban;
+        *self.mempool.config.write().unwrap() = config.mempool;
+        *self.prune_depth.write().await = config.prune_depth;
+        if let Some(advertised_address) = config.advertised_address {
+            *self.advertised_address.write().await = Some(advertised_address);
+        }
+        if let Some(proxy) = config.proxy {
+            *self.connector.write().await = Socks5Connector::new(proxy);
+        }
+    }
+
     /// Get the latest seen block
     pub fn last_seen_block(&self) -> Hash {
-        self.last_seen_block_reader.borrow().clone()
+        *self.last_seen_block_reader.borrow()
     }
 
     /// Set a new last seen block
@@ -93,6 +166,21 @@ impl NodeState {
         }
     }
 
+    /// Send a mempool event to chain event subscribers
+    pub fn send_mempool_event(&self, event: MemPoolEvent) {
+        let _ = self.chain_events.send(ChainEvent::MemPool { event });
+    }
+
+    /// Send a `MemPoolEvent::Removed` for each of `transactions`
+    pub fn send_removed(&self, transactions: &[TransactionId], reason: RemovalReason) {
+        for transaction in transactions {
+            self.send_mempool_event(MemPoolEvent::Removed {
+                transaction: *transaction,
+                reason,
+            });
+        }
+    }
+
     pub async fn get_live_transaction_difficulty(
         &self,
         transaction_difficulty: [u8; 32],
@@ -103,49 +191,112 @@ impl NodeState {
         )
     }
 
-    /// Punish a IP address
-    pub async fn punish_ip(&self, ip: IpAddr) {
-        *self
-            .client_health_scores
-            .write()
-            .await
-            .entry(ip)
-            .or_insert(0) += PUNISHMENT;
-    }
-
-    /// "Forgive" everyone by 1 pt
-    pub async fn decrement_punishments(&self) {
-        let mut scores = self.client_health_scores.write().await;
-
-        let mut to_remove = Vec::new();
-
-        for (ip, score) in scores.iter_mut() {
-            *score = score.saturating_sub(PUNISHMENT);
-            if *score == 0 {
-                to_remove.push(ip.clone());
-            }
+    /// Punish a peer for misbehaving, disconnecting it if that got its ip banned
+    pub async fn punish(&self, peer: &PeerHandle, misbehavior: Misbehavior) {
+        warn!("Peer {} misbehaved: {misbehavior:?}", peer.address);
+        if self.ban_list.punish(peer.address.ip(), misbehavior) {
+            let _ = peer.kill(format!("Banned for {misbehavior:?}")).await;
         }
+    }
 
-        for ip in to_remove {
-            scores.remove(&ip);
+    /// Ban an ip for `duration` seconds (forever if `None`), disconnecting all its peers
+    pub async fn ban_ip(&self, ip: IpAddr, duration: Option<u64>, reason: String) -> BanEntry {
+        let ban = self.ban_list.ban(ip, duration, reason);
+        let peers: Vec<PeerHandle> = self
+            .connected_peers
+            .read()
+            .await
+            .values()
+            .filter(|peer| peer.address.ip() == ip)
+            .cloned()
+            .collect();
+        for peer in peers {
+            let _ = peer.kill("Banned".to_string()).await;
         }
+        ban
     }
 
-    /// Get a list of banned ips
-    pub async fn get_banned_ips(&self) -> HashSet<IpAddr> {
-        self.client_health_scores
+    /// Get stats of all connected peers
+    pub async fn peer_info(&self) -> Vec<PeerInfo> {
+        self.connected_peers
             .read()
             .await
-            .iter()
-            .filter(|(_ip, score)| score > &&BAN_SCORE_THRESHOLD)
-            .map(|(ip, _score)| *ip)
+            .values()
+            .map(|peer| peer.info(self.ban_list.score(peer.address.ip())))
             .collect()
     }
+
+    /// Get dialable addresses to share in `SendPeers`. Outbound peers first, then addresses we connected to before, most recently seen first.
+    /// Inbound peers can advertise any listen address, so theirs are only shared once we connected to it ourselves
+    pub async fn shareable_peers(&self) -> Vec<PeerAddress> {
+        let now = chrono::Utc::now().timestamp() as u64;
+        let mut peers: Vec<PeerAddress> = self
+            .connected_peers
+            .read()
+            .await
+            .values()
+            .filter(|peer| !peer.is_client)
+            .filter_map(|peer| peer.listen_address())
+            .filter(is_dialable)
+            .map(|address| PeerAddress {
+                address,
+                last_seen: now,
+            })
+            .collect();
+
+        let mut known: Vec<PeerAddress> = self
+            .address_book
+            .entries()
+            .into_iter()
+            .filter(|entry| entry.last_success != 0 && !entry.is_terrible(now))
+            .filter_map(|entry| match entry.address {
+                // Host endpoints can't be sent, peers only take ip addresses
+                Endpoint::Ip(address) => Some(PeerAddress {
+                    address,
+                    last_seen: entry.last_seen,
+                }),
+                Endpoint::Host { .. } => None,
+            })
+            .filter(|known| {
+                is_dialable(&known.address)
+                    && !peers.iter().any(|peer| peer.address == known.address)
+            })
+            .collect();
+        known.sort_by_key(|known| std::cmp::Reverse(known.last_seen));
+        peers.extend(known);
+        peers.truncate(MAX_SEND_PEERS);
+        peers
+    }
+
+    /// Get the (inbound, outbound) addresses of all connected peers
+    pub async fn peers_by_direction(&self) -> (Vec<SocketAddr>, Vec<SocketAddr>) {
+        let peers = self.connected_peers.read().await;
+        let (inbound, outbound): (Vec<&PeerHandle>, Vec<&PeerHandle>) =
+            peers.values().partition(|peer| peer.is_client);
+        (
+            inbound.iter().map(|peer| peer.address).collect(),
+            outbound.iter().map(|peer| peer.address).collect(),
+        )
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub enum ChainEvent {
-    Block { block: Block },
-    Transaction { transaction: Transaction },
-    TransactionExpiration { transaction: TransactionId },
+    Block {
+        block: Block,
+    },
+    Transaction {
+        transaction: Transaction,
+    },
+    TransactionExpiration {
+        transaction: TransactionId,
+    },
+    TransactionReplaced {
+        transaction: TransactionId,
+        replaced_by: TransactionId,
+    },
+    /// Every transaction that enters or leaves the mempool, with the reason it left
+    MemPool {
+        event: MemPoolEvent,
+    },
 }
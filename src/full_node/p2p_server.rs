@@ -1,21 +1,46 @@
 use std::{
-    collections::HashMap,
+    cmp::Reverse,
+    collections::HashSet,
     net::{IpAddr, Ipv4Addr, SocketAddr},
-    time::Duration,
+    sync::atomic::Ordering,
 };
 
-use log::error;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use tokio::{io::AsyncWriteExt, net::TcpListener, sync::RwLock, task::JoinHandle, time::sleep};
+use tokio::{io::AsyncWriteExt, net::TcpListener, task::JoinHandle, time::sleep};
 
 use crate::{
-    full_node::{SharedBlockchain, behavior::FullNodePeerBehavior, node_state::SharedNodeState},
-    node::peer::create_peer,
+    full_node::{
+        SharedBlockchain, address_book::subnet_group, auto_peer::TARGET_PEERS,
+        behavior::FullNodePeerBehavior, node_state::SharedNodeState,
+    },
+    node::peer::{PeerHandle, create_peer_with_config},
 };
 
-pub const BAN_SCORE_THRESHOLD: u8 = 10;
-pub const PUNISHMENT: u8 = 2;
-pub type ClientHealthScores = RwLock<HashMap<IpAddr, u8>>;
+/// Limits on how many peers a node keeps connections with
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ConnectionLimits {
+    /// Max peers that connected to us
+    pub max_inbound: usize,
+    /// Max peers we connected to, persistent peers can always connect on top of this
+    pub max_outbound: usize,
+    /// Max inbound peers from one ip
+    pub max_inbound_per_ip: usize,
+    /// Max inbound peers from one subnet group (/16 for IPv4, /32 for IPv6)
+    pub max_inbound_per_subnet: usize,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            max_inbound: 64,
+            max_outbound: TARGET_PEERS,
+            max_inbound_per_ip: 8,
+            max_inbound_per_subnet: 16,
+        }
+    }
+}
 
 #[derive(Error, Debug)]
 pub enum P2PServerError {
@@ -31,30 +56,48 @@ pub async fn start_p2p_server(
     let listener =
         TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port)).await?;
 
+    node_state
+        .advertised_address
+        .write()
+        .await
+        .get_or_insert(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port));
+
     let node_state_ban = node_state.clone();
-    tokio::spawn(async move {
+    node_state.shutdown.spawn(async move {
         loop {
-            sleep(Duration::from_secs(60)).await;
-            node_state_ban.decrement_punishments().await;
+            let decay_interval = node_state_ban
+                .ban_list
+                .config
+                .read()
+                .unwrap()
+                .decay_interval;
+            sleep(decay_interval).await;
+            node_state_ban.ban_list.decay();
+            if let Err(e) = node_state_ban.ban_list.save() {
+                error!("Failed to save ban list, error: {e}");
+            }
         }
     });
 
-    Ok(tokio::spawn(async move {
+    let node_state_server = node_state.clone();
+    Ok(node_state_server.shutdown.spawn(async move {
         while let Ok((mut stream, address)) = listener.accept().await {
-            if node_state.get_banned_ips().await.contains(&address.ip()) {
-                match stream.shutdown().await {
-                    Ok(()) => {
-                        continue;
-                    }
-                    Err(e) => {
-                        error!("Failed to deny incoming connection: {} - {e}", address.ip());
-                    }
+            if node_state.ban_list.is_banned(address.ip()) {
+                if let Err(e) = stream.shutdown().await {
+                    error!("Failed to deny incoming connection: {} - {e}", address.ip());
                 }
+                continue;
             }
-            match create_peer(
+            if let Err(e) = make_inbound_slot(&node_state, address).await {
+                warn!("Denied incoming connection from {address}: {e}");
+                let _ = stream.shutdown().await;
+                continue;
+            }
+            match create_peer_with_config(
                 stream,
                 FullNodePeerBehavior::new(blockchain.clone(), node_state.clone()),
                 true,
+                *node_state.peer_config.read().await,
             ) {
                 Ok(handle) => {
                     node_state
@@ -70,3 +113,152 @@ pub async fn start_p2p_server(
         }
     }))
 }
+
+/// Inbound peers protected from eviction for each of: lowest ping, most blocks relayed, most transactions relayed, and distinct subnet groups
+pub const PROTECTED_PER_QUALITY: usize = 4;
+
+/// What inbound eviction looks at of a peer
+#[derive(Clone, Debug)]
+pub struct EvictionCandidate {
+    pub address: SocketAddr,
+    /// Round trip time of the last ping in milliseconds, `u64::MAX` until the first pong
+    pub ping_rtt_ms: u64,
+    pub blocks_relayed: u64,
+    pub transactions_relayed: u64,
+    pub connected_at: u64,
+}
+
+impl EvictionCandidate {
+    pub fn of(peer: &PeerHandle) -> Self {
+        Self {
+            address: peer.address,
+            ping_rtt_ms: peer.stats.ping_rtt_ms.load(Ordering::Relaxed),
+            blocks_relayed: peer.stats.blocks_relayed.load(Ordering::Relaxed),
+            transactions_relayed: peer.stats.transactions_relayed.load(Ordering::Relaxed),
+            connected_at: peer.stats.connected_at,
+        }
+    }
+}
+
+/// Pick the inbound peer to evict for a new connection, `None` if all of them are protected.
+/// Peers an attacker can't easily imitate are protected first: the fastest, the ones that relayed the most blocks or transactions, the oldest of a few subnet groups, then the longest connected half of the rest.
+/// The least useful of the others (least relayed data, then slowest, then youngest) is evicted
+pub fn select_eviction(candidates: &[EvictionCandidate]) -> Option<SocketAddr> {
+    let mut rest: Vec<&EvictionCandidate> = candidates.iter().collect();
+    protect(&mut rest, PROTECTED_PER_QUALITY, |peer| {
+        (peer.ping_rtt_ms != u64::MAX).then_some(peer.ping_rtt_ms)
+    });
+    protect(&mut rest, PROTECTED_PER_QUALITY, |peer| {
+        (peer.blocks_relayed > 0).then_some(Reverse(peer.blocks_relayed))
+    });
+    protect(&mut rest, PROTECTED_PER_QUALITY, |peer| {
+        (peer.transactions_relayed > 0).then_some(Reverse(peer.transactions_relayed))
+    });
+
+    // The oldest peer of each subnet group, so one network can't take every slot
+    rest.sort_by_key(|peer| peer.connected_at);
+    let mut groups = HashSet::new();
+    let mut diverse = HashSet::new();
+    for peer in &rest {
+        if groups.len() >= PROTECTED_PER_QUALITY {
+            break;
+        }
+        if groups.insert(subnet_group(peer.address.ip())) {
+            diverse.insert(peer.address);
+        }
+    }
+    rest.retain(|peer| !diverse.contains(&peer.address));
+
+    // Still sorted by age
+    let longest_connected = rest.len() / 2;
+    rest.drain(..longest_connected);
+
+    rest.into_iter()
+        .min_by_key(|peer| {
+            (
+                peer.blocks_relayed
+                    .saturating_mul(10)
+                    .saturating_add(peer.transactions_relayed),
+                Reverse(peer.ping_rtt_ms),
+                Reverse(peer.connected_at),
+            )
+        })
+        .map(|peer| peer.address)
+}
+
+/// Take the `count` best peers out of `rest`, lowest `rank` first. Peers ranked `None` don't qualify
+fn protect<K: Ord>(
+    rest: &mut Vec<&EvictionCandidate>,
+    count: usize,
+    rank: impl Fn(&EvictionCandidate) -> Option<K>,
+) {
+    let mut ranked: Vec<(K, SocketAddr)> = rest
+        .iter()
+        .filter_map(|peer| Some((rank(peer)?, peer.address)))
+        .collect();
+    ranked.sort_by(|a, b| a.0.cmp(&b.0));
+    let protected: HashSet<SocketAddr> = ranked
+        .into_iter()
+        .take(count)
+        .map(|(_, address)| address)
+        .collect();
+    rest.retain(|peer| !protected.contains(&peer.address));
+}
+
+/// Check the inbound limits for a new connection from `address`. When all inbound slots are taken, an unprotected inbound peer is evicted (see `select_eviction`)
+async fn make_inbound_slot(
+    node_state: &SharedNodeState,
+    address: SocketAddr,
+) -> Result<(), String> {
+    let limits = node_state.connection_limits.read().await.clone();
+    let inbound: Vec<PeerHandle> = node_state
+        .connected_peers
+        .read()
+        .await
+        .values()
+        .filter(|peer| peer.is_client)
+        .cloned()
+        .collect();
+
+    if inbound
+        .iter()
+        .filter(|peer| peer.address.ip() == address.ip())
+        .count()
+        >= limits.max_inbound_per_ip
+    {
+        return Err("too many connections from this ip".into());
+    }
+    let group = subnet_group(address.ip());
+    if inbound
+        .iter()
+        .filter(|peer| subnet_group(peer.address.ip()) == group)
+        .count()
+        >= limits.max_inbound_per_subnet
+    {
+        return Err("too many connections from this subnet".into());
+    }
+    if inbound.len() < limits.max_inbound {
+        return Ok(());
+    }
+
+    let candidates: Vec<EvictionCandidate> = inbound.iter().map(EvictionCandidate::of).collect();
+    let Some(evicted) = select_eviction(&candidates)
+        .and_then(|evicted| inbound.iter().find(|peer| peer.address == evicted))
+    else {
+        return Err("no inbound slots".into());
+    };
+
+    info!(
+        "Evicting inbound peer {} to make room for {address}",
+        evicted.address
+    );
+    node_state
+        .connected_peers
+        .write()
+        .await
+        .remove(&evicted.address);
+    let _ = evicted
+        .kill("Evicted to make room for a new peer".into())
+        .await;
+    Ok(())
+}
@@ -1,10 +1,20 @@
+use std::collections::HashSet;
+
 use log::info;
 
 use crate::{
-    core::blockchain::BlockchainError, full_node::SharedBlockchain, node::{
+    core::{
+        blockchain::BlockchainError,
+        transaction::{Transaction, TransactionId},
+    },
+    full_node::{
+        SharedBlockchain, mempool::RemovalReason, node_state::SharedNodeState, prune_blocks,
+        readmit_transactions,
+    },
+    node::{
         message::{Command, Message},
         peer::PeerHandle,
-    }
+    },
 };
 
 #[derive(thiserror::Error, Debug)]
@@ -19,10 +29,12 @@ pub enum SyncError {
     NoForkPoint,
 }
 
-/// Synchronize local blockchain to match peer's chain using longest chain rule with fork detection
+/// Synchronize local blockchain to match peer's chain using longest chain rule with fork detection.
+/// Afterwards the mempool is updated to the new chain, transactions of the rolled back blocks are put back in it if they are still valid
 pub async fn sync_to_peer(
     peer: &PeerHandle,
     blockchain: &SharedBlockchain,
+    node_state: &SharedNodeState,
     peer_height: usize,
 ) -> Result<(), SyncError> {
     let local_height = blockchain.block_store().get_height();
@@ -46,17 +58,16 @@ pub async fn sync_to_peer(
             let msg = Message::new(Command::GetBlock {
                 block_hash: local_hash,
             });
-            if let Ok(response) = peer.request(msg).await {
-                if let Command::GetBlockResponse {
+            if let Ok(response) = peer.request(msg).await
+                && let Command::GetBlockResponse {
                     block: Some(peer_block),
                 } = response.command
-                {
-                    // If hashes match, we found the fork point
-                    if peer_block.meta.hash.unwrap() == local_hash {
-                        fork_height = Some(height_to_check);
-                        info!("Fork point found at height {}", height_to_check);
-                        break;
-                    }
+            {
+                // If hashes match, we found the fork point
+                if peer_block.meta.hash.unwrap() == local_hash {
+                    fork_height = Some(height_to_check);
+                    info!("Fork point found at height {}", height_to_check);
+                    break;
                 }
             }
         }
@@ -66,10 +77,49 @@ pub async fn sync_to_peer(
     info!("Rolling back local chain to fork height {}", fork_height);
 
     // Rollback local chain to fork point
+    let mut disconnected = vec![];
     while blockchain.block_store().get_height() > fork_height {
-        blockchain.pop_block()?;
+        disconnected.push(blockchain.pop_block()?);
     }
 
+    // Even if applying the peer's blocks fails, the mempool has to follow the blocks we did apply
+    let mut connected = HashSet::new();
+    let res = apply_peer_blocks(peer, blockchain, fork_height, peer_height, &mut connected).await;
+
+    let mined = node_state
+        .mempool
+        .spend_transactions(connected.iter().copied().collect())
+        .await;
+    node_state.send_removed(&mined, RemovalReason::Mined);
+    let transactions: Vec<Transaction> = disconnected
+        .into_iter()
+        .rev()
+        .flat_map(|block| block.transactions)
+        .filter(|transaction| {
+            transaction
+                .transaction_id
+                .is_some_and(|transaction_id| !connected.contains(&transaction_id))
+        })
+        .collect();
+    readmit_transactions(blockchain, node_state, transactions).await;
+    prune_blocks(blockchain, node_state).await;
+    res?;
+
+    info!(
+        "Sync complete: local height now {}",
+        blockchain.block_store().get_height()
+    );
+    Ok(())
+}
+
+/// Download and apply the peer's blocks after the fork point, collecting the ids of the transactions in them
+async fn apply_peer_blocks(
+    peer: &PeerHandle,
+    blockchain: &SharedBlockchain,
+    fork_height: usize,
+    peer_height: usize,
+    connected: &mut HashSet<TransactionId>,
+) -> Result<(), SyncError> {
     info!(
         "Requesting block hashes from peer at height {}",
         fork_height
@@ -82,23 +132,24 @@ pub async fn sync_to_peer(
             end: current_height + 1,
         });
         let response = peer.request(msg).await?;
-        if let Command::GetBlockHashesResponse { block_hashes } = response.command {
-            if let Some(hash) = block_hashes.first() {
-                let block_msg = Message::new(Command::GetBlock { block_hash: *hash });
-                if let Command::GetBlockResponse { block: Some(block) } =
-                    peer.request(block_msg).await?.command
-                {
-                    blockchain.add_block(block, false)?;
-                    info!("Added block at height {}", current_height + 1);
-                }
+        if let Command::GetBlockHashesResponse { block_hashes } = response.command
+            && let Some(hash) = block_hashes.first()
+        {
+            let block_msg = Message::new(Command::GetBlock { block_hash: *hash });
+            if let Command::GetBlockResponse { block: Some(block) } =
+                peer.request(block_msg).await?.command
+            {
+                let transaction_ids: Vec<TransactionId> = block
+                    .transactions
+                    .iter()
+                    .filter_map(|transaction| transaction.transaction_id)
+                    .collect();
+                blockchain.add_block(block, false)?;
+                connected.extend(transaction_ids);
+                info!("Added block at height {}", current_height + 1);
             }
         }
         current_height += 1;
     }
-
-    info!(
-        "Sync complete: local height now {}",
-        blockchain.block_store().get_height()
-    );
     Ok(())
 }
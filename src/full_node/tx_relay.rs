@@ -0,0 +1,386 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+use rand::{random, seq::IndexedRandom};
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+
+use crate::{
+    core::{
+        blockchain::Blockchain,
+        transaction::{Transaction, TransactionId},
+    },
+    economics::EXPIRATION_TIME,
+    full_node::{
+        SharedBlockchain, accept_transaction,
+        node_state::{NodeState, SharedNodeState},
+    },
+    node::message::{Command, MAX_INV_SIZE, Message},
+};
+
+/// How often the relay daemon checks for due announcements and expired embargoes
+const RELAY_TICK: Duration = Duration::from_millis(100);
+
+/// How many expired local transactions we remember, for `TransactionStatus::Expired`
+const MAX_EXPIRED_LOCAL: usize = 500;
+
+/// How far back (in seconds) status lookups search the chain for a transaction. Transactions are mined within `EXPIRATION_TIME` of their timestamp,
+/// the rest is slack for block timestamps
+const CONFIRMATION_WINDOW: u64 = EXPIRATION_TIME * 2;
+
+/// Max announced transaction batches fetched from one peer at the same time, further announcements are ignored
+const MAX_FETCHES_PER_PEER: usize = 4;
+
+/// How transactions are relayed to peers
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct TxRelayConfig {
+    /// Mean delay before queued announcements are sent to a peer, drawn independently per peer
+    pub trickle_interval: Duration,
+    /// Chance that a stem transaction gets fluffed (broadcast) instead of passed on
+    pub fluff_probability: f64,
+    /// How long we keep relaying stem transactions to the same peer
+    pub stem_epoch: Duration,
+    /// Min time we wait for a stem transaction to come back fluffed, before fluffing it ourselves. A random extra of up to the same amount is added
+    pub embargo_timeout: Duration,
+    /// Time between re-announcements of transactions submitted through our API, until they are mined or expire
+    pub rebroadcast_interval: Duration,
+}
+
+impl Default for TxRelayConfig {
+    fn default() -> Self {
+        Self {
+            trickle_interval: Duration::from_secs(2),
+            fluff_probability: 0.1,
+            stem_epoch: Duration::from_secs(10 * 60),
+            embargo_timeout: Duration::from_secs(30),
+            rebroadcast_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Default)]
+struct TxRelayData {
+    /// Transaction ids waiting to be announced, per peer
+    queues: HashMap<SocketAddr, Vec<TransactionId>>,
+    /// When the queue of a peer gets sent next
+    next_flush: HashMap<SocketAddr, Instant>,
+    /// Peer stem transactions are relayed to this epoch, and when the epoch ends
+    stem_peer: Option<(SocketAddr, Instant)>,
+    /// Stem transactions we passed on, with the time we fluff them ourselves
+    embargoed: HashMap<TransactionId, (Transaction, Instant)>,
+    /// Transactions submitted publicly through our API, with the timestamp they expire at
+    local: HashMap<TransactionId, u64>,
+    /// Local transactions that expired before being mined, oldest first
+    expired_local: VecDeque<TransactionId>,
+    /// Announced transactions being fetched, per peer
+    fetching: HashMap<SocketAddr, usize>,
+}
+
+/// Where a transaction is at, as far as this node knows
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum TransactionStatus {
+    /// Waiting to be mined, in the mempool or still on its stem route
+    MemPool,
+    /// Mined in the block at `height`
+    Confirmed { height: usize },
+    /// Submitted through our API, but expired before it was mined
+    Expired,
+    /// Never seen, dropped from the mempool, or mined longer than `CONFIRMATION_WINDOW` ago
+    Unknown,
+}
+
+/// Transaction relay state. Broadcasts are trickled per peer as batched `InvTransactions`, and private submissions take a Dandelion++ stem route before being broadcast
+#[derive(Default)]
+pub struct TxRelay {
+    pub config: RwLock<TxRelayConfig>,
+    data: Mutex<TxRelayData>,
+}
+
+impl TxRelay {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue a transaction announcement for `peers`
+    pub fn announce(
+        &self,
+        transaction_id: TransactionId,
+        peers: impl IntoIterator<Item = SocketAddr>,
+    ) {
+        let mut data = self.data.lock().unwrap();
+        data.embargoed.remove(&transaction_id);
+        for peer in peers {
+            let queue = data.queues.entry(peer).or_default();
+            if !queue.contains(&transaction_id) {
+                queue.push(transaction_id);
+            }
+        }
+    }
+
+    /// Take the announcements of every peer whose trickle delay passed, and draw its next delay
+    pub fn take_due(&self, now: Instant) -> Vec<(SocketAddr, Vec<TransactionId>)> {
+        let trickle_interval = self.config.read().unwrap().trickle_interval;
+        let mut data = self.data.lock().unwrap();
+        let data = &mut *data;
+
+        let mut due = vec![];
+        for (peer, queue) in data.queues.iter_mut() {
+            let next_flush = data
+                .next_flush
+                .entry(*peer)
+                .or_insert_with(|| now + poisson_delay(trickle_interval));
+            if queue.is_empty() || *next_flush > now {
+                continue;
+            }
+            let count = queue.len().min(MAX_INV_SIZE);
+            due.push((*peer, queue.drain(..count).collect()));
+            *next_flush = now + poisson_delay(trickle_interval);
+        }
+        due
+    }
+
+    /// Get the stem peer for this epoch, picking a new one from `candidates` when the epoch ended or the old one is gone
+    pub fn stem_peer(&self, candidates: &[SocketAddr]) -> Option<SocketAddr> {
+        let stem_epoch = self.config.read().unwrap().stem_epoch;
+        let mut data = self.data.lock().unwrap();
+        let now = Instant::now();
+
+        if let Some((peer, epoch_end)) = data.stem_peer
+            && epoch_end > now
+            && candidates.contains(&peer)
+        {
+            return Some(peer);
+        }
+        data.stem_peer = candidates
+            .choose(&mut rand::rng())
+            .map(|peer| (*peer, now + stem_epoch));
+        data.stem_peer.map(|(peer, _)| peer)
+    }
+
+    /// Hold on to a stem transaction, so it can be fluffed if it doesn't come back. Returns false if it was already embargoed
+    pub fn embargo(&self, transaction: Transaction) -> bool {
+        let Some(transaction_id) = transaction.transaction_id else {
+            return false;
+        };
+        let embargo_timeout = self.config.read().unwrap().embargo_timeout;
+        let deadline = Instant::now() + embargo_timeout + embargo_timeout.mul_f64(random::<f64>());
+
+        let mut data = self.data.lock().unwrap();
+        if data.embargoed.contains_key(&transaction_id) {
+            return false;
+        }
+        data.embargoed
+            .insert(transaction_id, (transaction, deadline));
+        true
+    }
+
+    /// Returns true if we passed this stem transaction on, and it has not been fluffed yet
+    pub fn is_embargoed(&self, transaction_id: &TransactionId) -> bool {
+        self.data
+            .lock()
+            .unwrap()
+            .embargoed
+            .contains_key(transaction_id)
+    }
+
+    /// Take all stem transactions whose embargo ended
+    pub fn take_expired_embargoes(&self, now: Instant) -> Vec<Transaction> {
+        let mut data = self.data.lock().unwrap();
+        let expired: Vec<TransactionId> = data
+            .embargoed
+            .iter()
+            .filter(|(_, (_, deadline))| *deadline <= now)
+            .map(|(transaction_id, _)| *transaction_id)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|transaction_id| data.embargoed.remove(&transaction_id))
+            .map(|(transaction, _)| transaction)
+            .collect()
+    }
+
+    /// Track a transaction submitted through our API, so it gets re-announced until it is mined or expires at `expiry`
+    pub fn track_local(&self, transaction_id: TransactionId, expiry: u64) {
+        self.data
+            .lock()
+            .unwrap()
+            .local
+            .insert(transaction_id, expiry);
+    }
+
+    /// Get the tracked local transactions, with their expiry
+    pub fn local_transactions(&self) -> Vec<(TransactionId, u64)> {
+        let data = self.data.lock().unwrap();
+        data.local
+            .iter()
+            .map(|(id, expiry)| (*id, *expiry))
+            .collect()
+    }
+
+    /// Stop tracking a local transaction, remembering it if it `expired`
+    pub fn untrack_local(&self, transaction_id: &TransactionId, expired: bool) {
+        let mut data = self.data.lock().unwrap();
+        if data.local.remove(transaction_id).is_some() && expired {
+            data.expired_local.push_back(*transaction_id);
+            if data.expired_local.len() > MAX_EXPIRED_LOCAL {
+                data.expired_local.pop_front();
+            }
+        }
+    }
+
+    /// Returns true if a local transaction expired before it was mined
+    pub fn is_expired_local(&self, transaction_id: &TransactionId, now: u64) -> bool {
+        let data = self.data.lock().unwrap();
+        data.local
+            .get(transaction_id)
+            .is_some_and(|expiry| *expiry <= now)
+            || data.expired_local.contains(transaction_id)
+    }
+
+    /// Start fetching announced transactions from `peer`. Returns false if it already has `MAX_FETCHES_PER_PEER` fetches running
+    pub fn start_fetch(&self, peer: SocketAddr) -> bool {
+        let mut data = self.data.lock().unwrap();
+        let fetching = data.fetching.entry(peer).or_default();
+        if *fetching >= MAX_FETCHES_PER_PEER {
+            return false;
+        }
+        *fetching += 1;
+        true
+    }
+
+    /// A fetch started with `start_fetch` is done
+    pub fn end_fetch(&self, peer: SocketAddr) {
+        let mut data = self.data.lock().unwrap();
+        if let Some(fetching) = data.fetching.get_mut(&peer) {
+            *fetching = fetching.saturating_sub(1);
+        }
+    }
+
+    /// Drop everything queued for a disconnected peer
+    pub fn forget_peer(&self, peer: SocketAddr) {
+        let mut data = self.data.lock().unwrap();
+        data.queues.remove(&peer);
+        data.next_flush.remove(&peer);
+        data.fetching.remove(&peer);
+    }
+}
+
+/// Exponentially distributed delay with a mean of `mean`, so announcement times don't give away who saw a transaction first
+fn poisson_delay(mean: Duration) -> Duration {
+    // 1 - random is in (0, 1], which keeps ln finite
+    mean.mul_f64(-(1.0 - random::<f64>()).ln()).min(mean * 10)
+}
+
+/// Get the status of a transaction
+pub async fn transaction_status(
+    blockchain: &Blockchain,
+    node_state: &NodeState,
+    transaction_id: TransactionId,
+) -> TransactionStatus {
+    if node_state.mempool.contains(&transaction_id).await
+        || node_state.tx_relay.is_embargoed(&transaction_id)
+    {
+        return TransactionStatus::MemPool;
+    }
+    let now = chrono::Utc::now().timestamp() as u64;
+    if let Some(height) = blockchain
+        .block_store()
+        .get_transaction_height(transaction_id, now.saturating_sub(CONFIRMATION_WINDOW))
+    {
+        return TransactionStatus::Confirmed { height };
+    }
+    if node_state.tx_relay.is_expired_local(&transaction_id, now) {
+        return TransactionStatus::Expired;
+    }
+    TransactionStatus::Unknown
+}
+
+/// Track a transaction submitted through our API, expiring when a mempool entry added now would
+pub fn track_submitted(node_state: &NodeState, transaction_id: TransactionId) {
+    let expiry = chrono::Utc::now().timestamp() as u64 + EXPIRATION_TIME;
+    node_state.tx_relay.track_local(transaction_id, expiry);
+}
+
+/// Re-announce local transactions that are still in the mempool to every peer, so ones that got lost on the way still get relayed.
+/// Mined and expired ones are no longer tracked
+async fn rebroadcast_local(blockchain: &Blockchain, node_state: &NodeState) {
+    let now = chrono::Utc::now().timestamp() as u64;
+    let peers: Vec<SocketAddr> = node_state
+        .connected_peers
+        .read()
+        .await
+        .keys()
+        .copied()
+        .collect();
+
+    for (transaction_id, expiry) in node_state.tx_relay.local_transactions() {
+        if node_state.mempool.contains(&transaction_id).await {
+            node_state
+                .tx_relay
+                .announce(transaction_id, peers.iter().copied());
+        } else if blockchain
+            .block_store()
+            .get_transaction_height(transaction_id, now.saturating_sub(CONFIRMATION_WINDOW))
+            .is_some()
+        {
+            node_state.tx_relay.untrack_local(&transaction_id, false);
+        } else if expiry <= now {
+            node_state.tx_relay.untrack_local(&transaction_id, true);
+        }
+    }
+}
+
+/// Start the transaction relay daemon. It sends due announcements, fluffs stem transactions whose embargo ended and re-announces local transactions
+pub fn start_tx_relay(blockchain: SharedBlockchain, node_state: SharedNodeState) {
+    let shutdown_state = node_state.clone();
+    shutdown_state.shutdown.spawn(async move {
+        let mut last_rebroadcast = Instant::now();
+        loop {
+            sleep(RELAY_TICK).await;
+            let now = Instant::now();
+
+            let rebroadcast_interval = node_state
+                .tx_relay
+                .config
+                .read()
+                .unwrap()
+                .rebroadcast_interval;
+            if now >= last_rebroadcast + rebroadcast_interval {
+                last_rebroadcast = now;
+                rebroadcast_local(&blockchain, &node_state).await;
+            }
+
+            for (address, transaction_ids) in node_state.tx_relay.take_due(now) {
+                let peer = node_state
+                    .connected_peers
+                    .read()
+                    .await
+                    .get(&address)
+                    .cloned();
+                let Some(peer) = peer else {
+                    node_state.tx_relay.forget_peer(address);
+                    continue;
+                };
+                if let Err(e) = peer
+                    .send(Message::new(Command::InvTransactions { transaction_ids }))
+                    .await
+                {
+                    warn!("Failed to announce transactions to {address}: {e}");
+                }
+            }
+
+            for transaction in node_state.tx_relay.take_expired_embargoes(now) {
+                info!("Stem transaction embargo ended, fluffing it");
+                if let Err(e) = accept_transaction(&blockchain, &node_state, transaction).await {
+                    warn!("Embargoed transaction is no longer valid: {e}");
+                }
+            }
+        }
+    });
+}
@@ -32,6 +32,12 @@ pub mod api;
 /// A set with a maximum capacity that deletes the oldest elements first
 pub mod bounded_set;
 
+/// Optional process wide logger setup
+pub mod logging;
+
+/// Node config files, and a builder that starts a node in one call
+pub mod config;
+
 /// Tests
 mod tests;
 
@@ -88,6 +94,7 @@ pub enum UtilError {
 
 /// Build a new transactions, sending from sender to receiver where each receiver has a amount to receive attached. Takes biggest coins first.
 /// WARNING: this does not compute transaction pow!
+#[allow(clippy::ptr_arg)]
 pub async fn build_transaction<B>(
     blockchain_data_provider: &B,
     sender: Private,
@@ -134,7 +141,7 @@ where
         return Err(UtilError::TooMuchIO);
     }
 
-    used_inputs.sort_by(|a, b| a.1.amount.cmp(&b.1.amount)); // From highest amount to lowest amount (breadcrumbs last)
+    used_inputs.sort_by_key(|a| a.1.amount); // From highest amount to lowest amount (breadcrumbs last)
 
     let transaction = Transaction::new_transaction_now(
         used_inputs
@@ -163,6 +170,7 @@ where
 /// WARNING: This does not compute block pow nor hash!
 /// WARNING: It is assumed that all input transactions are fully valid (at current blockchain height)
 /// WARNING: This function adds reward transactions for you!
+#[allow(clippy::ptr_arg)]
 pub async fn build_block<B>(
     blockchain_data_provider: &B,
     transactions: &Vec<Transaction>,
@@ -187,7 +195,7 @@ where
                 receiver: miner,
             },
         ],
-        &mut vec![],
+        &mut [],
     )?);
 
     if transactions.len() > MAX_TRANSACTIONS_PER_BLOCK {
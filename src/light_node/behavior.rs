@@ -3,9 +3,11 @@ use std::sync::Arc;
 use log::warn;
 
 use crate::{
+    core::transaction::TransactionId,
     light_node::{SharedLightNodeState, accept_block, accept_transaction},
     node::{
-        message::{Command, Message},
+        message::{Command, MAX_INV_SIZE, Message},
+        misbehavior::Misbehavior,
         peer::{PeerError, PeerHandle},
         peer_behavior::{PeerBehavior, SharedPeerBehavior},
     },
@@ -16,6 +18,7 @@ pub struct LightNodePeerBehavior {
 }
 
 impl LightNodePeerBehavior {
+    #[allow(clippy::new_ret_no_self)]
     pub fn new(light_node_state: SharedLightNodeState) -> SharedPeerBehavior {
         Arc::new(Self { light_node_state })
     }
@@ -26,8 +29,11 @@ impl PeerBehavior for LightNodePeerBehavior {
     async fn on_message(&self, message: Message, _peer: &PeerHandle) -> Result<Message, PeerError> {
         let light_node_state = &self.light_node_state;
         let response = match message.command {
-            Command::Connect => message.make_response(Command::AcknowledgeConnection),
-            Command::AcknowledgeConnection => {
+            Command::Connect { .. } => message.make_response(Command::AcknowledgeConnection {
+                listen_address: None,
+                first_block: usize::MAX,
+            }),
+            Command::AcknowledgeConnection { .. } => {
                 return Err(PeerError::Unknown(
                     "Got unhandled AcknowledgeConnection".to_string(),
                 ));
@@ -40,36 +46,24 @@ impl PeerBehavior for LightNodePeerBehavior {
             Command::SendPeers { .. } => {
                 return Err(PeerError::Unknown("Got unhandled SendPeers".to_string()));
             }
-            Command::NewBlock { ref block } => {
-                // if !*light_node_state.is_syncing.read().await {
-                match accept_block(&light_node_state, block.clone()).await {
-                    Ok(()) => {}
-                    Err(e) => {
-                        warn!("Incoming block is invalid: {e}")
-                    }
-                }
-                // }
-
-                message.make_response(Command::NewBlockResolved)
-            }
-            Command::NewBlockResolved => {
+            Command::GetTransactions { .. } => {
+                message.make_response(Command::GetTransactionsResponse {
+                    transactions: vec![],
+                })
+            } // We don't keep a mempool
+            Command::GetTransactionsResponse { .. } => {
                 return Err(PeerError::Unknown(
-                    "Got unhandled NewBlockAccepted".to_string(),
+                    "Got unhandled GetTransactionsResponse".to_string(),
                 ));
             }
-            Command::NewTransaction { ref transaction } => {
-                match accept_transaction(&light_node_state, transaction.clone()).await {
-                    Ok(()) => {}
-                    Err(e) => {
-                        warn!("Incoming transaction is invalid: {e}")
-                    }
-                }
-                message.make_response(Command::NewTransactionResolved)
-            }
-            Command::NewTransactionResolved => {
-                return Err(PeerError::Unknown(
-                    "Got unhandled NewTransactionAccepted".to_string(),
-                ));
+            Command::NewBlock { .. }
+            | Command::NewTransaction { .. }
+            | Command::InvTransactions { .. }
+            | Command::StemTransaction { .. } => {
+                return Err(PeerError::Unknown(format!(
+                    "Got notification {} as a request",
+                    message.command.name()
+                )));
             }
             Command::GetBlock { .. } => message.make_response(Command::GetBlockResponse {
                 block: None, // We do not store blocks
@@ -97,6 +91,14 @@ impl PeerBehavior for LightNodePeerBehavior {
                     "Got unhandled GetBlockResponse".to_string(),
                 ));
             }
+            Command::GetHeaders { .. } => {
+                message.make_response(Command::GetHeadersResponse { headers: vec![] })
+            } // We can't build headers as we don't store blocks
+            Command::GetHeadersResponse { .. } => {
+                return Err(PeerError::Unknown(
+                    "Got unhandled GetHeadersResponse".to_string(),
+                ));
+            }
             Command::GetTransactionMerkleProof { .. } => {
                 message.make_response(Command::GetTransactionMerkleProofResponse { proof: None })
             } // We don't give merkle proofs as we don't have all blocks
@@ -119,10 +121,64 @@ impl PeerBehavior for LightNodePeerBehavior {
         Ok(response)
     }
 
+    async fn on_notification(&self, message: Message, peer: &PeerHandle) -> Result<(), PeerError> {
+        let light_node_state = &self.light_node_state;
+        match message.command {
+            Command::NewBlock { block } => {
+                if let Err(e) = accept_block(light_node_state, block).await {
+                    warn!("Incoming block is invalid: {e}")
+                }
+            }
+            Command::NewTransaction { transaction } => {
+                if let Err(e) = accept_transaction(light_node_state, transaction).await {
+                    warn!("Incoming transaction is invalid: {e}")
+                }
+            }
+            Command::InvTransactions { transaction_ids } => {
+                let unknown: Vec<TransactionId> = {
+                    let seen = light_node_state.seen_transactions.read().await;
+                    transaction_ids
+                        .into_iter()
+                        .take(MAX_INV_SIZE)
+                        .filter(|id| !seen.contains(id))
+                        .collect()
+                };
+                if unknown.is_empty() {
+                    return Ok(());
+                }
+                if let Command::GetTransactionsResponse { transactions } = peer
+                    .request(Message::new(Command::GetTransactions {
+                        transaction_ids: unknown,
+                    }))
+                    .await?
+                    .command
+                {
+                    for transaction in transactions {
+                        if let Err(e) = accept_transaction(light_node_state, transaction).await {
+                            warn!("Incoming transaction is invalid: {e}")
+                        }
+                    }
+                }
+            }
+            Command::StemTransaction { .. } => {} // Light nodes don't relay
+            command => {
+                return Err(PeerError::Unknown(format!(
+                    "Got {} as a notification",
+                    command.name()
+                )));
+            }
+        }
+        Ok(())
+    }
+
     async fn get_height(&self) -> usize {
         self.light_node_state.meta_store().get_height()
     }
 
+    async fn on_misbehavior(&self, _peer: &PeerHandle, _misbehavior: Misbehavior) {
+        // Light nodes only connect to nodes they chose, they don't keep ban scores
+    }
+
     async fn on_kill(&self, peer: &PeerHandle) {
         self.light_node_state
             .connected_peers
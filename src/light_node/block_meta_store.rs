@@ -2,7 +2,7 @@ use std::{
     collections::HashMap,
     fs::{self, File},
     io::Write,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{Mutex, RwLock},
 };
 
@@ -135,6 +135,11 @@ impl BlockMetaStore {
         Some(meta)
     }
 
+    /// Write the meta store data to disk. It is already saved on every block, this is for shutting down
+    pub fn flush(&self) -> Result<(), BlockMetaStoreError> {
+        self.save_meta_store_data()
+    }
+
     pub fn get_meta_by_hash(&self, hash: Hash) -> Option<BlockMetadata> {
         let height = *self.meta_index.read().unwrap().by_hash.get(&hash)?;
         self.get_meta_by_height(height)
@@ -159,13 +164,11 @@ impl BlockMetaStore {
         Ok(())
     }
 
-    fn load_meta_store_data(node_path: &PathBuf) -> Result<Self, BlockMetaStoreError> {
+    fn load_meta_store_data(node_path: &Path) -> Result<Self, BlockMetaStoreError> {
         let mut file = File::open(format!("{}/meta-store.dat", node_path.to_str().unwrap()))
             .map_err(|e| BlockMetaStoreError::Io(e.to_string()))?;
-        Ok(
-            bincode::decode_from_std_read(&mut file, bincode::config::standard())
-                .map_err(|_| BlockMetaStoreError::Decode)?,
-        )
+        bincode::decode_from_std_read(&mut file, bincode::config::standard())
+            .map_err(|_| BlockMetaStoreError::Decode)
     }
 }
 
@@ -32,6 +32,12 @@ pub async fn initial_block_download(
         info!("Already up-to-date (local height >= remote height)");
         return Ok(());
     }
+    if peer.first_block() > local_height {
+        return Err(PeerError::Unknown(format!(
+            "Peer is pruned, it has no blocks below height {}",
+            peer.first_block()
+        )));
+    }
 
     info!("Starting initial block download (full)");
 
@@ -83,13 +89,12 @@ pub async fn initial_block_download(
 
     info!("Saved! Updating difficulties...");
 
-    if let Some(last_hash) = hashes.last() {
-        if let Command::GetBlockResponse { block } = peer.request(Message::new(Command::GetBlock { block_hash: *last_hash })).await?.command && let Some(block) = block {
-            *light_node_state.meta_store().difficulty_state.block_difficulty.write().unwrap() = block.meta.block_pow_difficulty;
-            *light_node_state.meta_store().difficulty_state.transaction_difficulty.write().unwrap() = block.meta.tx_pow_difficulty;
-            light_node_state.meta_store().difficulty_state.update_difficulty(&block);
-        }
-
+    if let Some(last_hash) = hashes.last()
+        && let Command::GetBlockResponse { block } = peer.request(Message::new(Command::GetBlock { block_hash: *last_hash })).await?.command && let Some(block) = block
+    {
+        *light_node_state.meta_store().difficulty_state.block_difficulty.write().unwrap() = block.meta.block_pow_difficulty;
+        *light_node_state.meta_store().difficulty_state.transaction_difficulty.write().unwrap() = block.meta.tx_pow_difficulty;
+        light_node_state.meta_store().difficulty_state.update_difficulty(&block);
     }
 
     info!("Initial block download complete");
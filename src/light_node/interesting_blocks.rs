@@ -32,7 +32,7 @@ impl InterestingBlocks {
                 if is_present {
                     // Lock the map and append the hash to that specific address's list
                     let mut map = self.interested.write().unwrap();
-                    map.entry(*address).or_insert_with(Vec::new).push(block_hash);
+                    map.entry(*address).or_default().push(block_hash);
                 }
             }
         }
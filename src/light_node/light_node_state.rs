@@ -9,7 +9,11 @@ use crate::{
     },
     crypto::Hash,
     light_node::block_meta_store::BlockMetaStore,
-    node::peer::PeerHandle,
+    node::{
+        connector::{DirectConnector, SharedConnector},
+        peer::{PeerConfig, PeerHandle},
+        shutdown::Shutdown,
+    },
 };
 use std::{collections::HashMap, net::SocketAddr, path::PathBuf};
 
@@ -18,6 +22,12 @@ pub struct LightNodeState {
     pub connected_peers: RwLock<HashMap<SocketAddr, PeerHandle>>,
     pub seen_transactions: RwLock<BoundedSet<TransactionId>>,
     pub seen_blocks: RwLock<BoundedSet<Hash>>,
+    /// Opens peer connections, set a `Socks5Connector` to connect through Tor or another proxy
+    pub connector: RwLock<SharedConnector>,
+    /// Request settings for new peer connections
+    pub peer_config: RwLock<PeerConfig>,
+    /// Spawn light node daemons through this, so `LightNodeHandle::shutdown` can stop them
+    pub shutdown: Shutdown,
     meta_store: BlockMetaStore,
 }
 
@@ -29,6 +39,9 @@ impl LightNodeState {
             chain_events: broadcast::channel(12).0,
             seen_transactions: RwLock::new(BoundedSet::new(1000)),
             seen_blocks: RwLock::new(BoundedSet::new(100)),
+            connector: RwLock::new(DirectConnector::new()),
+            peer_config: RwLock::new(PeerConfig::default()),
+            shutdown: Shutdown::new(),
         }
     }
     pub fn meta_store(&self) -> &BlockMetaStore {
@@ -13,79 +13,142 @@ pub mod initial_block_download;
 /// Handles what the light node does when it gets a p2p message
 mod behavior;
 
-use flexi_logger::{Duplicate, FileSpec, Logger};
-use log::info;
+use flexi_logger::LoggerHandle;
+use log::{info, warn};
 use num_bigint::BigUint;
-use std::{
-    net::SocketAddr,
-    path::PathBuf,
-    sync::{Arc, Once},
-};
-use tokio::net::TcpStream;
+use std::{path::PathBuf, sync::Arc};
 
 use crate::{
+    config::{NodeConfig, StartError},
     core::{
         block::{Block, MAX_TRANSACTIONS_PER_BLOCK},
         blockchain::{self, BlockchainError},
         difficulty::calculate_block_difficulty,
         transaction::{MAX_TRANSACTION_IO, Transaction, TransactionError},
     },
+    crypto::randomx_set_mode,
     light_node::{
         behavior::LightNodePeerBehavior,
+        block_meta_store::BlockMetaStoreError,
         light_node_state::{LightChainEvent, LightNodeState},
     },
-    node::peer::{PeerError, PeerHandle, create_peer},
+    logging::init_logging_unless_set,
+    node::{
+        connector::{Endpoint, Socks5Connector},
+        peer::{PeerError, PeerHandle, create_peer_with_endpoint},
+        shutdown::disconnect_peers,
+    },
 };
 
 pub type SharedLightNodeState = Arc<LightNodeState>;
 
-static LOGGER_INIT: Once = Once::new();
-
-/// Creates a full node (SharedBlockchain and SharedNodeState), connecting to peers, accepting blocks and transactions
-pub fn create_light_node(node_path: &str, disable_stdout: bool) -> SharedLightNodeState {
+/// Creates a light node (SharedLightNodeState), that follows block headers and the blocks it is interested in.
+/// Any amount of nodes can run in one process, as long as they have different paths. Nodes don't set up logging, see `logging::init_logging`
+pub fn create_light_node(node_path: &str) -> SharedLightNodeState {
     let node_path = PathBuf::from(node_path);
 
-    LOGGER_INIT.call_once(|| {
-        let log_path = node_path.join("logs");
-        std::fs::create_dir_all(&log_path).expect("Failed to create log directory");
+    let node_state = LightNodeState::new_empty(node_path);
+
+    Arc::new(node_state)
+}
+
+/// Start a light node from a config in one call. Sets up logging and the RandomX mode if the config has them, and connects to the configured peers.
+/// Peers that can't be connected to are only logged
+pub async fn start_light_node(config: &NodeConfig) -> Result<LightNodeHandle, StartError> {
+    let logger = match &config.log {
+        Some(log) => init_logging_unless_set(log)?,
+        None => None,
+    };
+    if let Some(mode) = config.randomx_mode {
+        randomx_set_mode(mode);
+    }
 
-        let mut logger = Logger::try_with_str("info")
-            .unwrap()
-            .log_to_file(FileSpec::default().directory(&log_path));
+    let node_state = create_light_node(
+        config
+            .node_path
+            .to_str()
+            .expect("Node path is not valid UTF-8"),
+    );
+    *node_state.peer_config.write().await = config.peer;
+    if let Some(proxy) = config.proxy {
+        *node_state.connector.write().await = Socks5Connector::new(proxy);
+    }
 
-        if !disable_stdout {
-            logger = logger.duplicate_to_stderr(Duplicate::Info);
+    for endpoint in &config.peers {
+        if let Err(e) = connect_peer(endpoint.clone(), &node_state).await {
+            warn!("Failed to connect to peer {endpoint}, error: {e}");
         }
+    }
+    Ok(LightNodeHandle { node_state, logger })
+}
 
-        logger.start().ok(); // Ignore errors if logger is already set
+/// Handle to a running light node, used to shut it down
+#[derive(Clone)]
+pub struct LightNodeHandle {
+    pub node_state: SharedLightNodeState,
+    /// Logger set up by `start_light_node`, kept so file logs stay open while the node runs
+    logger: Option<LoggerHandle>,
+}
 
-        info!("Logger initialized for node at {:?}", node_path);
-    });
+impl LightNodeHandle {
+    pub fn new(node_state: SharedLightNodeState) -> Self {
+        Self {
+            node_state,
+            logger: None,
+        }
+    }
 
-    let node_state = LightNodeState::new_empty(node_path);
+    /// Stop the node. Daemons spawned through `LightNodeState::shutdown` are cancelled, peers are disconnected, and the meta store is flushed to disk
+    pub async fn shutdown(&self) -> Result<(), BlockMetaStoreError> {
+        let node_state = &self.node_state;
+        info!("Shutting down light node");
 
-    Arc::new(node_state)
+        node_state.shutdown.trigger();
+        if !node_state.shutdown.wait_for_daemons().await {
+            warn!("Some daemons did not stop in time");
+        }
+        disconnect_peers(&node_state.connected_peers).await;
+        node_state.meta_store().flush()?;
+
+        info!("Light node shut down");
+        if let Some(logger) = &self.logger {
+            logger.flush();
+        }
+        Ok(())
+    }
 }
 
-/// Connect to a peer
+/// Connect to a peer, an ip address or a host name (see `Endpoint`), using the connector of the node
 pub async fn connect_peer(
-    address: SocketAddr,
+    endpoint: Endpoint,
     light_node_state: &SharedLightNodeState,
 ) -> Result<PeerHandle, PeerError> {
-    let stream = TcpStream::connect(address)
+    if light_node_state.shutdown.is_shutting_down() {
+        return Err(PeerError::ShuttingDown);
+    }
+    let connector = light_node_state.connector.read().await.clone();
+    let stream = connector
+        .connect(&endpoint)
         .await
-        .map_err(|e| PeerError::Io(format!("IO error: {e}")))?;
+        .map_err(|e| PeerError::Io(format!("Connector error: {e}")))?;
 
-    let handle = create_peer(
+    let handle = create_peer_with_endpoint(
         stream,
+        endpoint,
         LightNodePeerBehavior::new(light_node_state.clone()),
         false,
+        *light_node_state.peer_config.read().await,
     )?;
+    // Light nodes don't accept connections, and serve no blocks
+    if let Err(e) = handle.handshake(None, usize::MAX).await {
+        let _ = handle.kill(e.to_string()).await;
+        return Err(e);
+    }
     light_node_state
         .connected_peers
         .write()
         .await
-        .insert(address, handle.clone());
+        .insert(handle.address, handle.clone());
 
     Ok(handle)
 }
@@ -0,0 +1,65 @@
+use std::path::PathBuf;
+
+use flexi_logger::{Duplicate, FileSpec, FlexiLoggerError, Logger, LoggerHandle};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum LoggingError {
+    #[error("Logger error: {0}")]
+    Logger(#[from] FlexiLoggerError),
+}
+
+/// Where a process logs to, and how much
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LogConfig {
+    /// Log level, or a full spec like `info,sled=warn`
+    pub level: String,
+    /// Directory to write log files to, no log files if `None`
+    pub directory: Option<PathBuf>,
+    /// Also log to stderr
+    pub stderr: bool,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            directory: None,
+            stderr: true,
+        }
+    }
+}
+
+/// Start logging for the whole process.
+/// Nodes only log through the `log` facade and never set up a logger themselves, so this is optional and any other `log` implementation can be used instead.
+/// Keep the returned handle around, file logs are flushed when it is dropped
+pub fn init_logging(config: &LogConfig) -> Result<LoggerHandle, LoggingError> {
+    let logger = Logger::try_with_str(&config.level)?;
+    let logger = match &config.directory {
+        Some(directory) => {
+            let logger = logger.log_to_file(FileSpec::default().directory(directory));
+            if config.stderr {
+                logger.duplicate_to_stderr(Duplicate::All)
+            } else {
+                logger
+            }
+        }
+        None if config.stderr => logger.log_to_stderr(),
+        None => logger.do_not_log(),
+    };
+    Ok(logger.start()?)
+}
+
+/// Start logging like `init_logging`, unless a logger is already set (by the application, or another node in this process). That one is kept, with a warning
+pub fn init_logging_unless_set(config: &LogConfig) -> Result<Option<LoggerHandle>, LoggingError> {
+    match init_logging(config) {
+        Ok(logger) => Ok(Some(logger)),
+        Err(LoggingError::Logger(FlexiLoggerError::Log(_))) => {
+            warn!("A logger is already set, the log config is ignored");
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,327 @@
+use std::{
+    fmt,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+
+/// Max time a connection (including the proxy handshake) may take
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Made up addresses for host endpoints live in this /48, the OnionCat range
+const HOST_ADDRESS_PREFIX: [u8; 6] = [0xfd, 0x87, 0xd8, 0x7e, 0xeb, 0x43];
+
+#[derive(Error, Debug, Clone)]
+pub enum ConnectorError {
+    #[error("IO error: {0}")]
+    Io(String),
+
+    #[error("Connection timed out")]
+    Timeout,
+
+    #[error("Can't connect to {0} without a proxy")]
+    UnsupportedEndpoint(String),
+
+    #[error("Invalid endpoint: {0}")]
+    InvalidEndpoint(String),
+
+    #[error("SOCKS5 error: {0}")]
+    Socks5(String),
+}
+
+impl From<std::io::Error> for ConnectorError {
+    fn from(e: std::io::Error) -> Self {
+        ConnectorError::Io(e.to_string())
+    }
+}
+
+/// Something we can connect to. Either an ip address, or a host name (like a .onion address) that gets resolved by whoever connects to it.
+/// Serialized as `ip:port` or `host:port`
+#[derive(Serialize, Deserialize, Encode, Decode, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(try_from = "String", into = "String")]
+pub enum Endpoint {
+    Ip(SocketAddr),
+    Host { host: String, port: u16 },
+}
+
+impl Endpoint {
+    /// Returns true for .onion hosts, which only Tor can reach
+    pub fn is_onion(&self) -> bool {
+        matches!(self, Endpoint::Host { host, .. } if host.ends_with(".onion"))
+    }
+
+    /// Ip of an ip endpoint, `None` for host endpoints
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Endpoint::Ip(address) => Some(address.ip()),
+            Endpoint::Host { .. } => None,
+        }
+    }
+
+    /// Address this endpoint is known by locally (in peer maps, ban lists...).
+    /// Host endpoints get a stable made up address in `fd87:d87e:eb43::/48`, as we never learn their real ip
+    pub fn socket_addr(&self) -> SocketAddr {
+        match self {
+            Endpoint::Ip(address) => *address,
+            Endpoint::Host { host, port } => {
+                let digest = Sha256::digest(host.to_lowercase().as_bytes());
+                let mut octets = [0u8; 16];
+                octets[..6].copy_from_slice(&HOST_ADDRESS_PREFIX);
+                octets[6..].copy_from_slice(&digest[..10]);
+                SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), *port)
+            }
+        }
+    }
+}
+
+/// Returns true if `ip` is a made up address of a host endpoint
+pub fn is_host_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V6(ip) => ip.octets()[..6] == HOST_ADDRESS_PREFIX,
+        IpAddr::V4(_) => false,
+    }
+}
+
+impl From<SocketAddr> for Endpoint {
+    fn from(address: SocketAddr) -> Self {
+        Endpoint::Ip(address)
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = ConnectorError;
+
+    /// Parse `ip:port`, `[ipv6]:port` or `host:port`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(address) = s.parse() {
+            return Ok(Endpoint::Ip(address));
+        }
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or(ConnectorError::InvalidEndpoint(s.to_string()))?;
+        let port = port
+            .parse()
+            .map_err(|_| ConnectorError::InvalidEndpoint(s.to_string()))?;
+        if host.is_empty() || host.len() > 255 || host.contains(['[', ']', ':', '/', ' ']) {
+            return Err(ConnectorError::InvalidEndpoint(s.to_string()));
+        }
+        Ok(Endpoint::Host {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl TryFrom<String> for Endpoint {
+    type Error = ConnectorError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Endpoint> for String {
+    fn from(endpoint: Endpoint) -> Self {
+        endpoint.to_string()
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Ip(address) => write!(f, "{address}"),
+            Endpoint::Host { host, port } => write!(f, "{host}:{port}"),
+        }
+    }
+}
+
+pub type SharedConnector = Arc<dyn Connector + Send + Sync>;
+
+/// Opens outbound connections for peers and API clients
+#[async_trait::async_trait]
+pub trait Connector {
+    async fn connect(&self, endpoint: &Endpoint) -> Result<TcpStream, ConnectorError>;
+}
+
+/// Connects straight to the endpoint, resolving host names with the system resolver
+pub struct DirectConnector;
+
+impl DirectConnector {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new() -> SharedConnector {
+        Arc::new(Self)
+    }
+}
+
+#[async_trait::async_trait]
+impl Connector for DirectConnector {
+    async fn connect(&self, endpoint: &Endpoint) -> Result<TcpStream, ConnectorError> {
+        let stream = match endpoint {
+            Endpoint::Ip(address) => timeout(CONNECT_TIMEOUT, TcpStream::connect(address)).await,
+            Endpoint::Host { .. } if endpoint.is_onion() => {
+                return Err(ConnectorError::UnsupportedEndpoint(endpoint.to_string()));
+            }
+            Endpoint::Host { host, port } => {
+                timeout(CONNECT_TIMEOUT, TcpStream::connect((host.as_str(), *port))).await
+            }
+        }
+        .map_err(|_| ConnectorError::Timeout)??;
+        Ok(stream)
+    }
+}
+
+/// Connects through a SOCKS5 proxy (like Tor). Host names are resolved by the proxy, so no DNS requests leak
+pub struct Socks5Connector {
+    pub proxy: SocketAddr,
+    /// Username and password, if the proxy wants them. Tor uses different credentials to isolate circuits
+    pub credentials: Option<(String, String)>,
+}
+
+impl Socks5Connector {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(proxy: SocketAddr) -> SharedConnector {
+        Arc::new(Self {
+            proxy,
+            credentials: None,
+        })
+    }
+
+    pub fn with_credentials(
+        proxy: SocketAddr,
+        username: String,
+        password: String,
+    ) -> SharedConnector {
+        Arc::new(Self {
+            proxy,
+            credentials: Some((username, password)),
+        })
+    }
+
+    async fn handshake(&self, endpoint: &Endpoint) -> Result<TcpStream, ConnectorError> {
+        let mut stream = TcpStream::connect(self.proxy).await?;
+
+        // Greeting, offer no auth, and username / password auth if we have credentials
+        let methods: &[u8] = if self.credentials.is_some() {
+            &[0x00, 0x02]
+        } else {
+            &[0x00]
+        };
+        let mut greeting = vec![0x05, methods.len() as u8];
+        greeting.extend_from_slice(methods);
+        stream.write_all(&greeting).await?;
+
+        let mut choice = [0u8; 2];
+        stream.read_exact(&mut choice).await?;
+        if choice[0] != 0x05 {
+            return Err(ConnectorError::Socks5("Proxy is not a SOCKS5 proxy".into()));
+        }
+        match (choice[1], &self.credentials) {
+            (0x00, _) => {}
+            (0x02, Some((username, password))) => {
+                if username.len() > 255 || password.len() > 255 {
+                    return Err(ConnectorError::Socks5("Credentials too long".into()));
+                }
+                let mut auth = vec![0x01, username.len() as u8];
+                auth.extend_from_slice(username.as_bytes());
+                auth.push(password.len() as u8);
+                auth.extend_from_slice(password.as_bytes());
+                stream.write_all(&auth).await?;
+
+                let mut status = [0u8; 2];
+                stream.read_exact(&mut status).await?;
+                if status[1] != 0x00 {
+                    return Err(ConnectorError::Socks5("Authentication failed".into()));
+                }
+            }
+            _ => {
+                return Err(ConnectorError::Socks5(
+                    "No acceptable authentication method".into(),
+                ));
+            }
+        }
+
+        // Connect request
+        let mut request = vec![0x05, 0x01, 0x00];
+        let port = match endpoint {
+            Endpoint::Ip(address) => {
+                match address.ip() {
+                    IpAddr::V4(ip) => {
+                        request.push(0x01);
+                        request.extend_from_slice(&ip.octets());
+                    }
+                    IpAddr::V6(ip) => {
+                        request.push(0x04);
+                        request.extend_from_slice(&ip.octets());
+                    }
+                }
+                address.port()
+            }
+            Endpoint::Host { host, port } => {
+                if host.len() > 255 {
+                    return Err(ConnectorError::InvalidEndpoint(endpoint.to_string()));
+                }
+                request.push(0x03);
+                request.push(host.len() as u8);
+                request.extend_from_slice(host.as_bytes());
+                *port
+            }
+        };
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        // Reply, the bound address that follows the header is not used
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != 0x05 {
+            return Err(ConnectorError::Socks5("Malformed reply".into()));
+        }
+        if reply[1] != 0x00 {
+            return Err(ConnectorError::Socks5(socks5_reply_message(reply[1])));
+        }
+        let address_len = match reply[3] {
+            0x01 => 4,
+            0x04 => 16,
+            0x03 => stream.read_u8().await? as usize,
+            _ => return Err(ConnectorError::Socks5("Malformed reply".into())),
+        };
+        let mut bound = vec![0u8; address_len + 2];
+        stream.read_exact(&mut bound).await?;
+
+        Ok(stream)
+    }
+}
+
+#[async_trait::async_trait]
+impl Connector for Socks5Connector {
+    async fn connect(&self, endpoint: &Endpoint) -> Result<TcpStream, ConnectorError> {
+        timeout(CONNECT_TIMEOUT, self.handshake(endpoint))
+            .await
+            .map_err(|_| ConnectorError::Timeout)?
+    }
+}
+
+fn socks5_reply_message(reply: u8) -> String {
+    match reply {
+        0x01 => "General failure",
+        0x02 => "Connection not allowed by ruleset",
+        0x03 => "Network unreachable",
+        0x04 => "Host unreachable",
+        0x05 => "Connection refused",
+        0x06 => "TTL expired",
+        0x07 => "Command not supported",
+        0x08 => "Address type not supported",
+        _ => "Unknown failure",
+    }
+    .to_string()
+}
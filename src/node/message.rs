@@ -1,4 +1,4 @@
-use std::array::TryFromSliceError;
+use std::{array::TryFromSliceError, net::SocketAddr};
 
 use bincode::{Decode, Encode};
 use rand::random;
@@ -10,7 +10,7 @@ use tokio::{
 
 use crate::{
     core::{
-        block::{Block, BlockMetadata},
+        block::{Block, BlockHeader, BlockMetadata},
         transaction::{Transaction, TransactionId},
     },
     crypto::{Hash, merkle_tree::MerkleTreeProof},
@@ -21,8 +21,16 @@ use crate::{
 #[derive(Encode, Decode, Debug, Clone)]
 pub enum Command {
     // Connect / keep-alive
-    Connect,
-    AcknowledgeConnection,
+    /// Handshake, sent by the connecting side. `listen_address` is where the sender accepts connections, an unspecified ip means "the ip you see me connecting from".
+    /// `first_block` is the lowest block height the sender serves, above 0 if it is pruned
+    Connect {
+        listen_address: Option<SocketAddr>,
+        first_block: usize,
+    },
+    AcknowledgeConnection {
+        listen_address: Option<SocketAddr>,
+        first_block: usize,
+    },
     Ping {
         height: usize,
     },
@@ -30,19 +38,32 @@ pub enum Command {
         height: usize,
     },
     GetPeers,
+    /// Dialable peer addresses, at most `MAX_SEND_PEERS`
     SendPeers {
-        peers: Vec<String>,
+        peers: Vec<PeerAddress>,
     },
 
-    // Live
+    // Live, these are notifications and get no response
     NewBlock {
         block: Block,
     },
-    NewBlockResolved,
     NewTransaction {
         transaction: Transaction,
     },
-    NewTransactionResolved,
+    /// Announce transactions, at most `MAX_INV_SIZE`. Peers fetch the ones they don't know with `GetTransactions`
+    InvTransactions {
+        transaction_ids: Vec<TransactionId>,
+    },
+    /// Dandelion++ stem phase, relay to a single peer
+    StemTransaction {
+        transaction: Transaction,
+    },
+    GetTransactions {
+        transaction_ids: Vec<TransactionId>,
+    },
+    GetTransactionsResponse {
+        transactions: Vec<Transaction>,
+    },
 
     // Historical
     GetBlockMetadata {
@@ -57,6 +78,7 @@ pub enum Command {
     GetBlockResponse {
         block: Option<Block>,
     },
+    /// Request hashes for heights `start..end`. Pruned nodes answer nothing if `start` is below their first block
     GetBlockHashes {
         start: usize,
         end: usize,
@@ -64,6 +86,14 @@ pub enum Command {
     GetBlockHashesResponse {
         block_hashes: Vec<Hash>,
     },
+    /// Request headers for heights `start..end`, responses are capped at `MAX_HEADERS_PER_REQUEST`
+    GetHeaders {
+        start: usize,
+        end: usize,
+    },
+    GetHeadersResponse {
+        headers: Vec<BlockHeader>,
+    },
 
     // Light node
     GetTransactionMerkleProof {
@@ -75,6 +105,69 @@ pub enum Command {
     },
 }
 
+impl Command {
+    /// Name of this command, without its data
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Connect { .. } => "Connect",
+            Command::AcknowledgeConnection { .. } => "AcknowledgeConnection",
+            Command::Ping { .. } => "Ping",
+            Command::Pong { .. } => "Pong",
+            Command::GetPeers => "GetPeers",
+            Command::SendPeers { .. } => "SendPeers",
+            Command::NewBlock { .. } => "NewBlock",
+            Command::NewTransaction { .. } => "NewTransaction",
+            Command::InvTransactions { .. } => "InvTransactions",
+            Command::StemTransaction { .. } => "StemTransaction",
+            Command::GetTransactions { .. } => "GetTransactions",
+            Command::GetTransactionsResponse { .. } => "GetTransactionsResponse",
+            Command::GetBlockMetadata { .. } => "GetBlockMetadata",
+            Command::GetBlockMetadataResponse { .. } => "GetBlockMetadataResponse",
+            Command::GetBlock { .. } => "GetBlock",
+            Command::GetBlockResponse { .. } => "GetBlockResponse",
+            Command::GetBlockHashes { .. } => "GetBlockHashes",
+            Command::GetBlockHashesResponse { .. } => "GetBlockHashesResponse",
+            Command::GetHeaders { .. } => "GetHeaders",
+            Command::GetHeadersResponse { .. } => "GetHeadersResponse",
+            Command::GetTransactionMerkleProof { .. } => "GetTransactionMerkleProof",
+            Command::GetTransactionMerkleProofResponse { .. } => {
+                "GetTransactionMerkleProofResponse"
+            }
+        }
+    }
+
+    /// Returns true for one way commands, which are sent with `PeerHandle::send` and never get a response
+    pub fn is_notification(&self) -> bool {
+        matches!(
+            self,
+            Command::NewBlock { .. }
+                | Command::NewTransaction { .. }
+                | Command::InvTransactions { .. }
+                | Command::StemTransaction { .. }
+        )
+    }
+}
+
+/// Max size of an encoded command, bigger messages are refused
+pub const MAX_MESSAGE_SIZE: usize = 32 * 1024 * 1024;
+
+/// Max amount of headers sent in one `GetHeadersResponse`
+pub const MAX_HEADERS_PER_REQUEST: usize = 200;
+
+/// Max amount of addresses sent in one `SendPeers`
+pub const MAX_SEND_PEERS: usize = 250;
+
+/// Max amount of transaction ids in one `InvTransactions` or `GetTransactions`
+pub const MAX_INV_SIZE: usize = 1000;
+
+/// A peer address that other nodes can connect to
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq)]
+pub struct PeerAddress {
+    pub address: SocketAddr,
+    /// Unix timestamp of when this peer was last known to be alive
+    pub last_seen: u64,
+}
+
 #[derive(Error, Debug)]
 pub enum MessageError {
     #[error("Failed to encode command")]
@@ -91,6 +184,12 @@ pub enum MessageError {
 
     #[error("Received header version or size bytes length is not correct")]
     HeaderItemLength(#[from] TryFromSliceError),
+
+    #[error("Message of {0} bytes is bigger than the limit")]
+    Oversized(usize),
+
+    #[error("Peer speaks protocol version {0}, we speak {VERSION}")]
+    VersionMismatch(u16),
 }
 
 pub type MessageId = u32;
@@ -125,6 +224,9 @@ impl Message {
     pub fn serialize(&self) -> Result<Vec<u8>, MessageError> {
         // Serialize just the command to get its size
         let command_bytes = bincode::encode_to_vec(&self.command, bincode::config::standard())?;
+        if command_bytes.len() > MAX_MESSAGE_SIZE {
+            return Err(MessageError::Oversized(command_bytes.len()));
+        }
         let size: u32 = command_bytes.len() as u32;
 
         // Serialize the header first
@@ -141,18 +243,25 @@ impl Message {
         Ok(message_bytes)
     }
 
-    /// Send this message to a TcpStream (its owned write half)
-    pub async fn send(&self, stream: &mut OwnedWriteHalf) -> Result<(), MessageError> {
+    /// Send this message to a TcpStream (its owned write half), returns the amount of bytes sent
+    pub async fn send(&self, stream: &mut OwnedWriteHalf) -> Result<usize, MessageError> {
         let buf = self.serialize()?;
         if let Err(e) = stream.write_all(&buf).await {
             return Err(e.into());
         }
         // info!("TX: {:#?}", self.command);
-        Ok(())
+        Ok(buf.len())
     }
 
     /// Read a message from a TcpStream (its owned read half)
     pub async fn from_stream(stream: &mut OwnedReadHalf) -> Result<Self, MessageError> {
+        Ok(Self::from_stream_with_size(stream).await?.0)
+    }
+
+    /// Read a message from a TcpStream (its owned read half), also returning the amount of bytes read
+    pub async fn from_stream_with_size(
+        stream: &mut OwnedReadHalf,
+    ) -> Result<(Self, usize), MessageError> {
         let mut header_bytes = [0u8; 10];
         if stream.read_exact(&mut header_bytes).await? != 10 {
             return Err(MessageError::HeaderLength);
@@ -164,16 +273,26 @@ impl Message {
         let version = u16::from_be_bytes(version_bytes.try_into()?);
         let id = MessageId::from_be_bytes(id_bytes.try_into()?);
         let size = u32::from_be_bytes(size_bytes.try_into()?);
+        if size as usize > MAX_MESSAGE_SIZE {
+            return Err(MessageError::Oversized(size as usize));
+        }
+        // Commands of other versions may decode into something else entirely
+        if version != VERSION {
+            return Err(MessageError::VersionMismatch(version));
+        }
 
         let mut command_bytes = vec![0u8; size as usize];
         stream.read_exact(&mut command_bytes).await?;
 
         let command = bincode::decode_from_slice(&command_bytes, bincode::config::standard())?.0;
         // info!("RX: {:#?}", command);
-        Ok(Message {
-            command,
-            id,
-            version,
-        })
+        Ok((
+            Message {
+                command,
+                id,
+                version,
+            },
+            header_bytes.len() + command_bytes.len(),
+        ))
     }
 }
@@ -0,0 +1,69 @@
+use serde::{Deserialize, Serialize};
+
+use crate::core::{block::BlockError, blockchain::BlockchainError};
+
+/// Things a peer can do wrong, each adds its weight to the peers ban score
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Misbehavior {
+    /// Block or header hash does not meet its difficulty, or is not the real hash
+    InvalidPow,
+    /// Block breaks consensus rules
+    InvalidBlock,
+    /// Transaction breaks consensus rules
+    InvalidTransaction,
+    /// Message is bigger than `MAX_MESSAGE_SIZE`
+    OversizedMessage,
+    /// Got a response nobody asked for
+    UnsolicitedResponse,
+    /// Anything else that breaks the protocol
+    ProtocolViolation,
+    /// Did not answer a request in time
+    RequestTimeout,
+    /// Sent requests or notifications faster than we handle them
+    Flooding,
+}
+
+impl Misbehavior {
+    /// Ban score added per offence
+    pub fn weight(&self) -> u32 {
+        match self {
+            Misbehavior::InvalidPow => 100,
+            Misbehavior::InvalidBlock => 50,
+            Misbehavior::InvalidTransaction => 5,
+            Misbehavior::OversizedMessage => 100,
+            Misbehavior::UnsolicitedResponse => 20,
+            Misbehavior::ProtocolViolation => 50,
+            Misbehavior::RequestTimeout => 2,
+            Misbehavior::Flooding => 1,
+        }
+    }
+
+    /// Classify why a relayed block was rejected. Returns `None` for errors an honest peer can cause (forks, clock skew, local failures)
+    pub fn from_block_error(error: &BlockchainError) -> Option<Self> {
+        match error {
+            BlockchainError::Block(
+                BlockError::BlockPowDifficultyIncorrect | BlockError::InvalidBlockHash,
+            ) => Some(Misbehavior::InvalidPow),
+            BlockchainError::Io(_)
+            | BlockchainError::BincodeDecode(_)
+            | BlockchainError::BincodeEncode(_)
+            | BlockchainError::NoBlocksToPop
+            | BlockchainError::BlockNotFound
+            | BlockchainError::InvalidTimestamp
+            | BlockchainError::InvalidPreviousBlockHash
+            | BlockchainError::BlockStore(_)
+            | BlockchainError::UTXOs(_)
+            | BlockchainError::LiveTransactionDifficulty
+            | BlockchainError::ReindexIncomplete(_) => None,
+            _ => Some(Misbehavior::InvalidBlock),
+        }
+    }
+
+    /// Classify why a relayed transaction was rejected. Returns `None` for errors an honest peer can cause (mempool races, expiry, difficulty changes)
+    pub fn from_transaction_error(error: &BlockchainError) -> Option<Self> {
+        match error {
+            BlockchainError::InvalidTransaction(_) => Some(Misbehavior::InvalidTransaction),
+            _ => None,
+        }
+    }
+}
@@ -5,4 +5,13 @@ pub mod message;
 pub mod peer;
 
 /// A trait that handles what a peer does on message, and on kill
-pub mod peer_behavior;
\ No newline at end of file
+pub mod peer_behavior;
+
+/// Ways a peer can misbehave, and how much each one counts towards a ban
+pub mod misbehavior;
+
+/// Opens outbound connections, directly or through a SOCKS5 proxy
+pub mod connector;
+
+/// Cancels node daemons and disconnects peers on shutdown
+pub mod shutdown;
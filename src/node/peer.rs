@@ -1,42 +1,94 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
 
-use log::{error};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
 use tokio::{
     net::{
         TcpStream,
         tcp::{OwnedReadHalf, OwnedWriteHalf},
     },
     sync::{
-        Mutex,
-        mpsc::{self, Receiver},
+        Mutex, Semaphore,
+        mpsc::{self, Receiver, UnboundedReceiver, UnboundedSender, error::TrySendError},
         oneshot,
     },
+    task::JoinSet,
     time::{sleep, timeout},
 };
 
 use thiserror::Error;
 
 use crate::{
-    core::blockchain::BlockchainError, light_node::block_meta_store::BlockMetaStoreError, node::{
-        message::{Command, Message, MessageId},peer_behavior::SharedPeerBehavior
-    }
+    bounded_set::BoundedSet,
+    core::blockchain::BlockchainError,
+    light_node::block_meta_store::BlockMetaStoreError,
+    node::{
+        connector::Endpoint,
+        message::{Command, Message, MessageError, MessageId},
+        misbehavior::Misbehavior,
+        peer_behavior::SharedPeerBehavior,
+    },
 };
 
-/// Message expecting a response OR not
-pub enum Outgoing {
-    Request(Message, oneshot::Sender<Message>),
-    OneWay(Message),
-}
-
-type Pending = Arc<Mutex<HashMap<MessageId, oneshot::Sender<Message>>>>;
+type Pending = Arc<std::sync::Mutex<HashMap<MessageId, oneshot::Sender<Message>>>>;
 type KillSignal = String;
 
-/// Peer timeout, in seconds
+/// Default time a message may wait to be queued for sending, before the peer counts as dead
 pub const PEER_TIMEOUT: Duration = Duration::from_secs(5);
 
-/// Peer ping interval, in seconds
+/// Default peer ping interval
 pub const PEER_PING_INTERVAL: Duration = Duration::from_secs(5);
 
+/// Default time a peer gets to answer a request
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default max amount of requests waiting for a response from one peer
+pub const MAX_IN_FLIGHT_REQUESTS: usize = 32;
+
+/// Default max amount of requests from one peer that are handled at the same time
+pub const REQUEST_WORKERS: usize = 4;
+
+/// Max amount of inbound requests or notifications queued per peer. Messages that don't fit are dropped and count against the peer
+pub const INBOUND_QUEUE_SIZE: usize = 64;
+
+/// Amount of timed out request ids remembered per peer, so their late responses don't look unsolicited
+const EXPIRED_REQUEST_MEMORY: usize = 256;
+
+/// Per connection request settings
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct PeerConfig {
+    /// Time a peer gets to answer a request before it counts as timed out
+    pub request_timeout: Duration,
+    /// Max requests waiting for a response, further requests wait for a slot
+    pub max_in_flight_requests: usize,
+    /// Max requests from the peer handled at the same time. Notifications are always handled one by one, in order
+    pub request_workers: usize,
+    /// Max time a request waits to be queued for sending. A peer that can't keep up is killed
+    pub send_timeout: Duration,
+    /// How often the peer is pinged. Pings also tell the peer our height, which is what triggers syncs and reorgs
+    pub ping_interval: Duration,
+}
+
+impl Default for PeerConfig {
+    fn default() -> Self {
+        Self {
+            request_timeout: REQUEST_TIMEOUT,
+            max_in_flight_requests: MAX_IN_FLIGHT_REQUESTS,
+            request_workers: REQUEST_WORKERS,
+            send_timeout: PEER_TIMEOUT,
+            ping_interval: PEER_PING_INTERVAL,
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum PeerError {
     #[error("IO error: {0}")]
@@ -77,44 +129,300 @@ pub enum PeerError {
 
     #[error("Incorrect response received")]
     IncorrectResponse,
+
+    #[error("Connection limit reached: {0}")]
+    ConnectionLimit(String),
+
+    #[error("Peer is banned")]
+    Banned,
+
+    #[error("Request timed out")]
+    RequestTimeout,
+
+    #[error("Peer is dead")]
+    Dead,
+
+    #[error("Node is shutting down")]
+    ShuttingDown,
+}
+
+/// Traffic of one command type
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct CommandStats {
+    pub messages_in: u64,
+    pub bytes_in: u64,
+    pub messages_out: u64,
+    pub bytes_out: u64,
+}
+
+/// Connection statistics of a peer, used to decide which peers are worth keeping
+#[derive(Debug)]
+pub struct PeerStats {
+    /// Unix timestamp of when the connection was made
+    pub connected_at: u64,
+    /// Round trip time of the last ping in milliseconds, `u64::MAX` until the first pong
+    pub ping_rtt_ms: AtomicU64,
+    /// Last height this peer reported in a ping or pong, `u64::MAX` until it did
+    pub reported_height: AtomicU64,
+    /// Lowest block height the peer serves, from the handshake. Above 0 for pruned nodes
+    pub first_block: AtomicU64,
+    /// New blocks this peer sent us that we accepted
+    pub blocks_relayed: AtomicU64,
+    /// New transactions this peer sent us that we accepted
+    pub transactions_relayed: AtomicU64,
+    /// Requests this peer did not answer in time
+    pub timeouts: AtomicU64,
+    /// Traffic per command name
+    pub commands: std::sync::Mutex<HashMap<&'static str, CommandStats>>,
+}
+
+impl PeerStats {
+    pub fn new() -> Self {
+        Self {
+            connected_at: chrono::Utc::now().timestamp() as u64,
+            ping_rtt_ms: AtomicU64::new(u64::MAX),
+            reported_height: AtomicU64::new(u64::MAX),
+            first_block: AtomicU64::new(0),
+            blocks_relayed: AtomicU64::new(0),
+            transactions_relayed: AtomicU64::new(0),
+            timeouts: AtomicU64::new(0),
+            commands: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// How much useful data this peer relayed, a block counts as much as 10 transactions
+    pub fn relay_score(&self) -> u64 {
+        self.blocks_relayed
+            .load(Ordering::Relaxed)
+            .saturating_mul(10)
+            .saturating_add(self.transactions_relayed.load(Ordering::Relaxed))
+    }
+
+    /// Record a received message of `size` bytes
+    pub fn record_in(&self, command: &Command, size: usize) {
+        let mut commands = self.commands.lock().unwrap();
+        let stats = commands.entry(command.name()).or_default();
+        stats.messages_in += 1;
+        stats.bytes_in += size as u64;
+    }
+
+    /// Record a sent message of `size` bytes
+    pub fn record_out(&self, command: &Command, size: usize) {
+        let mut commands = self.commands.lock().unwrap();
+        let stats = commands.entry(command.name()).or_default();
+        stats.messages_out += 1;
+        stats.bytes_out += size as u64;
+    }
+}
+
+impl Default for PeerStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Everything we know about a connected peer
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PeerInfo {
+    pub address: SocketAddr,
+    /// What we connected to, the ip and port of `address` for inbound peers
+    pub endpoint: Endpoint,
+    pub listen_address: Option<SocketAddr>,
+    /// True if the peer connected to us
+    pub inbound: bool,
+    pub connected_at: u64,
+    pub ping_rtt_ms: Option<u64>,
+    pub reported_height: Option<usize>,
+    /// Lowest block height the peer serves, above 0 for pruned nodes
+    pub first_block: usize,
+    pub blocks_relayed: u64,
+    pub transactions_relayed: u64,
+    pub timeouts: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub messages_in: u64,
+    pub messages_out: u64,
+    /// Traffic per command name
+    pub commands: BTreeMap<String, CommandStats>,
+    pub ban_score: u32,
 }
 
 /// Used to reference, request, and kill
 #[derive(Clone, Debug)]
 pub struct PeerHandle {
+    /// Address this peer is known by, made up for host endpoints (see `Endpoint::socket_addr`)
     pub address: SocketAddr,
+    pub endpoint: Endpoint,
     pub is_client: bool,
-    send: mpsc::Sender<Outgoing>,
+    send: mpsc::Sender<Message>,
     kill: Arc<Mutex<Option<oneshot::Sender<KillSignal>>>>,
+    listen_address: Arc<std::sync::RwLock<Option<SocketAddr>>>,
+    pub stats: Arc<PeerStats>,
+    config: PeerConfig,
+    pending: Pending,
+    /// Ids of requests that timed out
+    expired: Arc<std::sync::Mutex<BoundedSet<MessageId>>>,
+    /// One permit per request slot
+    in_flight: Arc<Semaphore>,
+    misbehavior: UnboundedSender<Misbehavior>,
+}
+
+/// Removes a pending request that was given up on (timed out or cancelled), and remembers its id
+struct PendingGuard<'a> {
+    handle: &'a PeerHandle,
+    id: MessageId,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        // Answered requests were already removed by the reader
+        if self
+            .handle
+            .pending
+            .lock()
+            .unwrap()
+            .remove(&self.id)
+            .is_some()
+        {
+            self.handle.expired.lock().unwrap().insert(self.id);
+        }
+    }
 }
 
 impl PeerHandle {
-    /// Send a request message, and expect a response message from this peer
-    pub async fn request(&self, request: Message) -> Result<Message, PeerError> {
-        let (callback_tx, callback_rx) = oneshot::channel::<Message>();
+    /// Address this peer accepts connections on. Always known for peers we connected to, inbound peers have to advertise it in the handshake
+    pub fn listen_address(&self) -> Option<SocketAddr> {
+        *self.listen_address.read().unwrap()
+    }
 
-        match timeout(
-            PEER_TIMEOUT,
-            self.send.send(Outgoing::Request(request, callback_tx)),
-        )
-        .await
+    /// Set the address this peer advertised. An unspecified ip is replaced with the ip the peer is connected from
+    pub fn set_listen_address(&self, mut advertised: SocketAddr) {
+        if advertised.ip().is_unspecified() {
+            advertised.set_ip(self.address.ip());
+        }
+        *self.listen_address.write().unwrap() = Some(advertised);
+    }
+
+    /// Lowest block height this peer serves, blocks below it were pruned
+    pub fn first_block(&self) -> usize {
+        self.stats.first_block.load(Ordering::Relaxed) as usize
+    }
+
+    /// Set the lowest block height this peer advertised in the handshake
+    pub fn set_first_block(&self, first_block: usize) {
+        self.stats
+            .first_block
+            .store(first_block as u64, Ordering::Relaxed);
+    }
+
+    /// Snapshot the stats of this peer
+    pub fn info(&self, ban_score: u32) -> PeerInfo {
+        let stats = &self.stats;
+        let commands: BTreeMap<String, CommandStats> = stats
+            .commands
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, stats)| (name.to_string(), *stats))
+            .collect();
+        let known = |value: u64| (value != u64::MAX).then_some(value);
+
+        PeerInfo {
+            address: self.address,
+            endpoint: self.endpoint.clone(),
+            listen_address: self.listen_address(),
+            inbound: self.is_client,
+            connected_at: stats.connected_at,
+            ping_rtt_ms: known(stats.ping_rtt_ms.load(Ordering::Relaxed)),
+            reported_height: known(stats.reported_height.load(Ordering::Relaxed))
+                .map(|height| height as usize),
+            first_block: self.first_block(),
+            blocks_relayed: stats.blocks_relayed.load(Ordering::Relaxed),
+            transactions_relayed: stats.transactions_relayed.load(Ordering::Relaxed),
+            timeouts: stats.timeouts.load(Ordering::Relaxed),
+            bytes_in: commands.values().map(|command| command.bytes_in).sum(),
+            bytes_out: commands.values().map(|command| command.bytes_out).sum(),
+            messages_in: commands.values().map(|command| command.messages_in).sum(),
+            messages_out: commands.values().map(|command| command.messages_out).sum(),
+            commands,
+            ban_score,
+        }
+    }
+
+    /// Do the connection handshake with a peer we connected to, advertising our own `listen_address` and `first_block`
+    pub async fn handshake(
+        &self,
+        listen_address: Option<SocketAddr>,
+        first_block: usize,
+    ) -> Result<(), PeerError> {
+        match self
+            .request(Message::new(Command::Connect {
+                listen_address,
+                first_block,
+            }))
+            .await?
+            .command
         {
-            Ok(res) => res.map_err(|e| PeerError::SendError(e.to_string()))?,
+            Command::AcknowledgeConnection { first_block, .. } => {
+                self.set_first_block(first_block);
+                Ok(())
+            }
+            _ => Err(PeerError::IncorrectResponse),
+        }
+    }
+
+    /// Send a request message, and expect a response message from this peer within the configured request timeout
+    pub async fn request(&self, request: Message) -> Result<Message, PeerError> {
+        self.request_with_timeout(request, self.config.request_timeout)
+            .await
+    }
+
+    /// Send a request message, and expect a response message from this peer within `deadline`.
+    /// Waits for a free request slot first if this peer already has the max amount of requests in flight
+    pub async fn request_with_timeout(
+        &self,
+        request: Message,
+        deadline: Duration,
+    ) -> Result<Message, PeerError> {
+        let _permit = timeout(deadline, self.in_flight.acquire())
+            .await
+            .map_err(|_| PeerError::RequestTimeout)?
+            .map_err(|_| PeerError::Dead)?;
+
+        let (callback_tx, callback_rx) = oneshot::channel::<Message>();
+        self.pending.lock().unwrap().insert(request.id, callback_tx);
+        let _guard = PendingGuard {
+            handle: self,
+            id: request.id,
+        };
+
+        match timeout(self.config.send_timeout, self.send.send(request)).await {
+            Ok(res) => res.map_err(|_| PeerError::Dead)?,
             Err(_) => {
                 self.kill("Peer timed out".to_string()).await?;
                 return Err(PeerError::Timeout);
             }
         }
 
-        callback_rx
-            .await
-            .map_err(|e| PeerError::ReceiveError(e.to_string()))
+        match timeout(deadline, callback_rx).await {
+            Ok(response) => response.map_err(|_| PeerError::Dead),
+            Err(_) => {
+                self.stats.timeouts.fetch_add(1, Ordering::Relaxed);
+                let _ = self.misbehavior.send(Misbehavior::RequestTimeout);
+                Err(PeerError::RequestTimeout)
+            }
+        }
+    }
+
+    /// Amount of requests currently waiting for a response
+    pub fn in_flight_requests(&self) -> usize {
+        self.pending.lock().unwrap().len()
     }
 
-    /// Send a message without expecting a response
+    /// Send a message without expecting a response. Used for responses and notifications
     pub async fn send(&self, message: Message) -> Result<(), PeerError> {
         self.send
-            .send(Outgoing::OneWay(message))
+            .send(message)
             .await
             .map_err(|e| PeerError::SendError(e.to_string()))
     }
@@ -129,24 +437,64 @@ impl PeerHandle {
     }
 }
 
-/// Create a new peer, start internal tasks, and return a PeerHandle
+/// Create a new peer with the default `PeerConfig`, start internal tasks, and return a PeerHandle
 pub fn create_peer(
     stream: TcpStream,
     behavior: SharedPeerBehavior,
     is_client: bool,
+) -> Result<PeerHandle, PeerError> {
+    create_peer_with_config(stream, behavior, is_client, PeerConfig::default())
+}
+
+/// Create a new peer, start internal tasks, and return a PeerHandle
+pub fn create_peer_with_config(
+    stream: TcpStream,
+    behavior: SharedPeerBehavior,
+    is_client: bool,
+    config: PeerConfig,
 ) -> Result<PeerHandle, PeerError> {
     let address = stream
         .peer_addr()
         .map_err(|e| PeerError::Io(format!("IO error: {e}")))?;
+    create_peer_with_endpoint(stream, address.into(), behavior, is_client, config)
+}
 
-    let (outgoing_tx, outgoing_rx) = mpsc::channel::<Outgoing>(64);
+/// Create a new peer for a stream connected to `endpoint` (possibly through a proxy), start internal tasks, and return a PeerHandle
+pub fn create_peer_with_endpoint(
+    stream: TcpStream,
+    endpoint: Endpoint,
+    behavior: SharedPeerBehavior,
+    is_client: bool,
+    config: PeerConfig,
+) -> Result<PeerHandle, PeerError> {
+    let address = endpoint.socket_addr();
+    // We know we can reach peers we connected to, other nodes can only use it if it is an ip
+    let listen_address = match endpoint {
+        Endpoint::Ip(address) if !is_client => Some(address),
+        _ => None,
+    };
+
+    let (outgoing_tx, outgoing_rx) = mpsc::channel::<Message>(64);
     let (kill, should_kill) = oneshot::channel::<KillSignal>();
+    let (misbehavior_tx, misbehavior_rx) = mpsc::unbounded_channel::<Misbehavior>();
+    let (requests_tx, requests_rx) = mpsc::channel::<Message>(INBOUND_QUEUE_SIZE);
+    let (notifications_tx, notifications_rx) = mpsc::channel::<Message>(INBOUND_QUEUE_SIZE);
 
     let handle = PeerHandle {
         send: outgoing_tx,
         kill: Arc::new(Mutex::new(Some(kill))),
         is_client,
         address,
+        endpoint,
+        listen_address: Arc::new(std::sync::RwLock::new(listen_address)),
+        stats: Arc::new(PeerStats::new()),
+        config,
+        pending: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        expired: Arc::new(std::sync::Mutex::new(BoundedSet::new(
+            EXPIRED_REQUEST_MEMORY,
+        ))),
+        in_flight: Arc::new(Semaphore::new(config.max_in_flight_requests.max(1))),
+        misbehavior: misbehavior_tx,
     };
     let my_handle = handle.clone();
 
@@ -156,20 +504,25 @@ pub fn create_peer(
         if let Err(e) = async move {
             let (reader, writer) = stream.into_split();
 
-            let pending: Pending =
-                Arc::new(Mutex::new(HashMap::<MessageId, oneshot::Sender<Message>>::new()));
-
-            tokio::select! {
-                res = reader_task(reader, pending.clone(), my_handle.clone(), behavior.clone()) => res,
-                res = writer_task(writer, outgoing_rx, pending) => res,
-                res = pinger_task(my_handle, behavior.clone()) => res,
+            let res = tokio::select! {
+                res = reader_task(reader, my_handle.clone(), behavior.clone(), requests_tx, notifications_tx) => res,
+                res = worker_pool(requests_rx, config.request_workers.max(1), my_handle.clone(), behavior.clone()) => res,
+                res = worker_pool(notifications_rx, 1, my_handle.clone(), behavior.clone()) => res,
+                res = writer_task(writer, outgoing_rx, my_handle.stats.clone()) => res,
+                res = pinger_task(my_handle.clone(), behavior.clone()) => res,
+                res = misbehavior_task(misbehavior_rx, my_handle.clone(), behavior.clone()) => res,
                 res = async move {
                     let message = should_kill
                         .await
                         .map_err(|_| PeerError::Killed("Kill channel closed".to_string()))?;
                     Err(PeerError::Killed(message))
                 } => res
-            }?;
+            };
+
+            // Fail everything still waiting on this peer
+            my_handle.in_flight.close();
+            my_handle.pending.lock().unwrap().clear();
+            res?;
 
             Ok::<(), PeerError>(())
         }
@@ -179,67 +532,150 @@ pub fn create_peer(
                 behavior_on_kill.on_kill(&my_handle_on_kill).await;
                 error!("Peer error (disconnected): {e}");
             });
-            
         }
     });
 
     Ok(handle)
 }
 
+/// Reads messages from the peer. Responses are delivered right here, requests and notifications are queued for the workers, so a slow handler never holds up responses
 async fn reader_task(
     mut stream: OwnedReadHalf,
-    pending: Pending,
     my_handle: PeerHandle,
-    behavior: SharedPeerBehavior
+    behavior: SharedPeerBehavior,
+    requests: mpsc::Sender<Message>,
+    notifications: mpsc::Sender<Message>,
 ) -> Result<(), PeerError> {
     loop {
-        let message = Message::from_stream(&mut stream)
-            .await
-            .map_err(|e| PeerError::MessageDecode(e.to_string()))?;
+        let message = match Message::from_stream_with_size(&mut stream).await {
+            Ok((message, size)) => {
+                my_handle.stats.record_in(&message.command, size);
+                if let Command::Ping { height } | Command::Pong { height } = message.command {
+                    my_handle
+                        .stats
+                        .reported_height
+                        .store(height as u64, Ordering::Relaxed);
+                }
+                message
+            }
+            Err(e) => {
+                if let MessageError::Oversized(_) = e {
+                    behavior
+                        .on_misbehavior(&my_handle, Misbehavior::OversizedMessage)
+                        .await;
+                }
+                return Err(PeerError::MessageDecode(e.to_string()));
+            }
+        };
 
-        if let Some(requester) = pending.lock().await.remove(&message.id) {
+        let requester = my_handle.pending.lock().unwrap().remove(&message.id);
+        if let Some(requester) = requester {
             let _ = requester.send(message);
+        } else if my_handle.expired.lock().unwrap().contains(&message.id) {
+            // Late response to a request that timed out, already counted against the peer
+            continue;
         } else {
-            let response = behavior.on_message(message, &my_handle).await?;
-            my_handle.send(response).await?;
+            let queue = if message.command.is_notification() {
+                &notifications
+            } else {
+                &requests
+            };
+            // Never wait on a full queue, that would stop us from reading responses too
+            match queue.try_send(message) {
+                Ok(()) => {}
+                Err(TrySendError::Full(message)) => {
+                    warn!(
+                        "Inbound queue of peer {} is full, dropping {}",
+                        my_handle.address,
+                        message.command.name()
+                    );
+                    let _ = my_handle.misbehavior.send(Misbehavior::Flooding);
+                }
+                Err(TrySendError::Closed(_)) => return Err(PeerError::Dead),
+            }
         }
     }
 }
 
-async fn writer_task(
-    mut stream: OwnedWriteHalf,
-    mut receiver: Receiver<Outgoing>,
-    pending: Pending,
+/// Handle queued inbound messages, at most `workers` at the same time. Handlers run in their own tasks, so heavy validation doesn't stall this peers reader
+async fn worker_pool(
+    mut receiver: Receiver<Message>,
+    workers: usize,
+    my_handle: PeerHandle,
+    behavior: SharedPeerBehavior,
 ) -> Result<(), PeerError> {
-    while let Some(outgoing) = receiver.recv().await {
-        match outgoing {
-            Outgoing::Request(msg, responder) => {
-                pending.lock().await.insert(msg.id, responder);
-                msg.send(&mut stream)
-                    .await
-                    .map_err(|e| PeerError::MessageEncode(e.to_string()))?;
+    let mut running = RunningHandlers(JoinSet::new());
+    let running = &mut running.0;
+    loop {
+        tokio::select! {
+            Some(res) = running.join_next() => {
+                res.map_err(|e| PeerError::Unknown(e.to_string()))??;
             }
-            Outgoing::OneWay(msg) => {
-                msg.send(&mut stream)
-                    .await
-                    .map_err(|e| PeerError::MessageEncode(e.to_string()))?;
+            message = receiver.recv(), if running.len() < workers => {
+                let Some(message) = message else {
+                    return Err(PeerError::Dead);
+                };
+                let (my_handle, behavior) = (my_handle.clone(), behavior.clone());
+                running.spawn(async move {
+                    if message.command.is_notification() {
+                        behavior.on_notification(message, &my_handle).await
+                    } else {
+                        let response = behavior.on_message(message, &my_handle).await?;
+                        my_handle.send(response).await
+                    }
+                });
             }
         }
     }
+}
+
+/// Handlers of a worker pool. When the peer dies they are detached instead of aborted, so one can't stop halfway through accepting a block
+struct RunningHandlers(JoinSet<Result<(), PeerError>>);
+
+impl Drop for RunningHandlers {
+    fn drop(&mut self) {
+        self.0.detach_all();
+    }
+}
+
+async fn writer_task(
+    mut stream: OwnedWriteHalf,
+    mut receiver: Receiver<Message>,
+    stats: Arc<PeerStats>,
+) -> Result<(), PeerError> {
+    while let Some(msg) = receiver.recv().await {
+        let size = msg
+            .send(&mut stream)
+            .await
+            .map_err(|e| PeerError::MessageEncode(e.to_string()))?;
+        stats.record_out(&msg.command, size);
+    }
     Err(PeerError::Disconnected)
 }
 
-async fn pinger_task(
+async fn pinger_task(my_handle: PeerHandle, behavior: SharedPeerBehavior) -> Result<(), PeerError> {
+    loop {
+        sleep(my_handle.config.ping_interval).await;
+        let height = behavior.get_height().await;
+        let sent_at = Instant::now();
+        my_handle
+            .request(Message::new(Command::Ping { height }))
+            .await?;
+        my_handle
+            .stats
+            .ping_rtt_ms
+            .store(sent_at.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Report misbehavior noticed by the handle itself (like request timeouts) to the peer behavior
+async fn misbehavior_task(
+    mut receiver: UnboundedReceiver<Misbehavior>,
     my_handle: PeerHandle,
-    behavior: SharedPeerBehavior
+    behavior: SharedPeerBehavior,
 ) -> Result<(), PeerError> {
-    loop {
-        sleep(PEER_PING_INTERVAL).await;
-        my_handle.request(
-            Message::new(Command::Ping {
-                height: behavior.get_height().await,
-            }),
-        )
-        .await?;
+    while let Some(misbehavior) = receiver.recv().await {
+        behavior.on_misbehavior(&my_handle, misbehavior).await;
     }
+    Err(PeerError::Dead)
 }
@@ -1,6 +1,10 @@
 use std::sync::Arc;
 
-use crate::node::{message::Message, peer::{PeerError, PeerHandle}};
+use crate::node::{
+    message::Message,
+    misbehavior::Misbehavior,
+    peer::{PeerError, PeerHandle},
+};
 
 pub type SharedPeerBehavior = Arc<dyn PeerBehavior + Send + Sync>;
 
@@ -9,9 +13,15 @@ pub trait PeerBehavior {
     /// Handles what this peer does when it receives a message, and creates this peers response
     async fn on_message(&self, message: Message, peer: &PeerHandle) -> Result<Message, PeerError>;
 
+    /// Handles a one way notification (see `Command::is_notification`), these never get a response
+    async fn on_notification(&self, message: Message, peer: &PeerHandle) -> Result<(), PeerError>;
+
     /// Handles what happens when this peer gets killed (apart from peer process' being killed)
     async fn on_kill(&self, peer: &PeerHandle);
 
+    /// Handles what happens when this peer misbehaves on the protocol level (before `on_message`)
+    async fn on_misbehavior(&self, peer: &PeerHandle, misbehavior: Misbehavior);
+
     /// Return current blockchain height
     async fn get_height(&self) -> usize;
 }
\ No newline at end of file
@@ -0,0 +1,87 @@
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
+
+use log::warn;
+use tokio::{
+    sync::{RwLock, watch},
+    task::JoinHandle,
+    time::{Instant, sleep, timeout},
+};
+
+use crate::node::peer::PeerHandle;
+
+/// Max time a shutdown waits for daemons to stop, and for peers to disconnect
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Tells the daemons of a node to stop, and lets shutdown wait until they did
+pub struct Shutdown {
+    signal: watch::Sender<bool>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self {
+            signal: watch::channel(false).0,
+        }
+    }
+
+    /// Spawn a daemon. It is cancelled at its next await point once shutdown starts
+    pub fn spawn<F>(&self, daemon: F) -> JoinHandle<()>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let mut signal = self.signal.subscribe();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = daemon => {}
+                _ = signal.wait_for(|stopped| *stopped) => {}
+            }
+        })
+    }
+
+    /// Start shutting down, every daemon gets cancelled
+    pub fn trigger(&self) {
+        self.signal.send_replace(true);
+    }
+
+    /// Returns true once shutdown started
+    pub fn is_shutting_down(&self) -> bool {
+        *self.signal.borrow()
+    }
+
+    /// Wait until shutdown starts
+    pub async fn triggered(&self) {
+        let _ = self.signal.subscribe().wait_for(|stopped| *stopped).await;
+    }
+
+    /// Wait until every daemon stopped, returns false if some are still running after `SHUTDOWN_TIMEOUT`
+    pub async fn wait_for_daemons(&self) -> bool {
+        timeout(SHUTDOWN_TIMEOUT, self.signal.closed())
+            .await
+            .is_ok()
+    }
+}
+
+/// Kill every peer in `peers`, and wait until they are gone
+pub async fn disconnect_peers(peers: &RwLock<HashMap<SocketAddr, PeerHandle>>) {
+    let connected: Vec<PeerHandle> = peers.read().await.values().cloned().collect();
+    for peer in connected {
+        let _ = peer.kill("Node is shutting down".to_string()).await;
+    }
+
+    // Killed peers remove themselves in `on_kill`
+    let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+    while !peers.read().await.is_empty() {
+        if Instant::now() >= deadline {
+            warn!("Peers did not disconnect in time, dropping them");
+            break;
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+    peers.write().await.clear();
+}
@@ -7,12 +7,16 @@ use rand::random;
 use crate::{
     build_block, build_transaction,
     core::{
-        blockchain::Blockchain,
-        difficulty::{STARTING_BLOCK_DIFFICULTY, STARTING_TX_DIFFICULTY},
-        transaction::TransactionOutput,
+        blockchain::{Blockchain, BlockchainError, validate_header},
+        difficulty::{
+            DifficultyState, STARTING_BLOCK_DIFFICULTY, STARTING_TX_DIFFICULTY,
+            calculate_live_transaction_difficulty,
+        },
+        transaction::{Transaction, TransactionOutput},
     },
     crypto::{address_inclusion_filter::AddressInclusionFilter, keys::Private},
-    full_node::mempool::MemPool,
+    economics::GENESIS_PREVIOUS_BLOCK_HASH,
+    full_node::mempool::{MemPool, transaction_size},
 };
 
 fn new_tmp_blockchain() -> Blockchain {
@@ -23,6 +27,23 @@ fn new_tmp_blockchain() -> Blockchain {
     Blockchain::new(&bc_path)
 }
 
+/// Mine a block for each of `n` new senders, and build a transaction spending each reward. None of them conflict
+async fn funded_transactions(bc: &Blockchain, n: usize) -> Result<Vec<Transaction>, anyhow::Error> {
+    let mut txs = vec![];
+    for _ in 0..n {
+        let private = Private::new_random();
+        let mut block = build_block(bc, &vec![], private.to_public()).await?;
+        #[allow(deprecated)]
+        block.compute_pow()?;
+        bc.add_block(block, false)?;
+        let mut tx =
+            build_transaction(bc, private, vec![(private.to_public(), 100)], &vec![]).await?;
+        tx.compute_pow(&bc.get_transaction_difficulty(), None)?;
+        txs.push(tx);
+    }
+    Ok(txs)
+}
+
 #[tokio::test]
 async fn test_blockchain_rollback() -> Result<(), anyhow::Error> {
     const TEST_HEIGHT: usize = 5;
@@ -226,3 +247,254 @@ async fn test_mempool() -> Result<(), anyhow::Error> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_mempool_index() -> Result<(), anyhow::Error> {
+    let private = Private::new_random();
+    let public = private.to_public();
+
+    let bc = new_tmp_blockchain();
+    let mempool = MemPool::new();
+    let mut block = build_block(&bc, &vec![], public).await?;
+    #[allow(deprecated)]
+    block.compute_pow()?;
+    bc.add_block(block, false)?;
+
+    let mut tx = build_transaction(&bc, private, vec![(public, 100)], &vec![]).await?;
+    tx.compute_pow(&bc.get_transaction_difficulty(), None)?;
+    let tx_id = tx.transaction_id.unwrap();
+    let mut double_spend = build_transaction(&bc, private, vec![(public, 50)], &vec![]).await?;
+    double_spend.compute_pow(&bc.get_transaction_difficulty(), None)?;
+
+    mempool.add_transaction(tx.clone()).await;
+    mempool.add_transaction(tx.clone()).await;
+    assert_eq!(
+        mempool.mempool_size().await,
+        1,
+        "Duplicate transaction added"
+    );
+    assert!(mempool.contains(&tx_id).await);
+    assert_eq!(
+        mempool.get(&tx_id).await.and_then(|tx| tx.transaction_id),
+        Some(tx_id)
+    );
+    assert_eq!(mempool.conflicts_with(&double_spend).await, vec![tx_id]);
+    assert!(matches!(
+        mempool.try_add_transaction(double_spend.clone()).await,
+        Err(BlockchainError::DoubleSpend)
+    ));
+    assert_eq!(mempool.mempool_size().await, 1);
+
+    // Spending frees the outputs it used
+    mempool.spend_transactions(vec![tx_id]).await;
+    assert!(!mempool.contains(&tx_id).await);
+    assert!(mempool.get_mempool().await.is_empty());
+    assert!(mempool.conflicts_with(&double_spend).await.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_mempool_eviction() -> Result<(), anyhow::Error> {
+    let bc = new_tmp_blockchain();
+    let mempool = MemPool::new();
+
+    let mut txs = funded_transactions(&bc, 3).await?;
+    txs.sort_by_key(|tx| tx.transaction_id.unwrap().dump_buf());
+    let [strong, medium, weak]: [Transaction; 3] = txs.try_into().unwrap();
+
+    mempool.config.write().unwrap().max_transactions = 2;
+    assert!(
+        mempool
+            .try_add_transaction(medium.clone())
+            .await?
+            .is_empty()
+    );
+    assert!(mempool.try_add_transaction(weak.clone()).await?.is_empty());
+    assert_eq!(
+        mempool.mempool_bytes().await,
+        transaction_size(&medium) + transaction_size(&weak)
+    );
+
+    // A stronger pow pushes out the weakest, a weaker one is turned away
+    assert_eq!(
+        mempool.try_add_transaction(strong.clone()).await?,
+        vec![weak.transaction_id.unwrap()]
+    );
+    assert!(matches!(
+        mempool.try_add_transaction(weak).await,
+        Err(BlockchainError::MemPoolFull)
+    ));
+    assert_eq!(mempool.mempool_size().await, 2);
+
+    // Nothing gets evicted for a transaction that can't fit on its own
+    mempool.clear().await;
+    mempool.config.write().unwrap().max_bytes = transaction_size(&strong) - 1;
+    assert!(matches!(
+        mempool.try_add_transaction(strong).await,
+        Err(BlockchainError::MemPoolFull)
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_mempool_info() -> Result<(), anyhow::Error> {
+    let bc = new_tmp_blockchain();
+    let mempool = MemPool::new();
+    let difficulty = bc.get_transaction_difficulty();
+
+    let info = mempool.info(difficulty).await;
+    assert_eq!(info.transactions, 0);
+    assert!(info.oldest.is_none());
+    assert_eq!(info.expiry_histogram.iter().sum::<usize>(), 0);
+    assert_eq!(info.live_difficulty, difficulty);
+    assert_eq!(info.expected_wait, 1);
+
+    let txs = funded_transactions(&bc, 2).await?;
+    let before = chrono::Utc::now().timestamp() as u64;
+    for tx in &txs {
+        mempool.add_transaction(tx.clone()).await;
+    }
+
+    let info = mempool.info(difficulty).await;
+    assert_eq!(info.transactions, 2);
+    assert_eq!(
+        info.bytes,
+        transaction_size(&txs[0]) + transaction_size(&txs[1])
+    );
+    let (oldest, entered) = info.oldest.unwrap();
+    assert_eq!(oldest, txs[0].transaction_id.unwrap());
+    assert!(entered >= before);
+    assert_eq!(info.newest.unwrap().0, txs[1].transaction_id.unwrap());
+    // Both just got in, so they expire in the last window
+    assert_eq!(info.expiry_histogram.last(), Some(&2));
+    assert_eq!(
+        info.live_difficulty,
+        calculate_live_transaction_difficulty(&difficulty, 2)
+    );
+
+    // A transaction expiring early (like one readmitted after a reorg) still counts by when it got in
+    let late = funded_transactions(&bc, 1).await?.remove(0);
+    let before = chrono::Utc::now().timestamp() as u64;
+    mempool
+        .try_add_transaction_expiring(late.clone(), before + 1)
+        .await?;
+    let info = mempool.info(difficulty).await;
+    assert_eq!(info.oldest.unwrap().0, txs[0].transaction_id.unwrap());
+    let (newest, entered) = info.newest.unwrap();
+    assert_eq!(newest, late.transaction_id.unwrap());
+    assert!(entered >= before);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_header_chain_validation() -> Result<(), anyhow::Error> {
+    let private = Private::new_random();
+    let public = private.to_public();
+    let bc = new_tmp_blockchain();
+
+    let mut headers = vec![];
+    for _ in 0..3 {
+        let mut block = build_block(&bc, &vec![], public).await?;
+        #[allow(deprecated)]
+        block.compute_pow()?;
+        let header = block.to_header()?;
+        assert_eq!(
+            header.get_hashing_buf()?,
+            block.get_hashing_buf()?,
+            "Header hashing buffer does not match its blocks"
+        );
+        headers.push(header);
+        bc.add_block(block, false)?;
+    }
+
+    // A fresh difficulty state must follow the chain purely from headers
+    let difficulty_state = DifficultyState::new_default();
+    let mut previous_block = GENESIS_PREVIOUS_BLOCK_HASH;
+    for header in &headers {
+        validate_header(header, previous_block, &difficulty_state)?;
+        previous_block = header.meta.hash.unwrap();
+    }
+    assert_eq!(
+        difficulty_state.get_block_difficulty(),
+        bc.get_block_difficulty(),
+        "Header difficulty tracking diverged from blockchain"
+    );
+
+    // Broken links and tampered headers must be rejected
+    let difficulty_state = DifficultyState::new_default();
+    assert!(
+        validate_header(&headers[1], GENESIS_PREVIOUS_BLOCK_HASH, &difficulty_state).is_err(),
+        "Header with wrong previous block passed"
+    );
+    let mut tampered = headers[0].clone();
+    tampered.nonce = tampered.nonce.wrapping_add(1);
+    assert!(
+        validate_header(&tampered, GENESIS_PREVIOUS_BLOCK_HASH, &difficulty_state).is_err(),
+        "Tampered header passed"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reindex() -> Result<(), anyhow::Error> {
+    let bc_path = "/tmp/bc-".to_string() + &(random::<u64>()).to_string();
+    let bc = Blockchain::new(&bc_path);
+    let miner = Private::new_random();
+    for _ in 0..3 {
+        let mut new_block = build_block(&bc, &vec![], miner.to_public()).await?;
+        #[allow(deprecated)]
+        new_block.compute_pow()?;
+        bc.add_block(new_block, false)?;
+    }
+    let tip = bc.block_store().get_last_block_hash();
+    let utxos = bc.get_utxos().get_all_utxos();
+    let difficulties = (bc.get_block_difficulty(), bc.get_transaction_difficulty());
+    drop(bc);
+
+    // Lose the block index, the blocks themselves are enough to rebuild everything
+    fs::remove_file(format!("{bc_path}/blockchain/blockchain.dat"))?;
+    let bc = Blockchain::reindex(&bc_path)?;
+    assert_eq!(bc.block_store().get_height(), 3);
+    assert_eq!(bc.block_store().get_last_block_hash(), tip);
+    assert_eq!(bc.block_store().get_block_height_by_hash(tip), Some(2));
+    assert_eq!(bc.get_utxos().get_all_utxos(), utxos);
+    assert_eq!(
+        (bc.get_block_difficulty(), bc.get_transaction_difficulty()),
+        difficulties
+    );
+    assert!(!fs::exists(format!("{bc_path}/blockchain.reindex"))?);
+    drop(bc);
+
+    // Reopening gives the reindexed chain
+    assert_eq!(Blockchain::new(&bc_path).block_store().get_height(), 3);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reindex_incomplete() -> Result<(), anyhow::Error> {
+    let bc_path = "/tmp/bc-".to_string() + &(random::<u64>()).to_string();
+    let bc = Blockchain::new(&bc_path);
+    let miner = Private::new_random();
+    for _ in 0..3 {
+        let mut new_block = build_block(&bc, &vec![], miner.to_public()).await?;
+        #[allow(deprecated)]
+        new_block.compute_pow()?;
+        bc.add_block(new_block, false)?;
+    }
+    drop(bc);
+
+    // A corrupt block in the middle stops the replay, the blocks after it must not be thrown away
+    fs::write(format!("{bc_path}/blockchain/blocks/1.dat"), b"corrupt")?;
+    assert!(matches!(
+        Blockchain::reindex(&bc_path),
+        Err(BlockchainError::ReindexIncomplete(1))
+    ));
+    assert!(fs::exists(format!(
+        "{bc_path}/blockchain.reindex/blocks/2.dat"
+    ))?);
+    Ok(())
+}
@@ -1,18 +1,65 @@
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use num_bigint::BigUint;
 use rand::random;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::sleep,
+};
 
 use crate::{
     api::{api_server::Server, client::Client},
+    blockchain_data_provider::BlockchainDataProvider,
     build_block, build_transaction,
-    crypto::keys::Private,
-    full_node::{SharedBlockchain, accept_block, accept_transaction, create_full_node, node_state::SharedNodeState},
+    config::{NodeBuilder, NodeConfig},
+    core::{
+        blockchain::{Blockchain, BlockchainError},
+        transaction::{Transaction, TransactionError, TransactionId},
+    },
+    crypto::{
+        Hash, RandomXMode,
+        keys::{Private, Public},
+    },
+    full_node::{
+        NodeHandle, SharedBlockchain, accept_block, accept_stem_transaction, accept_transaction,
+        address_book::{AddressBook, AddressSource, BUCKET_SIZE, subnet_group},
+        auto_peer::{TARGET_PEERS, start_auto_peer},
+        ban_list::{BanList, SCORE_DECAY},
+        block_template::build_block_template,
+        bootstrap::{BootstrapConfig, MAX_SEED_FILE_SIZE, parse_seed_list},
+        connect_peer, create_full_node,
+        ibd::{IbdPhase, ibd_blockchain},
+        localnet::Localnet,
+        mempool::{MAX_MEMPOOL_BYTES, MemPool, MemPoolEvent, RemovalReason, transaction_size},
+        mempool_view::MemPoolView,
+        node_state::{ChainEvent, SharedNodeState},
+        p2p_server::{EvictionCandidate, select_eviction, start_p2p_server},
+        readmit_transactions, start_full_node,
+        tx_relay::TransactionStatus,
+    },
+    logging::LogConfig,
+    node::{
+        connector::{Endpoint, Socks5Connector, is_host_address},
+        message::{Command, Message},
+        misbehavior::Misbehavior,
+        peer::{PEER_PING_INTERVAL, PeerConfig, PeerError},
+    },
     to_nano,
+    version::VERSION,
 };
 
 async fn reset_bc(blockchain: &SharedBlockchain) {
     let height = blockchain.block_store().get_height();
     for _ in 0..height {
         match blockchain.pop_block() {
-            Ok(()) => {}
+            Ok(_) => {}
             Err(e) => panic!("{}", e),
         }
     }
@@ -129,7 +176,7 @@ async fn test_api(
 #[tokio::test]
 async fn test_node() -> Result<(), anyhow::Error> {
     let node_path = "/tmp/node-".to_string() + &(random::<u64>()).to_string();
-    let (blockchain, node_state) = create_full_node(&node_path, true);
+    let (blockchain, node_state) = create_full_node(&node_path);
 
     test_mempool(&blockchain, &node_state).await?;
     reset_bc(&blockchain).await;
@@ -138,3 +185,1369 @@ async fn test_node() -> Result<(), anyhow::Error> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_ibd() -> Result<(), anyhow::Error> {
+    let (source_blockchain, source_state) =
+        create_full_node(&format!("/tmp/node-{}", random::<u64>()));
+    let (blockchain, node_state) = create_full_node(&format!("/tmp/node-{}", random::<u64>()));
+
+    let miner = Private::new_random().to_public();
+    for _ in 0..5 {
+        let mut block = build_block(&*source_blockchain, &vec![], miner).await?;
+        #[allow(deprecated)]
+        block.compute_pow()?;
+        accept_block(&source_blockchain, &source_state, block).await?;
+    }
+
+    let p2p_port = 8572u16;
+    start_p2p_server(p2p_port, source_blockchain.clone(), source_state.clone()).await?;
+    let peer = connect_peer(
+        format!("127.0.0.1:{}", p2p_port).parse().unwrap(),
+        &blockchain,
+        &node_state,
+    )
+    .await?;
+
+    ibd_blockchain(peer, blockchain.clone(), node_state.clone(), true).await?;
+
+    assert_eq!(
+        blockchain.block_store().get_last_block_hash(),
+        source_blockchain.block_store().get_last_block_hash(),
+        "IBD did not reach the source chain tip"
+    );
+    let status = node_state.ibd_status.read().await.clone();
+    assert_eq!(status.phase, IbdPhase::Complete);
+    assert_eq!(status.headers_validated, 5);
+    assert_eq!(status.blocks_applied, 5);
+
+    Ok(())
+}
+
+#[test]
+fn test_address_book() -> Result<(), anyhow::Error> {
+    let path = PathBuf::from(format!("/tmp/node-{}/peers.dat", random::<u64>()));
+    let book = AddressBook::load(path.clone());
+
+    // One referrer flooding us with addresses from one subnet can only fill a single bucket
+    let attacker = "44.1.0.1".parse()?;
+    for i in 0..=255u8 {
+        book.add(
+            SocketAddr::from(([10, 20, 30, i], 8998)).into(),
+            AddressSource::Referral { from: attacker },
+        );
+    }
+    assert_eq!(book.len(), BUCKET_SIZE);
+
+    let honest: Endpoint = "10.21.0.1:8998".parse()?;
+    book.add(honest.clone(), AddressSource::Manual);
+    book.mark_success(&honest);
+    book.save()?;
+
+    // Survives a restart
+    let book = AddressBook::load(path);
+    assert!(book.len() <= BUCKET_SIZE + 1);
+    assert_eq!(book.get(&honest).unwrap().successes, 1);
+
+    // Best address first, at most one per subnet group
+    let candidates = book.select_candidates(TARGET_PEERS, &HashSet::new(), &HashSet::new());
+    assert_eq!(candidates, vec![honest.clone(), candidates[1].clone()]);
+    assert_ne!(
+        subnet_group(candidates[1].socket_addr().ip()),
+        subnet_group(honest.socket_addr().ip())
+    );
+
+    // Failed addresses are backed off from
+    book.mark_failure(&honest);
+    let candidates = book.select_candidates(TARGET_PEERS, &HashSet::new(), &HashSet::new());
+    assert!(!candidates.contains(&honest));
+
+    // Every host endpoint is a subnet group of its own
+    let onions: Vec<Endpoint> = vec!["first.onion:8998".parse()?, "second.onion:8998".parse()?];
+    for onion in &onions {
+        book.add(onion.clone(), AddressSource::Seed);
+    }
+    let candidates = book.select_candidates(TARGET_PEERS, &HashSet::new(), &HashSet::new());
+    assert!(onions.iter().all(|onion| candidates.contains(onion)));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bootstrap() -> Result<(), anyhow::Error> {
+    let seed_list =
+        "# seeds\n10.0.0.1:8998\n\n10.0.0.2:8998 # second\nseed.onion:8998\nnot-an-address\n";
+    assert_eq!(
+        parse_seed_list(seed_list),
+        vec![
+            "10.0.0.1:8998".parse()?,
+            "10.0.0.2:8998".parse()?,
+            "seed.onion:8998".parse()?
+        ]
+    );
+
+    // From a file, merged with static seeds
+    let seed_path = format!("/tmp/seeds-{}.txt", random::<u64>());
+    std::fs::write(&seed_path, seed_list)?;
+    let seeds = BootstrapConfig::new(vec!["10.0.0.1:8998".parse()?, "10.0.0.3:8998".parse()?])
+        .with_seed_file(format!("file://{seed_path}"))
+        .resolve_seeds()
+        .await;
+    assert_eq!(seeds.len(), 4);
+
+    // From a http server
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let http_address = listener.local_addr()?;
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = [0u8; 1024];
+        let _ = stream.read(&mut request).await.unwrap();
+        stream
+            .write_all(format!("HTTP/1.0 200 OK\r\n\r\n{seed_list}").as_bytes())
+            .await
+            .unwrap();
+    });
+    let seeds = BootstrapConfig::default()
+        .with_seed_file(format!("http://{http_address}/seeds.txt"))
+        .resolve_seeds()
+        .await;
+    assert_eq!(seeds.len(), 3);
+
+    // Malformed, redirected, chunked, truncated and oversized responses are refused
+    let oversized = format!(
+        "HTTP/1.0 200 OK\r\n\r\n{}",
+        "10.0.0.4:8998\n".repeat(MAX_SEED_FILE_SIZE as usize / 14 + 1)
+    );
+    for response in [
+        "garbage".to_string(),
+        format!("HTTP/1.0 200 OK\n\n{seed_list}"),
+        format!(
+            "HTTP/1.0 301 Moved Permanently\r\nLocation: http://{http_address}/\r\n\r\n{seed_list}"
+        ),
+        format!("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n{seed_list}"),
+        format!("HTTP/1.0 200 OK\r\nContent-Length: 1000\r\n\r\n{seed_list}"),
+        format!("HTTP/1.0 200 OK\r\nbroken header\r\n\r\n{seed_list}"),
+        oversized,
+    ] {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let http_address = listener.local_addr()?;
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request).await.unwrap();
+            // The client may hang up before an oversized response is fully written
+            let _ = stream.write_all(response.as_bytes()).await;
+        });
+        let seeds = BootstrapConfig::new(vec!["10.0.0.1:8998".parse()?])
+            .with_seed_file(format!("http://{http_address}/seeds.txt"))
+            .resolve_seeds()
+            .await;
+        assert_eq!(seeds, vec!["10.0.0.1:8998".parse()?]);
+    }
+
+    // A seed file that can't be read still leaves the static seeds
+    let seeds = BootstrapConfig::new(vec!["10.0.0.1:8998".parse()?])
+        .with_seed_file("https://example.com/seeds.txt")
+        .resolve_seeds()
+        .await;
+    assert_eq!(seeds, vec!["10.0.0.1:8998".parse()?]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_send_peers() -> Result<(), anyhow::Error> {
+    let (hub_blockchain, hub_state) = create_full_node(&format!("/tmp/node-{}", random::<u64>()));
+    let (listener_blockchain, listener_state) =
+        create_full_node(&format!("/tmp/node-{}", random::<u64>()));
+    let (client_blockchain, client_state) =
+        create_full_node(&format!("/tmp/node-{}", random::<u64>()));
+
+    let hub_address: SocketAddr = "127.0.0.1:8574".parse()?;
+    start_p2p_server(8574, hub_blockchain.clone(), hub_state.clone()).await?;
+    start_p2p_server(8575, listener_blockchain.clone(), listener_state.clone()).await?;
+
+    // Advertises port 8575 in the handshake
+    let hub = connect_peer(hub_address.into(), &listener_blockchain, &listener_state).await?;
+    // Doesn't listen, so it should never be shared
+    connect_peer(hub_address.into(), &client_blockchain, &client_state).await?;
+
+    // Not shared before the hub connected to it itself
+    let Command::SendPeers { peers } = hub.request(Message::new(Command::GetPeers)).await?.command
+    else {
+        panic!("GetPeers returned incorrect response");
+    };
+    assert!(peers.is_empty());
+
+    connect_peer("127.0.0.1:8575".parse()?, &hub_blockchain, &hub_state).await?;
+    let Command::SendPeers { peers } = hub.request(Message::new(Command::GetPeers)).await?.command
+    else {
+        panic!("GetPeers returned incorrect response");
+    };
+    assert_eq!(
+        peers.iter().map(|peer| peer.address).collect::<Vec<_>>(),
+        vec!["127.0.0.1:8575".parse()?]
+    );
+    assert!(peers[0].last_seen > 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_connection_limits() -> Result<(), anyhow::Error> {
+    let (hub_blockchain, hub_state) = create_full_node(&format!("/tmp/node-{}", random::<u64>()));
+    let hub_address: SocketAddr = "127.0.0.1:8576".parse()?;
+    hub_state.connection_limits.write().await.max_inbound = 3;
+    start_p2p_server(8576, hub_blockchain.clone(), hub_state.clone()).await?;
+
+    let mut nodes = vec![];
+    for _ in 0..5 {
+        nodes.push(create_full_node(&format!("/tmp/node-{}", random::<u64>())));
+    }
+
+    // The fourth connection evicts an unprotected peer, as slots are full
+    for (blockchain, node_state) in &nodes[..4] {
+        connect_peer(hub_address.into(), blockchain, node_state).await?;
+    }
+    sleep(Duration::from_millis(500)).await;
+    let (inbound, outbound) = hub_state.peers_by_direction().await;
+    assert_eq!((inbound.len(), outbound.len()), (3, 0));
+    let mut still_connected = 0;
+    for (_, node_state) in &nodes[..3] {
+        still_connected += node_state.connected_peers.read().await.len();
+    }
+    assert_eq!(still_connected, 2);
+    assert_eq!(nodes[3].1.connected_peers.read().await.len(), 1);
+
+    // Per ip cap
+    hub_state.connection_limits.write().await.max_inbound_per_ip = 1;
+    assert!(
+        connect_peer(hub_address.into(), &nodes[4].0, &nodes[4].1)
+            .await
+            .is_err()
+    );
+
+    // Outbound cap
+    nodes[4].1.connection_limits.write().await.max_outbound = 0;
+    assert!(matches!(
+        connect_peer(hub_address.into(), &nodes[4].0, &nodes[4].1).await,
+        Err(PeerError::ConnectionLimit(_))
+    ));
+
+    // Except for persistent peers
+    hub_state.connection_limits.write().await.max_inbound_per_ip = 8;
+    let (blockchain, node_state) = &nodes[4];
+    node_state
+        .persistent_peers
+        .write()
+        .await
+        .insert(hub_address.into());
+    connect_peer(hub_address.into(), blockchain, node_state).await?;
+
+    Ok(())
+}
+
+#[test]
+fn test_ban_list() -> Result<(), anyhow::Error> {
+    let path = PathBuf::from(format!("/tmp/node-{}/bans.dat", random::<u64>()));
+    let ban_list = BanList::load(path.clone());
+    let ip: IpAddr = "10.0.0.1".parse()?;
+
+    // Scores add up per kind until the threshold
+    assert!(!ban_list.punish(ip, Misbehavior::InvalidTransaction));
+    assert!(!ban_list.punish(ip, Misbehavior::InvalidBlock));
+    assert_eq!(
+        ban_list.score(ip),
+        Misbehavior::InvalidTransaction.weight() + Misbehavior::InvalidBlock.weight()
+    );
+    assert!(ban_list.punish(ip, Misbehavior::InvalidBlock));
+    assert!(ban_list.is_banned(ip));
+
+    // Scores and bans survive a restart
+    let other: IpAddr = "10.0.0.2".parse()?;
+    ban_list.punish(other, Misbehavior::UnsolicitedResponse);
+    ban_list.save()?;
+    let ban_list = BanList::load(path);
+    assert!(ban_list.is_banned(ip));
+    assert_eq!(
+        ban_list.score(other),
+        Misbehavior::UnsolicitedResponse.weight()
+    );
+
+    // Decay forgives scores, expired bans are dropped
+    ban_list.decay();
+    assert_eq!(
+        ban_list.score(other),
+        Misbehavior::UnsolicitedResponse.weight() - SCORE_DECAY
+    );
+    ban_list.ban(other, Some(0), "test".to_string());
+    assert!(!ban_list.is_banned(other));
+
+    assert!(ban_list.unban(ip));
+    assert!(ban_list.list_bans().is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_misbehavior() -> Result<(), anyhow::Error> {
+    let (hub_blockchain, hub_state) = create_full_node(&format!("/tmp/node-{}", random::<u64>()));
+    let (blockchain, node_state) = create_full_node(&format!("/tmp/node-{}", random::<u64>()));
+    let hub_address: SocketAddr = "127.0.0.1:8577".parse()?;
+    start_p2p_server(8577, hub_blockchain.clone(), hub_state.clone()).await?;
+
+    // A message header announcing a 4 GiB payload gets us banned
+    let mut stream = TcpStream::connect(hub_address).await?;
+    let mut header = [0u8; 10];
+    header[6..].copy_from_slice(&u32::MAX.to_be_bytes());
+    stream.write_all(&header).await?;
+    sleep(Duration::from_millis(500)).await;
+
+    let localhost: IpAddr = "127.0.0.1".parse()?;
+    assert!(hub_state.ban_list.is_banned(localhost));
+    assert!(
+        connect_peer(hub_address.into(), &blockchain, &node_state)
+            .await
+            .is_err()
+    );
+
+    // Manual unban over the API
+    let api_port = 8578u32;
+    Server::new(api_port, hub_blockchain.clone(), hub_state.clone())
+        .listen()
+        .await?;
+    let client = Client::connect(format!("127.0.0.1:{}", api_port).parse()?).await?;
+    assert_eq!(client.list_bans().await?.len(), 1);
+    assert!(client.unban(localhost).await?);
+    assert!(client.list_bans().await?.is_empty());
+    connect_peer(hub_address.into(), &blockchain, &node_state).await?;
+
+    // Manual ban over the API disconnects
+    client.ban(localhost, None).await?;
+    sleep(Duration::from_millis(500)).await;
+    assert!(node_state.connected_peers.read().await.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_peer_info() -> Result<(), anyhow::Error> {
+    let (hub_blockchain, hub_state) = create_full_node(&format!("/tmp/node-{}", random::<u64>()));
+    let (blockchain, node_state) = create_full_node(&format!("/tmp/node-{}", random::<u64>()));
+    start_p2p_server(8579, hub_blockchain.clone(), hub_state.clone()).await?;
+    let api_port = 8580u32;
+    Server::new(api_port, hub_blockchain.clone(), hub_state.clone())
+        .listen()
+        .await?;
+
+    let hub = connect_peer("127.0.0.1:8579".parse()?, &blockchain, &node_state).await?;
+    hub.request(Message::new(Command::GetPeers)).await?;
+
+    // Wait for a ping round trip
+    sleep(PEER_PING_INTERVAL + Duration::from_secs(1)).await;
+
+    let client = Client::connect(format!("127.0.0.1:{}", api_port).parse()?).await?;
+    let peers = client.get_peer_info().await?;
+    assert_eq!(peers.len(), 1);
+    let info = &peers[0];
+    assert!(info.inbound);
+    assert_eq!(info.reported_height, Some(0));
+    assert!(info.ping_rtt_ms.is_some());
+    assert_eq!(info.commands["Connect"].messages_in, 1);
+    assert_eq!(info.commands["GetPeers"].messages_in, 1);
+    assert_eq!(info.commands["SendPeers"].messages_out, 1);
+    assert!(info.bytes_in > 0 && info.bytes_out > 0);
+    assert_eq!(info.ban_score, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_request_timeout() -> Result<(), anyhow::Error> {
+    let (blockchain, node_state) = create_full_node(&format!("/tmp/node-{}", random::<u64>()));
+    *node_state.peer_config.write().await = PeerConfig {
+        request_timeout: Duration::from_millis(300),
+        max_in_flight_requests: 1,
+        ..Default::default()
+    };
+
+    // A peer that completes the handshake, answers its first request too late, and hangs up on the next one
+    let listener = TcpListener::bind("127.0.0.1:8581").await?;
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await?;
+        let (mut reader, mut writer) = stream.into_split();
+        let connect = Message::from_stream(&mut reader).await?;
+        connect
+            .make_response(Command::AcknowledgeConnection {
+                listen_address: None,
+                first_block: 0,
+            })
+            .send(&mut writer)
+            .await?;
+
+        let request = Message::from_stream(&mut reader).await?;
+        sleep(Duration::from_millis(600)).await;
+        request
+            .make_response(Command::SendPeers { peers: vec![] })
+            .send(&mut writer)
+            .await?;
+
+        Message::from_stream(&mut reader).await?;
+        Ok::<(), anyhow::Error>(())
+    });
+
+    let peer = connect_peer("127.0.0.1:8581".parse()?, &blockchain, &node_state).await?;
+    assert!(matches!(
+        peer.request(Message::new(Command::GetPeers)).await,
+        Err(PeerError::RequestTimeout)
+    ));
+    assert_eq!(peer.in_flight_requests(), 0);
+
+    // The late response is dropped instead of punished as unsolicited
+    sleep(Duration::from_millis(600)).await;
+    let localhost: IpAddr = "127.0.0.1".parse()?;
+    assert_eq!(
+        node_state.ban_list.score(localhost),
+        Misbehavior::RequestTimeout.weight()
+    );
+    assert_eq!(peer.info(0).timeouts, 1);
+    assert_eq!(node_state.connected_peers.read().await.len(), 1);
+
+    // A dead peer fails requests right away
+    assert!(matches!(
+        peer.request(Message::new(Command::GetPeers)).await,
+        Err(PeerError::Dead)
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_block_relay() -> Result<(), anyhow::Error> {
+    let (hub_blockchain, hub_state) = create_full_node(&format!("/tmp/node-{}", random::<u64>()));
+    let (blockchain, node_state) = create_full_node(&format!("/tmp/node-{}", random::<u64>()));
+    start_p2p_server(8582, hub_blockchain.clone(), hub_state.clone()).await?;
+    let hub = connect_peer("127.0.0.1:8582".parse()?, &blockchain, &node_state).await?;
+
+    let miner = Private::new_random().to_public();
+    for _ in 0..3 {
+        let mut block = build_block(&*hub_blockchain, &vec![], miner).await?;
+        #[allow(deprecated)]
+        block.compute_pow()?;
+        accept_block(&hub_blockchain, &hub_state, block).await?;
+    }
+    sleep(Duration::from_millis(1000)).await;
+
+    // Notifications are handled in order, and never answered
+    assert_eq!(
+        blockchain.block_store().get_last_block_hash(),
+        hub_blockchain.block_store().get_last_block_hash()
+    );
+    let info = hub.info(0);
+    assert_eq!(info.commands["NewBlock"].messages_in, 3);
+    assert_eq!(info.blocks_relayed, 3);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transaction_relay() -> Result<(), anyhow::Error> {
+    let (hub_blockchain, hub_state) = create_full_node(&format!("/tmp/node-{}", random::<u64>()));
+    let (blockchain, node_state) = create_full_node(&format!("/tmp/node-{}", random::<u64>()));
+    for state in [&hub_state, &node_state] {
+        let mut config = state.tx_relay.config.write().unwrap();
+        config.trickle_interval = Duration::from_millis(100);
+        config.embargo_timeout = Duration::from_millis(500);
+        // Relays always fluff, the origin has to stem anyway
+        config.fluff_probability = 1.0;
+    }
+
+    let private = Private::new_random();
+    let mut block = build_block(&*blockchain, &vec![], private.to_public()).await?;
+    #[allow(deprecated)]
+    block.compute_pow()?;
+    accept_block(&blockchain, &node_state, block.clone()).await?;
+    accept_block(&hub_blockchain, &hub_state, block).await?;
+
+    start_p2p_server(8583, hub_blockchain.clone(), hub_state.clone()).await?;
+    let hub = connect_peer("127.0.0.1:8583".parse()?, &blockchain, &node_state).await?;
+
+    // Private submissions go to the stem peer only, and stay out of our own mempool
+    let recipient = Private::new_random().to_public();
+    let mut private_tx =
+        build_transaction(&*blockchain, private, vec![(recipient, 100)], &vec![]).await?;
+    private_tx.compute_pow(
+        &node_state
+            .get_live_transaction_difficulty(blockchain.get_transaction_difficulty())
+            .await,
+        None,
+    )?;
+    accept_stem_transaction(&blockchain, &node_state, private_tx, true).await?;
+    assert!(node_state.mempool.get_mempool().await.is_empty());
+    sleep(Duration::from_millis(1500)).await;
+    assert_eq!(hub.info(0).commands["StemTransaction"].messages_out, 1);
+
+    // The hub has no outbound peers to stem to, so it fluffs, and the transaction comes back to us as an announcement
+    assert_eq!(hub_state.mempool.mempool_size().await, 1);
+    assert_eq!(node_state.mempool.mempool_size().await, 1);
+    let info = hub.info(0);
+    assert_eq!(info.commands["InvTransactions"].messages_in, 1);
+    assert_eq!(info.commands["GetTransactions"].messages_out, 1);
+    assert!(!info.commands.contains_key("NewTransaction"));
+
+    Ok(())
+}
+
+/// Minimal SOCKS5 proxy without auth, that sends every host name to localhost
+async fn start_socks5_stand_in(port: u16) -> Result<(), anyhow::Error> {
+    let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+    tokio::spawn(async move {
+        while let Ok((mut client, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut greeting = [0u8; 2];
+                client.read_exact(&mut greeting).await?;
+                let mut methods = vec![0u8; greeting[1] as usize];
+                client.read_exact(&mut methods).await?;
+                client.write_all(&[0x05, 0x00]).await?;
+
+                let mut request = [0u8; 4];
+                client.read_exact(&mut request).await?;
+                let ip: IpAddr = match request[3] {
+                    0x01 => {
+                        let mut octets = [0u8; 4];
+                        client.read_exact(&mut octets).await?;
+                        octets.into()
+                    }
+                    _ => {
+                        let mut host = vec![0u8; client.read_u8().await? as usize];
+                        client.read_exact(&mut host).await?;
+                        "127.0.0.1".parse()?
+                    }
+                };
+                let port = client.read_u16().await?;
+
+                let mut target = TcpStream::connect((ip, port)).await?;
+                client
+                    .write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                    .await?;
+                tokio::io::copy_bidirectional(&mut client, &mut target).await?;
+                Ok::<(), anyhow::Error>(())
+            });
+        }
+    });
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_socks5_connector() -> Result<(), anyhow::Error> {
+    let (hub_blockchain, hub_state) = create_full_node(&format!("/tmp/node-{}", random::<u64>()));
+    let (blockchain, node_state) = create_full_node(&format!("/tmp/node-{}", random::<u64>()));
+    start_p2p_server(8585, hub_blockchain.clone(), hub_state.clone()).await?;
+    Server::new(8586, hub_blockchain.clone(), hub_state.clone())
+        .listen()
+        .await?;
+    start_socks5_stand_in(8584).await?;
+
+    // Onion addresses can't be reached without a proxy
+    let endpoint: Endpoint = "snapcoinhub.onion:8585".parse()?;
+    assert!(
+        connect_peer(endpoint.clone(), &blockchain, &node_state)
+            .await
+            .is_err()
+    );
+
+    let proxy = Socks5Connector::new("127.0.0.1:8584".parse()?);
+    *node_state.connector.write().await = proxy.clone();
+    let hub = connect_peer(endpoint.clone(), &blockchain, &node_state).await?;
+    hub.request(Message::new(Command::GetPeers)).await?;
+    assert_eq!(hub.endpoint, endpoint);
+    assert_eq!(hub.address, endpoint.socket_addr());
+    assert!(is_host_address(hub.address.ip()));
+    // Onion peers are remembered by their host, but never shared, peers only take ip addresses
+    assert!(node_state.address_book.get(&endpoint).is_some());
+    assert!(node_state.shareable_peers().await.is_empty());
+
+    // Plain ips go through the proxy too
+    let client = Client::connect_via("127.0.0.1:8586".parse()?, &proxy).await?;
+    assert_eq!(client.get_peer_info().await?.len(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_graceful_shutdown() -> Result<(), anyhow::Error> {
+    let hub_path = format!("/tmp/node-{}", random::<u64>());
+    let (hub_blockchain, hub_state) = create_full_node(&hub_path);
+    let (blockchain, node_state) = create_full_node(&format!("/tmp/node-{}", random::<u64>()));
+    start_p2p_server(8587, hub_blockchain.clone(), hub_state.clone()).await?;
+    start_auto_peer(
+        hub_state.clone(),
+        hub_blockchain.clone(),
+        vec![],
+        BootstrapConfig::default(),
+    );
+    Server::new(8588, hub_blockchain.clone(), hub_state.clone())
+        .listen()
+        .await?;
+    connect_peer("127.0.0.1:8587".parse()?, &blockchain, &node_state).await?;
+
+    let mut block =
+        build_block(&*hub_blockchain, &vec![], Private::new_random().to_public()).await?;
+    #[allow(deprecated)]
+    block.compute_pow()?;
+    accept_block(&hub_blockchain, &hub_state, block).await?;
+    sleep(Duration::from_millis(200)).await;
+
+    let handle = NodeHandle::new(hub_blockchain.clone(), hub_state.clone());
+    handle.shutdown().await?;
+    assert!(hub_state.connected_peers.read().await.is_empty());
+    assert!(matches!(
+        connect_peer("127.0.0.1:8587".parse()?, &hub_blockchain, &hub_state).await,
+        Err(PeerError::ShuttingDown)
+    ));
+
+    // The other side saw the disconnect, and both listeners are closed
+    sleep(Duration::from_millis(200)).await;
+    assert!(node_state.connected_peers.read().await.is_empty());
+    assert!(TcpStream::connect("127.0.0.1:8587").await.is_err());
+    assert!(TcpStream::connect("127.0.0.1:8588").await.is_err());
+
+    // Nothing holds on to the blockchain anymore, so it can be opened again
+    drop(handle);
+    assert_eq!(Arc::strong_count(&hub_blockchain), 1);
+    drop(hub_blockchain);
+    let reopened = Blockchain::new(&format!("{hub_path}/blockchain"));
+    assert_eq!(reopened.block_store().get_height(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_localnet() -> Result<(), anyhow::Error> {
+    let localnet = Localnet::start(5, 8589).await?;
+    localnet.connect_line().await?;
+
+    // Blocks travel down the whole line, both ways
+    localnet.mine(0).await?;
+    assert!(localnet.wait_for_all(Duration::from_secs(5)).await);
+    localnet.mine(4).await?;
+    assert!(localnet.wait_for_all(Duration::from_secs(5)).await);
+    assert!(localnet.nodes.iter().all(|node| node.height() == 2));
+
+    // Split into {0, 1} and {2, 3, 4}, and let both sides build their own chain
+    localnet.disconnect(1, 2).await?;
+    localnet.mine(0).await?;
+    for _ in 0..3 {
+        localnet.mine(4).await?;
+    }
+    assert!(
+        localnet
+            .wait_for_consensus(&[0, 1], Duration::from_secs(5))
+            .await
+    );
+    assert!(
+        localnet
+            .wait_for_consensus(&[2, 3, 4], Duration::from_secs(5))
+            .await
+    );
+    assert_ne!(localnet.nodes[0].tip(), localnet.nodes[4].tip());
+
+    // After healing, the shorter side reorgs to the longer chain
+    localnet.connect(1, 2).await?;
+    assert!(localnet.wait_for_all(Duration::from_secs(10)).await);
+    assert!(localnet.nodes.iter().all(|node| node.height() == 5));
+
+    localnet.shutdown().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_node_config() -> Result<(), anyhow::Error> {
+    let config = NodeBuilder::new(format!("/tmp/node-{}", random::<u64>()))
+        .p2p_port(8594)
+        .api_address("127.0.0.1:8595".parse()?)
+        .peer("example.onion:8998".parse()?)
+        .prune_depth(500)
+        .randomx_mode(RandomXMode::Light)
+        .build();
+    assert_eq!(NodeConfig::from_toml(&config.to_toml()?)?, config);
+    assert_eq!(NodeConfig::from_json(&config.to_json()?)?, config);
+
+    // Missing fields get their defaults
+    let partial = NodeConfig::from_toml("prune_depth = 200\npeers = [\"127.0.0.1:8594\"]")?;
+    assert_eq!(partial.peer, PeerConfig::default());
+    assert_eq!(partial.peers, vec![Endpoint::Ip("127.0.0.1:8594".parse()?)]);
+
+    // One call starts the p2p and API server, and connects to peers. Only the first node gets to set the logger
+    let log = LogConfig {
+        stderr: false,
+        ..Default::default()
+    };
+    let hub = NodeBuilder::new(format!("/tmp/node-{}", random::<u64>()))
+        .p2p_port(8594)
+        .api_address("127.0.0.1:8595".parse()?)
+        .prune_depth(500)
+        .log(log.clone())
+        .start_full_node()
+        .await?;
+    assert_eq!(*hub.node_state.prune_depth.read().await, Some(500));
+    let node = start_full_node(&NodeConfig {
+        node_path: PathBuf::from(format!("/tmp/node-{}", random::<u64>())),
+        log: Some(log),
+        ..partial
+    })
+    .await?;
+    assert_eq!(node.node_state.connected_peers.read().await.len(), 1);
+
+    sleep(Duration::from_millis(200)).await;
+    let client = Client::connect("127.0.0.1:8595".parse()?).await?;
+    assert_eq!(client.get_peers().await?.len(), 1);
+
+    node.shutdown().await?;
+    hub.shutdown().await?;
+    Ok(())
+}
+
+/// Mine a block with `transactions` and accept it
+async fn mine_block(
+    blockchain: &SharedBlockchain,
+    node_state: &SharedNodeState,
+    transactions: &Vec<Transaction>,
+    miner: Public,
+) -> Result<(), anyhow::Error> {
+    let mut block = build_block(&**blockchain, transactions, miner).await?;
+    #[allow(deprecated)]
+    block.compute_pow()?;
+    accept_block(blockchain, node_state, block).await?;
+    Ok(())
+}
+
+/// Build a transaction sending 1 SNAP, with its pow done
+async fn build_send(
+    provider: &impl BlockchainDataProvider,
+    blockchain: &SharedBlockchain,
+    node_state: &SharedNodeState,
+    private: Private,
+    receiver: Public,
+) -> Result<Transaction, anyhow::Error> {
+    let mut tx =
+        build_transaction(provider, private, vec![(receiver, to_nano(1.0))], &vec![]).await?;
+    tx.compute_pow(
+        &node_state
+            .get_live_transaction_difficulty(blockchain.get_transaction_difficulty())
+            .await,
+        None,
+    )?;
+    Ok(tx)
+}
+
+#[tokio::test]
+async fn test_chained_transactions() -> Result<(), anyhow::Error> {
+    let (blockchain, node_state) = create_full_node(&format!("/tmp/node-{}", random::<u64>()));
+    let view = MemPoolView::new(blockchain.clone(), node_state.clone());
+    let private = Private::new_random();
+    let public = private.to_public();
+    let receiver = Private::new_random().to_public();
+
+    mine_block(&blockchain, &node_state, &vec![], public).await?;
+
+    // Each transaction spends the change of the one before
+    let mut chain = vec![];
+    for _ in 0..3 {
+        let tx = build_send(&view, &blockchain, &node_state, private, receiver).await?;
+        accept_transaction(&blockchain, &node_state, tx.clone()).await?;
+        chain.push(tx);
+    }
+    let ids: Vec<TransactionId> = chain.iter().map(|tx| tx.transaction_id.unwrap()).collect();
+    assert_eq!(node_state.mempool.chain_depth(&chain[2]).await, 2);
+    assert_eq!(node_state.mempool.descendants(&ids[0]).await, ids[1..]);
+
+    node_state.mempool.config.write().unwrap().max_chain_depth = 2;
+    let too_deep = build_send(&view, &blockchain, &node_state, private, receiver).await?;
+    assert!(matches!(
+        accept_transaction(&blockchain, &node_state, too_deep).await,
+        Err(BlockchainError::ChainTooLong)
+    ));
+
+    // Parents come first, but a block can't spend outputs created in the same block
+    let mempool = node_state.mempool.get_mempool().await;
+    let mempool_ids: Vec<TransactionId> = mempool
+        .iter()
+        .map(|tx| tx.transaction_id.unwrap())
+        .collect();
+    assert_eq!(mempool_ids, ids);
+    let other_miner = Private::new_random().to_public();
+    let mut block = build_block(&*blockchain, &mempool[..2].to_vec(), other_miner).await?;
+    #[allow(deprecated)]
+    block.compute_pow()?;
+    assert!(blockchain.add_block(block, false).is_err());
+
+    // So the chain gets mined one block at a time
+    for mined in 1..=2 {
+        mine_block(
+            &blockchain,
+            &node_state,
+            &vec![chain[mined - 1].clone()],
+            other_miner,
+        )
+        .await?;
+        assert_eq!(node_state.mempool.mempool_size().await, 3 - mined);
+    }
+    assert_eq!(node_state.mempool.chain_depth(&chain[2]).await, 0);
+
+    // A block double spending the last one evicts it, and the child built on it
+    let child = build_send(&view, &blockchain, &node_state, private, receiver).await?;
+    accept_transaction(&blockchain, &node_state, child).await?;
+    assert_eq!(node_state.mempool.mempool_size().await, 2);
+    let double_spend =
+        build_send(&*blockchain, &blockchain, &node_state, private, receiver).await?;
+    mine_block(&blockchain, &node_state, &vec![double_spend], other_miner).await?;
+    assert!(node_state.mempool.get_mempool().await.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reorg_readmission() -> Result<(), anyhow::Error> {
+    let (blockchain, node_state) = create_full_node(&format!("/tmp/node-{}", random::<u64>()));
+    let view = MemPoolView::new(blockchain.clone(), node_state.clone());
+    let private = Private::new_random();
+    let public = private.to_public();
+    let receiver = Private::new_random().to_public();
+    let other_miner = Private::new_random().to_public();
+
+    mine_block(&blockchain, &node_state, &vec![], public).await?;
+    let payment = build_send(&*blockchain, &blockchain, &node_state, private, receiver).await?;
+    mine_block(
+        &blockchain,
+        &node_state,
+        &vec![payment.clone()],
+        other_miner,
+    )
+    .await?;
+    let child = build_send(&view, &blockchain, &node_state, private, receiver).await?;
+    accept_transaction(&blockchain, &node_state, child.clone()).await?;
+
+    // The payment goes back in the mempool, before the child that spends it
+    let popped = blockchain.pop_block()?;
+    assert_eq!(popped.transactions.len(), 2);
+    readmit_transactions(&blockchain, &node_state, popped.transactions).await;
+    let ids: Vec<TransactionId> = node_state
+        .mempool
+        .get_mempool()
+        .await
+        .iter()
+        .map(|tx| tx.transaction_id.unwrap())
+        .collect();
+    assert_eq!(
+        ids,
+        vec![
+            payment.transaction_id.unwrap(),
+            child.transaction_id.unwrap()
+        ]
+    );
+
+    // A new chain double spending the payment invalidates both
+    let mut events = node_state.chain_events.subscribe();
+    let double_spend =
+        build_send(&*blockchain, &blockchain, &node_state, private, receiver).await?;
+    let mut block = build_block(&*blockchain, &vec![double_spend], other_miner).await?;
+    #[allow(deprecated)]
+    block.compute_pow()?;
+    blockchain.add_block(block, false)?;
+    readmit_transactions(&blockchain, &node_state, vec![]).await;
+    assert_eq!(node_state.mempool.mempool_size().await, 0);
+    let mut invalidated = vec![];
+    while let Ok(ChainEvent::MemPool {
+        event:
+            MemPoolEvent::Removed {
+                transaction,
+                reason: RemovalReason::Reorg,
+            },
+    }) = events.try_recv()
+    {
+        invalidated.push(transaction);
+    }
+    assert_eq!(invalidated, ids);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_mempool_persistence() -> Result<(), anyhow::Error> {
+    let node_path = PathBuf::from(format!("/tmp/node-{}", random::<u64>()));
+    let (blockchain, node_state) = create_full_node(node_path.to_str().unwrap());
+    let view = MemPoolView::new(blockchain.clone(), node_state.clone());
+    let private = Private::new_random();
+    let public = private.to_public();
+    let receiver = Private::new_random().to_public();
+
+    mine_block(&blockchain, &node_state, &vec![], public).await?;
+    let payment = build_send(&view, &blockchain, &node_state, private, receiver).await?;
+    accept_transaction(&blockchain, &node_state, payment.clone()).await?;
+    let child = build_send(&view, &blockchain, &node_state, private, receiver).await?;
+    accept_transaction(&blockchain, &node_state, child.clone()).await?;
+
+    // Saved on shutdown, and loaded back in order
+    NodeHandle::new(blockchain.clone(), node_state.clone())
+        .shutdown()
+        .await?;
+    let mempool_path = node_path.join("mempool.dat");
+    let loaded = MemPool::load(mempool_path.clone(), &blockchain);
+    let ids: Vec<TransactionId> = loaded
+        .get_mempool()
+        .await
+        .iter()
+        .map(|tx| tx.transaction_id.unwrap())
+        .collect();
+    assert_eq!(
+        ids,
+        vec![
+            payment.transaction_id.unwrap(),
+            child.transaction_id.unwrap()
+        ]
+    );
+
+    // Transactions double spent by the chain since are dropped on load
+    let double_spend =
+        build_send(&*blockchain, &blockchain, &node_state, private, receiver).await?;
+    let mut block = build_block(&*blockchain, &vec![double_spend], receiver).await?;
+    #[allow(deprecated)]
+    block.compute_pow()?;
+    blockchain.add_block(block, false)?;
+    let loaded = MemPool::load(mempool_path, &blockchain);
+    assert_eq!(loaded.mempool_size().await, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_block_template() -> Result<(), anyhow::Error> {
+    let (blockchain, node_state) = create_full_node(&format!("/tmp/node-{}", random::<u64>()));
+    let view = MemPoolView::new(blockchain.clone(), node_state.clone());
+    Server::new(8596, blockchain.clone(), node_state.clone())
+        .listen()
+        .await?;
+    let private = Private::new_random();
+    let public = private.to_public();
+    let receiver = Private::new_random().to_public();
+
+    mine_block(&blockchain, &node_state, &vec![], public).await?;
+    let payment = build_send(&view, &blockchain, &node_state, private, receiver).await?;
+    accept_transaction(&blockchain, &node_state, payment.clone()).await?;
+    let child = build_send(&view, &blockchain, &node_state, private, receiver).await?;
+    accept_transaction(&blockchain, &node_state, child.clone()).await?;
+
+    // Forced in, it conflicts with the payment and must be left out
+    let double_spend =
+        build_send(&*blockchain, &blockchain, &node_state, private, receiver).await?;
+    node_state.mempool.add_transaction(double_spend).await;
+
+    let client = Client::connect("127.0.0.1:8596".parse()?).await?;
+    let mut template = client.get_block_template(public).await?;
+    assert_eq!(template.height, 1);
+    // The child spends an unconfirmed output, so it waits for the next block
+    assert_eq!(template.block.transactions.len(), 2);
+    assert_eq!(
+        template.block.transactions[0].transaction_id,
+        payment.transaction_id
+    );
+
+    #[allow(deprecated)]
+    template.block.compute_pow()?;
+    client.submit_block(template.block).await??;
+    assert_eq!(blockchain.block_store().get_height(), 2);
+    assert_eq!(node_state.mempool.mempool_size().await, 1);
+
+    // Its parent is confirmed now
+    let template = build_block_template(&blockchain, &node_state.mempool, public).await?;
+    assert_eq!(template.block.transactions.len(), 2);
+    assert_eq!(
+        template.block.transactions[0].transaction_id,
+        child.transaction_id
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transaction_replacement() -> Result<(), anyhow::Error> {
+    let (blockchain, node_state) = create_full_node(&format!("/tmp/node-{}", random::<u64>()));
+    let view = MemPoolView::new(blockchain.clone(), node_state.clone());
+    let private = Private::new_random();
+    let public = private.to_public();
+    let receiver = Private::new_random().to_public();
+
+    mine_block(&blockchain, &node_state, &vec![], public).await?;
+    let original = build_send(&*blockchain, &blockchain, &node_state, private, receiver).await?;
+    accept_transaction(&blockchain, &node_state, original.clone()).await?;
+    let child = build_send(&view, &blockchain, &node_state, private, receiver).await?;
+    accept_transaction(&blockchain, &node_state, child.clone()).await?;
+    let original_id = original.transaction_id.unwrap();
+
+    // Same inputs, with pow twice as strong
+    let half = BigUint::from_bytes_be(&original_id.dump_buf()) / 2u32;
+    let mut target = [0u8; 32];
+    let half = half.to_bytes_be();
+    target[32 - half.len()..].copy_from_slice(&half);
+    let mut replacement =
+        build_transaction(&*blockchain, private, vec![(public, to_nano(2.0))], &vec![]).await?;
+    replacement.compute_pow(&target, None)?;
+    let replacement_id = replacement.transaction_id.unwrap();
+
+    // Not with replacement turned off, or a margin it doesn't beat
+    let config = &node_state.mempool.config;
+    config.write().unwrap().replacement_margin = 0;
+    assert!(matches!(
+        node_state.mempool.check_replacement(&replacement).await,
+        Err(BlockchainError::DoubleSpend)
+    ));
+    config.write().unwrap().replacement_margin = u64::MAX;
+    assert!(matches!(
+        node_state.mempool.check_replacement(&replacement).await,
+        Err(BlockchainError::DoubleSpend)
+    ));
+
+    // Rejected when it is added (it can't fit on its own), the original and its child stay
+    config.write().unwrap().replacement_margin = 2;
+    config.write().unwrap().max_bytes = transaction_size(&replacement) - 1;
+    node_state.mempool.check_replacement(&replacement).await?;
+    assert!(matches!(
+        node_state
+            .mempool
+            .try_replace_transaction(replacement.clone(), u64::MAX)
+            .await,
+        Err(BlockchainError::MemPoolFull)
+    ));
+    let ids: Vec<TransactionId> = node_state
+        .mempool
+        .get_mempool()
+        .await
+        .iter()
+        .map(|tx| tx.transaction_id.unwrap())
+        .collect();
+    assert_eq!(ids, vec![original_id, child.transaction_id.unwrap()]);
+
+    // It takes the place of the original, whose child goes with it
+    config.write().unwrap().max_bytes = MAX_MEMPOOL_BYTES;
+    let mut events = node_state.chain_events.subscribe();
+    accept_transaction(&blockchain, &node_state, replacement).await?;
+    let ids: Vec<TransactionId> = node_state
+        .mempool
+        .get_mempool()
+        .await
+        .iter()
+        .map(|tx| tx.transaction_id.unwrap())
+        .collect();
+    assert_eq!(ids, vec![replacement_id]);
+    assert!(matches!(
+        events.try_recv()?,
+        ChainEvent::TransactionReplaced { transaction, replaced_by }
+            if transaction == original_id && replaced_by == replacement_id
+    ));
+    for removed in [original_id, child.transaction_id.unwrap()] {
+        assert!(matches!(
+            events.try_recv()?,
+            ChainEvent::MemPool {
+                event: MemPoolEvent::Removed {
+                    transaction,
+                    reason: RemovalReason::Conflict
+                }
+            } if transaction == removed
+        ));
+    }
+
+    // The original can't come back, its pow is weaker
+    assert!(matches!(
+        node_state.mempool.check_replacement(&original).await,
+        Err(BlockchainError::DoubleSpend)
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_mempool_events() -> Result<(), anyhow::Error> {
+    let (blockchain, node_state) = create_full_node(&format!("/tmp/node-{}", random::<u64>()));
+    Server::new(8597, blockchain.clone(), node_state.clone())
+        .listen()
+        .await?;
+    let private = Private::new_random();
+    let public = private.to_public();
+    let receiver = Private::new_random().to_public();
+    mine_block(&blockchain, &node_state, &vec![], public).await?;
+
+    // Stream the mempool events a wallet would see
+    let (events_tx, mut events) = tokio::sync::mpsc::unbounded_channel();
+    let listener = Client::connect("127.0.0.1:8597".parse()?).await?;
+    tokio::spawn(listener.convert_to_event_listener(move |event| {
+        if let ChainEvent::MemPool { event } = event {
+            let _ = events_tx.send(event);
+        }
+    }));
+    sleep(Duration::from_millis(100)).await;
+
+    let client = Client::connect("127.0.0.1:8597".parse()?).await?;
+    let payment = build_send(&*blockchain, &blockchain, &node_state, private, receiver).await?;
+    let payment_id = payment.transaction_id.unwrap();
+    client.submit_transaction(payment.clone()).await??;
+    assert!(matches!(
+        events.recv().await,
+        Some(MemPoolEvent::Added { transaction }) if transaction == payment_id
+    ));
+
+    let config = &node_state.mempool.config;
+    config.write().unwrap().replacement_margin = 0;
+    let double_spend =
+        build_send(&*blockchain, &blockchain, &node_state, private, receiver).await?;
+    let status = client.submit_transaction(double_spend.clone()).await?;
+    assert!(status.is_err());
+    assert!(matches!(
+        events.recv().await,
+        Some(MemPoolEvent::Rejected { transaction, reason: BlockchainError::DoubleSpend })
+            if transaction == double_spend.transaction_id
+    ));
+
+    mine_block(&blockchain, &node_state, &vec![payment], public).await?;
+    assert!(matches!(
+        events.recv().await,
+        Some(MemPoolEvent::Removed { transaction, reason: RemovalReason::Mined })
+            if transaction == payment_id
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_local_rebroadcast() -> Result<(), anyhow::Error> {
+    let (hub_blockchain, hub_state) = create_full_node(&format!("/tmp/node-{}", random::<u64>()));
+    let (blockchain, node_state) = create_full_node(&format!("/tmp/node-{}", random::<u64>()));
+    {
+        let mut config = node_state.tx_relay.config.write().unwrap();
+        config.trickle_interval = Duration::from_millis(100);
+        config.rebroadcast_interval = Duration::from_millis(200);
+    }
+    Server::new(8598, blockchain.clone(), node_state.clone())
+        .listen()
+        .await?;
+    let private = Private::new_random();
+    let public = private.to_public();
+    let receiver = Private::new_random().to_public();
+
+    let mut block = build_block(&*blockchain, &vec![], public).await?;
+    #[allow(deprecated)]
+    block.compute_pow()?;
+    accept_block(&blockchain, &node_state, block.clone()).await?;
+    accept_block(&hub_blockchain, &hub_state, block).await?;
+
+    // Submitted while we have no peers, so nobody heard of it
+    let client = Client::connect("127.0.0.1:8598".parse()?).await?;
+    let payment = build_send(&*blockchain, &blockchain, &node_state, private, receiver).await?;
+    let payment_id = payment.transaction_id.unwrap();
+    client.submit_transaction(payment.clone()).await??;
+    assert_eq!(
+        client.get_transaction_status(payment_id).await?,
+        TransactionStatus::MemPool
+    );
+
+    // Re-announced once we have a peer
+    start_p2p_server(8599, hub_blockchain.clone(), hub_state.clone()).await?;
+    connect_peer("127.0.0.1:8599".parse()?, &blockchain, &node_state).await?;
+    sleep(Duration::from_millis(1000)).await;
+    assert!(hub_state.mempool.contains(&payment_id).await);
+
+    // Mined, so no longer tracked
+    mine_block(&blockchain, &node_state, &vec![payment], public).await?;
+    assert_eq!(
+        client.get_transaction_status(payment_id).await?,
+        TransactionStatus::Confirmed { height: 1 }
+    );
+    sleep(Duration::from_millis(500)).await;
+    assert!(node_state.tx_relay.local_transactions().is_empty());
+
+    // Private submissions are not re-announced, that would give away where they came from
+    let private_payment =
+        build_send(&*blockchain, &blockchain, &node_state, private, receiver).await?;
+    client.submit_private_transaction(private_payment).await??;
+    assert!(node_state.tx_relay.local_transactions().is_empty());
+
+    let lost = Hash::new_from_buf(random::<[u8; 32]>());
+    assert_eq!(
+        client.get_transaction_status(lost).await?,
+        TransactionStatus::Unknown
+    );
+    node_state.tx_relay.track_local(lost, 0);
+    sleep(Duration::from_millis(500)).await;
+    assert!(node_state.tx_relay.local_transactions().is_empty());
+    assert_eq!(
+        client.get_transaction_status(lost).await?,
+        TransactionStatus::Expired
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_protocol_version() -> Result<(), anyhow::Error> {
+    let (hub_blockchain, hub_state) = create_full_node(&format!("/tmp/node-{}", random::<u64>()));
+    start_p2p_server(8600, hub_blockchain.clone(), hub_state.clone()).await?;
+
+    // A handshake of an older protocol version gets the connection dropped, without an answer
+    let mut stream = TcpStream::connect("127.0.0.1:8600").await?;
+    let mut connect = Message::new(Command::Connect {
+        listen_address: None,
+        first_block: 0,
+    });
+    connect.version = VERSION - 1;
+    stream.write_all(&connect.serialize()?).await?;
+    let mut response = vec![];
+    stream.read_to_end(&mut response).await?;
+    assert!(response.is_empty());
+    assert!(hub_state.connected_peers.read().await.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pruned_node() -> Result<(), anyhow::Error> {
+    let (source_blockchain, source_state) =
+        create_full_node(&format!("/tmp/node-{}", random::<u64>()));
+    let (blockchain, node_state) = create_full_node(&format!("/tmp/node-{}", random::<u64>()));
+
+    let miner = Private::new_random().to_public();
+    for _ in 0..5 {
+        mine_block(&source_blockchain, &source_state, &vec![], miner).await?;
+    }
+    assert_eq!(source_blockchain.block_store().prune(2)?, 3);
+    assert_eq!(source_blockchain.block_store().first_block(), 3);
+
+    start_p2p_server(8601, source_blockchain.clone(), source_state.clone()).await?;
+    let peer = connect_peer("127.0.0.1:8601".parse()?, &blockchain, &node_state).await?;
+    assert_eq!(peer.first_block(), 3);
+
+    // Hashes of pruned blocks are refused, the ones we still have are served
+    for (start, expected) in [(0, 0), (3, 2)] {
+        let response = peer
+            .request(Message::new(Command::GetBlockHashes { start, end: 5 }))
+            .await?;
+        let Command::GetBlockHashesResponse { block_hashes } = response.command else {
+            panic!("Unexpected response {}", response.command.name());
+        };
+        assert_eq!(block_hashes.len(), expected);
+    }
+
+    // An empty node can't download the chain from it
+    assert!(
+        ibd_blockchain(peer.clone(), blockchain.clone(), node_state.clone(), true)
+            .await
+            .is_err()
+    );
+    assert_eq!(blockchain.block_store().get_height(), 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_competing_block() -> Result<(), anyhow::Error> {
+    let (hub_blockchain, hub_state) = create_full_node(&format!("/tmp/node-{}", random::<u64>()));
+    let (blockchain, node_state) = create_full_node(&format!("/tmp/node-{}", random::<u64>()));
+    start_p2p_server(8602, hub_blockchain.clone(), hub_state.clone()).await?;
+
+    // Difficulties stay at their max on the first block, past it every block changes them
+    let miner = Private::new_random().to_public();
+    mine_block(&hub_blockchain, &hub_state, &vec![], miner).await?;
+
+    // Two blocks on the same parent, the hub accepts one of them first
+    let mut blocks = vec![];
+    for _ in 0..2 {
+        let miner = Private::new_random().to_public();
+        let mut block = build_block(&*hub_blockchain, &vec![], miner).await?;
+        #[allow(deprecated)]
+        block.compute_pow()?;
+        blocks.push(block);
+    }
+    let sibling = blocks.pop().unwrap();
+    accept_block(&hub_blockchain, &hub_state, blocks.pop().unwrap()).await?;
+
+    // The sibling fails the hub's difficulty checks, but the peer relaying it is honest
+    let hub = connect_peer("127.0.0.1:8602".parse()?, &blockchain, &node_state).await?;
+    hub.send(Message::new(Command::NewBlock { block: sibling }))
+        .await?;
+    sleep(Duration::from_millis(500)).await;
+
+    let localhost: IpAddr = "127.0.0.1".parse()?;
+    assert_eq!(hub_state.ban_list.score(localhost), 0);
+    assert_eq!(hub_state.connected_peers.read().await.len(), 1);
+    assert_eq!(hub_blockchain.block_store().get_height(), 2);
+
+    Ok(())
+}
+
+#[test]
+fn test_transaction_misbehavior() {
+    // Broken on any chain, the relaying peer is punished
+    for error in [
+        TransactionError::InvalidSignature(String::new()),
+        TransactionError::InvalidHash(String::new()),
+        TransactionError::SumMismatch(String::new()),
+    ] {
+        assert_eq!(
+            Misbehavior::from_transaction_error(&error.into()),
+            Some(Misbehavior::InvalidTransaction)
+        );
+    }
+
+    // Depends on the chain state, honest peers send these in races or after difficulty changes
+    for error in [
+        TransactionError::InsufficientDifficulty(String::new()),
+        TransactionError::InputNotFound(String::new()),
+        TransactionError::SpentInputIndex,
+    ] {
+        assert_eq!(Misbehavior::from_transaction_error(&error.into()), None);
+    }
+    assert_eq!(
+        Misbehavior::from_transaction_error(&BlockchainError::DoubleSpend),
+        None
+    );
+}
+
+#[test]
+fn test_inbound_eviction() -> Result<(), anyhow::Error> {
+    // Idle honest peers from different networks, connected first
+    let honest: Vec<EvictionCandidate> = (0..8u8)
+        .map(|i| EvictionCandidate {
+            address: SocketAddr::new(IpAddr::from([10, i, 0, 1]), 8000),
+            ping_rtt_ms: u64::MAX,
+            blocks_relayed: 0,
+            transactions_relayed: 0,
+            connected_at: 1000 + i as u64,
+        })
+        .collect();
+    let mut connected = honest.clone();
+
+    // An attacker that answers pings fast keeps reconnecting from one network
+    let mut evictions = 0;
+    for i in 0..200u16 {
+        if connected.len() >= 16 {
+            let Some(evicted) = select_eviction(&connected) else {
+                continue;
+            };
+            connected.retain(|peer| peer.address != evicted);
+            evictions += 1;
+        }
+        connected.push(EvictionCandidate {
+            address: SocketAddr::new(IpAddr::from([1, 2, (i >> 8) as u8, i as u8]), 8000),
+            ping_rtt_ms: 1,
+            blocks_relayed: 0,
+            transactions_relayed: 0,
+            connected_at: 2000 + i as u64,
+        });
+    }
+
+    assert!(evictions > 100);
+    for peer in honest {
+        assert!(connected.iter().any(|other| other.address == peer.address));
+    }
+
+    Ok(())
+}
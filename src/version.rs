@@ -1 +1,2 @@
-pub const VERSION: u16 = 2u16;
\ No newline at end of file
+/// P2P protocol version, sent in every message header. Peers with another version are refused
+pub const VERSION: u16 = 4u16;
\ No newline at end of file